@@ -1,10 +1,10 @@
 descr = "freifunk.net"
 
 function detect(network)
-    return network:find("freifunk.net") ~= nil
+    return network['ssid']:find("freifunk.net") ~= nil
 end
 
-function decap(infos)
+function decap(network)
     -- nothing to do :)
     return true
 end
@@ -1,20 +1,23 @@
 descr = "moxy"
 -- NOTE: hijacks all dns traffic
--- TODO: clients3.google.com is explicitly whitelisted, so automatic decap doesn't work
+-- NOTE: clients3.google.com is explicitly whitelisted, the other probes still detect the portal
 
 function detect(network)
-    return network == "#ATTHEMOXY"
+    return network['ssid'] == "#ATTHEMOXY"
 end
 
-function decap(infos)
+function decap(network)
     session = http_mksession()
 
     -- get redirect url
-    req = http_request(session, 'GET', 'http://example.com', {})
-    x = http_send(req)
-    if last_err() then return end
+    redirect = network['redirect']
+    if not redirect then
+        req = http_request(session, 'GET', 'http://example.com', {})
+        x = http_send(req)
+        if last_err() then return end
 
-    redirect = x['headers']['location']
+        redirect = x['headers']['location']
+    end
 
     -- open captive portal page
     req = http_request(session, 'GET', redirect, {})
@@ -1,3 +1,4 @@
+use config::DnsProtocol;
 use structopt::clap::{AppSettings, Shell};
 use std::net::IpAddr;
 
@@ -33,6 +34,12 @@ pub enum SubCommand {
                 name="status",
                 about="Show current network status")]
     Status(Status),
+    /// Show what snail knows about networks it has seen
+    #[structopt(author = "", name="networks")]
+    Networks(Networks),
+    /// Show dns cache statistics
+    #[structopt(author = "", name="cache")]
+    Cache(Cache),
     #[structopt(author = "",
                 name="dns",
                 about="Run dns request inside target network")]
@@ -44,9 +51,9 @@ pub enum SubCommand {
     /// Open a tcp connection inside the target network
     #[structopt(author = "", name="connect")]
     Connect(Connect),
-    /// Resolve a dns name with dns-over-https
+    /// Resolve a dns name with the configured upstreams, or test a specific upstream
     #[structopt(author = "", name="doh")]
-    Doh(Dns),
+    Doh(Doh),
     /// Generate shell completions
     #[structopt(author="", name="completions")]
     Completions(Completions),
@@ -54,6 +61,9 @@ pub enum SubCommand {
 
 #[derive(StructOpt, Debug)]
 pub struct Scan {
+    #[structopt(long="json",
+                help="Report as json")]
+    pub json: bool,
     pub interface: String,
 }
 
@@ -79,13 +89,58 @@ pub struct Status {
     pub json: bool,
 }
 
+#[derive(StructOpt, Debug)]
+pub struct Networks {
+    #[structopt(long="json",
+                help="Report as json")]
+    pub json: bool,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Cache {
+    #[structopt(long="json",
+                help="Report as json")]
+    pub json: bool,
+}
+
 #[derive(StructOpt, Debug)]
 pub struct Dns {
     #[structopt(help="The record you want to query")]
     pub query: String,
     #[structopt(default_value="A",
-                help="The query type you want to lookup")]
+                help="The query type you want to lookup (A, AAAA, CNAME, MX, NS, PTR, SOA, SRV, TXT)")]
+    pub record: String,
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Doh {
+    #[structopt(help="The record you want to query")]
+    pub query: String,
+    #[structopt(default_value="A",
+                help="The query type you want to lookup (A, AAAA, CNAME, MX, NS, PTR, SOA, SRV, TXT)")]
     pub record: String,
+    #[structopt(long="server",
+                help="Use this server instead of the configured upstreams")]
+    pub servers: Vec<IpAddr>,
+    #[structopt(long="protocol",
+                help="Overwrite the protocol (udp, tcp, tls, https)")]
+    pub protocol: Option<DnsProtocol>,
+    #[structopt(long="port",
+                help="Overwrite the port")]
+    pub port: Option<u16>,
+    #[structopt(long="tls-name",
+                help="Overwrite the name in the certificate")]
+    pub tls_name: Option<String>,
+}
+
+impl Doh {
+    /// Check if the configured upstreams are modified
+    pub fn is_override(&self) -> bool {
+        !self.servers.is_empty() ||
+        self.protocol.is_some() ||
+        self.port.is_some() ||
+        self.tls_name.is_some()
+    }
 }
 
 #[derive(StructOpt, Debug)]
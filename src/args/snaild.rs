@@ -31,6 +31,10 @@ pub enum SubCommand {
                 name="decap",
                 about="Start decap daemon")]
     Decap,
+    #[structopt(author = "",
+                name="dns",
+                about="Start dns server")]
+    Dns,
 }
 
 #[derive(StructOpt, Debug)]
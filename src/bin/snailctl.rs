@@ -18,22 +18,238 @@ use reduce::Reduce;
 use http::{Request, Uri};
 use hyper::Body;
 
+use std::fs;
 use std::io::stdout;
+use std::path::Path;
 
-use snail::args::snailctl::{Args, SubCommand};
-use snail::config;
+use snail::args::snailctl::{Args, SubCommand, Doh};
+use snail::capport::CaptivePortalStatus;
+use snail::config::{self, DnsConfig, DnsServer};
 use snail::connect;
-use snail::decap;
-use snail::dns::{Resolver, DnsResolver};
+use snail::decap::{self, DecapReport, DecapOptions, DecapOutcome, ProbeStatus};
+use snail::dhcp::LeaseOptions;
+use snail::dns::{self, Resolver, DnsResolver};
+use snail::dnscheck::DnsCheck;
+use snail::dnsd::Forwarder;
 use snail::errors::{Result, ResultExt};
 use snail::ipc::Client;
+use snail::knowledge::{self, KnowledgeBase, NetworkKnowledge};
 use snail::sandbox;
-use snail::scripts::Loader;
-use snail::utils;
+use snail::scripts::{Loader, DetectInfo};
 use snail::web::{self, HttpClient};
-use snail::wifi::NetworkStatus;
+use snail::wifi::{self, NetworkStatus};
 
 
+fn print_probe(probe: &ProbeStatus) -> colored::ColoredString {
+    match probe {
+        ProbeStatus::Online     => "online".green(),
+        ProbeStatus::Portal     => "captive portal".yellow(),
+        ProbeStatus::Error(err) => format!("error: {}", err).red(),
+    }
+}
+
+fn print_dns_check(check: &DnsCheck) -> String {
+    let hijacked = match check.hijacked {
+        Some(true)  => {
+            let names = check.mismatches.iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>();
+            format!("answers modified ({})", names.join(", ")).red()
+        },
+        Some(false) => "answers ok".green(),
+        None        => "answers unknown".yellow(),
+    };
+    let intercepted = match check.intercepted {
+        Some(true)  => "udp/53 intercepted".red(),
+        Some(false) => "udp/53 not intercepted".green(),
+        None        => "udp/53 unknown".yellow(),
+    };
+    format!("{}, {}", hijacked, intercepted)
+}
+
+fn print_list<T: ToString>(list: &[T]) -> String {
+    list.iter()
+        .map(|x| x.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn print_lease(lease: &LeaseOptions) {
+    if let Some(domain) = &lease.domain_name {
+        println!("domain:  {:?}", domain);
+    }
+    if !lease.domain_search.is_empty() {
+        println!("search:  [{}]", print_list(&lease.domain_search));
+    }
+    if let Some(mtu) = lease.mtu {
+        println!("mtu:     {}", mtu);
+    }
+    if let Some(lease_time) = lease.lease_time {
+        println!("lease:   {}s", lease_time);
+    }
+    if let Some(server) = lease.server_identifier {
+        println!("server:  {}", server);
+    }
+    if let Some(broadcast) = lease.broadcast_address {
+        println!("bcast:   {}", broadcast);
+    }
+    if !lease.classless_routes.is_empty() {
+        println!("routes:  [{}]", print_list(&lease.classless_routes));
+    }
+    if !lease.ntp_servers.is_empty() {
+        println!("ntp:     [{}]", print_list(&lease.ntp_servers));
+    }
+    if let Some(ipv6) = &lease.ipv6 {
+        let mut addresses = print_list(&ipv6.addresses);
+        if !ipv6.prefixes.is_empty() {
+            if !addresses.is_empty() {
+                addresses += ", ";
+            }
+            addresses += &print_list(&ipv6.prefixes);
+        }
+        println!("ipv6:    [{}]", addresses);
+        if !ipv6.dns_servers.is_empty() {
+            println!("dns6:    [{}]", print_list(&ipv6.dns_servers));
+        }
+    }
+}
+
+fn print_capport(api: &CaptivePortalStatus) -> String {
+    let mut out = if api.captive {
+        "captive".red().to_string()
+    } else {
+        "not captive".green().to_string()
+    };
+    if let Some(url) = &api.user_portal_url {
+        out += &format!(", portal {:?}", url);
+    }
+    if let Some(seconds) = api.seconds_remaining {
+        out += &format!(", {}s remaining", seconds);
+    }
+    out
+}
+
+fn print_report(report: &DecapReport) {
+    println!("decap:   {}", match report.outcome {
+        DecapOutcome::NoPortal => "no portal".green(),
+        DecapOutcome::Solved   => "solved".green(),
+        DecapOutcome::Unsolved => "unsolved".red(),
+    });
+    if let Some(fingerprint) = &report.fingerprint {
+        println!("portal:  {}", match fingerprint.portal_host() {
+            Some(host) => format!("{:?}", host),
+            None       => "unknown".to_string(),
+        });
+    }
+    println!("probe:   {}", print_probe(&report.probe));
+    if let Some(check) = &report.dns_check {
+        println!("dnscheck: {}", print_dns_check(check));
+    }
+    if let Some(message) = &report.message {
+        println!("message: {}", message);
+    }
+
+    if !report.scripts.is_empty() {
+        println!("scripts:");
+    }
+    for script in &report.scripts {
+        if !script.detected {
+            println!("  {:?}: not detected", script.script);
+            continue;
+        }
+
+        let result = match &script.result {
+            Some(Ok(_))    => "ok".green(),
+            Some(Err(err)) => format!("error: {}", err).red(),
+            None           => "not executed".yellow(),
+        };
+        let duration = match script.duration_ms {
+            Some(ms) => format!(" ({}ms)", ms),
+            None     => String::new(),
+        };
+        let probe = match &script.probe {
+            Some(probe) => format!(", probe: {}", print_probe(probe)),
+            None        => String::new(),
+        };
+        println!("  {:?}: decap {}{}{}", script.script, result, duration, probe);
+    }
+}
+
+fn ago(now: u64, timestamp: u64) -> String {
+    let secs = now.saturating_sub(timestamp);
+    match secs {
+        0..=59          => format!("{}s ago", secs),
+        60..=3599       => format!("{}m ago", secs / 60),
+        3600..=86399    => format!("{}h ago", secs / 3600),
+        _               => format!("{}d ago", secs / 86400),
+    }
+}
+
+fn print_network(ssid: &str, network: &NetworkKnowledge, now: u64) {
+    println!("{} (last seen {}, {})", format!("{:?}", ssid).green(),
+                                      ago(now, network.last_seen),
+                                      match network.last_outcome {
+        Some(DecapOutcome::NoPortal) => "no portal".green(),
+        Some(DecapOutcome::Solved)   => "solved".green(),
+        Some(DecapOutcome::Unsolved) => "unsolved".red(),
+        None                         => "unknown".yellow(),
+    });
+
+    if !network.bssids.is_empty() {
+        println!("  bssids: {}", network.bssids.iter()
+                                    .cloned()
+                                    .reduce(|a, b| a + ", " + &b)
+                                    .unwrap_or_else(String::new));
+    }
+
+    for solution in &network.solutions {
+        println!("  solved by {:?} {} (bssid: {}, portal: {})",
+            solution.script,
+            ago(now, solution.solved_at),
+            solution.bssid.as_ref().map(|x| x.as_str()).unwrap_or("unknown"),
+            solution.portal.as_ref().map(|x| x.as_str()).unwrap_or("unknown"));
+    }
+
+    for (script, stats) in &network.scripts {
+        println!("  {:?}: {} succeeded, {} failed", script, stats.successes, stats.failures);
+    }
+}
+
+/// The upstreams for `snailctl doh` with the overrides from the command line
+fn doh_upstreams(dns: Option<&DnsConfig>, args: &Doh) -> Result<Vec<DnsServer>> {
+    let mut upstreams = if !args.servers.is_empty() {
+        args.servers.iter()
+            .map(|ip| DnsServer {
+                ip: *ip,
+                protocol: dns.map(|dns| dns.protocol).unwrap_or_default(),
+                port: None,
+                tls_name: dns.and_then(|dns| dns.sni.clone()),
+            })
+            .collect()
+    } else if let Some(dns) = dns {
+        dns.upstreams()
+    } else {
+        bail!("dns is not configured");
+    };
+
+    for upstream in &mut upstreams {
+        if let Some(protocol) = args.protocol {
+            if protocol != upstream.protocol {
+                upstream.protocol = protocol;
+                upstream.port = None;
+            }
+        }
+        if let Some(port) = args.port {
+            upstream.port = Some(port);
+        }
+        if let Some(tls_name) = &args.tls_name {
+            upstream.tls_name = Some(tls_name.to_string());
+        }
+    }
+
+    Ok(upstreams)
+}
+
 fn run() -> Result<()> {
     let args = Args::from_args();
 
@@ -54,40 +270,60 @@ fn run() -> Result<()> {
         Some(SubCommand::Scan(scan)) => {
             // println!("scanning on {:?}", scan.interface);
 
-            // there is no network status, so we just use a default environment
-            let scripts = Loader::init_all_scripts_default(&config)?;
-
-            let networks = utils::scan_wifi(&scan.interface)
-                            .context("scan_wifi failed")?;
-            for network in networks {
-                let encryption = match network.encryption.as_str() {
-                    "on"  => "on ".red().to_string(),
-                    "off" => "off".green().to_string(),
-                    _     => String::new(),
-                };
+            let networks = wifi::scan(&scan.interface)
+                            .context("wifi scan failed")?;
 
-                let mut has_script = false;
-                for script in &scripts {
-                    if script.detect_network(&network.essid)? {
-                        info!("found script! {:?}", script);
-                        has_script = true;
-                        break;
+            if scan.json {
+                println!("{}", serde_json::to_string(&networks)?);
+            } else {
+                // there is no network status, so we just use a default environment
+                let scripts = Loader::init_all_scripts_default(&config)?;
+
+                for network in networks {
+                    let security = format!("{:4}", network.security.mode.as_str());
+                    let security = if network.security.is_open() {
+                        security.green().to_string()
+                    } else {
+                        security.red().to_string()
+                    };
+
+                    let auth = network.security.auth_suites().iter()
+                        .map(|x| x.as_str())
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let band = network.band
+                        .map(|x| x.as_str())
+                        .unwrap_or("?");
+                    let rate = network.bitrates.iter()
+                        .cloned()
+                        .fold(0.0, f32::max);
+
+                    let mut has_script = false;
+                    for script in &scripts {
+                        if script.detect_network(&DetectInfo::from(&network))? {
+                            info!("found script! {:?}", script);
+                            has_script = true;
+                            break;
+                        }
                     }
-                }
 
-                let script_indicator = if has_script {
-                    "$".green().to_string()
-                } else {
-                    String::from(" ")
-                };
-
-                println!(" {} {:?} {:28} encryption={} signal={:?} dBm channel={:?}",
-                         script_indicator,
-                         network.ap,
-                         format!("{:?}", network.essid),
-                         encryption,
-                         network.signal,
-                         network.channel);
+                    let script_indicator = if has_script {
+                        "$".green().to_string()
+                    } else {
+                        String::from(" ")
+                    };
+
+                    println!(" {} {:?} {:28} security={} auth={:9} signal={:?} dBm channel={:?} ({}) rate={} Mb/s",
+                             script_indicator,
+                             network.ap,
+                             format!("{:?}", network.essid),
+                             security,
+                             if auth.is_empty() { "-" } else { &auth },
+                             network.signal,
+                             network.channel,
+                             band,
+                             rate);
+                }
             }
         },
         Some(SubCommand::Decap(decap)) => {
@@ -96,23 +332,43 @@ fn run() -> Result<()> {
             }
 
             let mut loader = Loader::new();
-            loader.load_all_scripts(&config)?;
+            if let Some(path) = &decap.script {
+                let code = fs::read_to_string(path)
+                    .context(format!("failed to open {:?}", path))?;
+                let name = Path::new(path).file_name()
+                    .and_then(|name| name.to_str())
+                    .unwrap_or(path)
+                    .to_string();
+                loader.load(name, code, true)?;
+            } else {
+                loader.load_all_scripts(&config)?;
+            }
 
             // always request that info before entering stage2
             let system_config = NetworkStatus::from_system()?;
+            let system_knowledge = if decap.standalone {
+                knowledge::read_from(knowledge::PATH)
+                    .unwrap_or_else(|err| {
+                        warn!("failed to read knowledge base: {}", err);
+                        KnowledgeBase::default()
+                    })
+            } else {
+                KnowledgeBase::default()
+            };
 
             if !config.security.danger_disable_seccomp_security {
                 socket = sandbox::decap_stage2(&config, &socket)?;
             }
 
-            let mut status = if !decap.standalone {
+            let (mut status, knowledge) = if !decap.standalone {
                 let mut client = Client::connect(&socket)?;
-                match client.status()? {
+                let status = match client.status()? {
                     Some(status) => status,
                     None => bail!("not connected to a network"),
-                }
+                };
+                (status, client.knowledge()?)
             } else {
-                system_config
+                (system_config, system_knowledge)
             };
 
             let dns = if decap.dns.is_empty() {
@@ -125,8 +381,17 @@ fn run() -> Result<()> {
                 sandbox::decap_stage3()?;
             }
 
-            // TODO: there's no output here unless -v is provided
-            decap::decap(&loader, &mut status, &dns, decap.skip_check)?;
+            // if a specific script was selected, --skip-check also skips detect()
+            let opts = DecapOptions {
+                force_decap: decap.skip_check,
+                skip_detect: decap.skip_check && decap.script.is_some(),
+                knowledge,
+                ..Default::default()
+            };
+            let report = decap::decap(&loader, &config, &mut status, &dns, &opts)?;
+
+            print_report(&report);
+            std::process::exit(report.outcome.exit_code());
         },
         Some(SubCommand::Status(args)) => {
             let mut client = Client::connect(&socket)?;
@@ -141,11 +406,21 @@ fn run() -> Result<()> {
                             Some(ssid) => format!("{:?}", ssid).green(),
                             None       => "unknown".yellow(),
                         });
-                        println!("router:  {:?}", status.router);
+                        if let Some(mac) = &status.mac {
+                            println!("mac:     {}", mac);
+                        }
+                        if let Some(address) = &status.address {
+                            println!("address: {}", address);
+                        }
+                        println!("router:  [{}]", status.routers.iter()
+                                                    .map(|x| x.to_string())
+                                                    .reduce(|a, b| a + ", " + &b)
+                                                    .unwrap_or_else(|| String::new()));
                         println!("dns:     [{}]", status.dns.iter()
                                                     .map(|x| x.to_string())
                                                     .reduce(|a, b| a + ", " + &b)
                                                     .unwrap_or_else(|| String::new()));
+                        print_lease(&status.lease);
                         println!("uplink:  {}", match status.has_uplink {
                             Some(true)  => "yes".green(),
                             Some(false) => "no".red(),
@@ -155,6 +430,16 @@ fn run() -> Result<()> {
                             Some(script) => format!("{:?}", script),
                             None         => "none".to_string(),
                         });
+                        if let Some(check) = &status.dns_check {
+                            println!("dnscheck: {}", print_dns_check(check));
+                        }
+                        if let Some(api) = &status.capport {
+                            println!("capport: {}", print_capport(api));
+                        }
+                        if let Some(report) = status.decap_report {
+                            println!();
+                            print_report(&report);
+                        }
                     },
                     None => {
                         println!("network: {}", "none".red());
@@ -162,6 +447,39 @@ fn run() -> Result<()> {
                 }
             }
         },
+        Some(SubCommand::Networks(args)) => {
+            let mut client = Client::connect(&socket)?;
+            let knowledge = client.knowledge()?;
+
+            if args.json {
+                println!("{}", serde_json::to_string(&knowledge)?);
+            } else {
+                let now = knowledge::now();
+                for (ssid, network) in &knowledge.networks {
+                    print_network(ssid, network, now);
+                }
+            }
+        },
+        Some(SubCommand::Cache(args)) => {
+            let mut client = Client::connect(&socket)?;
+            let stats = client.cache_stats()?;
+
+            if args.json {
+                println!("{}", serde_json::to_string(&stats)?);
+            } else {
+                for (name, stats) in &stats {
+                    println!("{}: {}/{} entries, {} hits, {} negative hits, {} misses, {} evictions, {} flushes",
+                        name.green(),
+                        stats.entries,
+                        stats.capacity,
+                        stats.hits,
+                        stats.negative_hits,
+                        stats.misses,
+                        stats.evictions,
+                        stats.flushes);
+                }
+            }
+        },
         Some(SubCommand::Dns(dns)) => {
             let mut client = Client::connect(&socket)?;
 
@@ -170,9 +488,10 @@ fn run() -> Result<()> {
                 None => bail!("no active network"),
             };
 
+            let record_type = dns::parse_record_type(&dns.record)?;
             let resolver = Resolver::with_udp(&status.dns)?;
-            for ip in resolver.resolve(&dns.query)? {
-                println!("{}", ip);
+            for record in resolver.query(&dns.query, record_type)? {
+                println!("{}", record);
             }
         },
         Some(SubCommand::Http(http)) => {
@@ -215,17 +534,24 @@ fn run() -> Result<()> {
             connect::connect(resolver, &connect.host, connect.port)?;
         },
         Some(SubCommand::Doh(doh)) => {
-            let dns = match &config.dns {
-                Some(config) => config,
-                None => bail!("dns is not configured"),
-            };
+            let record_type = dns::parse_record_type(&doh.record)?;
+
+            let records = if doh.is_override() {
+                let upstreams = doh_upstreams(config.dns.as_ref(), &doh)?;
+                info!("using upstreams: {:?}", upstreams);
+                Resolver::with_servers(&upstreams)?.query(&doh.query, record_type)?
+            } else {
+                let dns = match &config.dns {
+                    Some(config) => config,
+                    None => bail!("dns is not configured"),
+                };
 
-            let resolver = Resolver::with_https(&dns.servers,
-                                                dns.port,
-                                                dns.sni.to_string())?;
+                // honors [dns.records] and [dns.zones]
+                Forwarder::new(dns)?.query(&doh.query, record_type)?
+            };
 
-            for ip in resolver.resolve(&doh.query)? {
-                println!("{}", ip);
+            for record in records {
+                println!("{}", record);
             }
         },
         Some(SubCommand::Completions(args)) => {
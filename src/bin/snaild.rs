@@ -10,73 +10,37 @@ extern crate serde_json;
 use structopt::StructOpt;
 
 use snail::args::snaild::{Args, SubCommand};
-use snail::config::{self, Config};
-use snail::decap;
+use snail::config::{self, Config, DhcpClient};
+use snail::decap::{self, DecapOptions, LatestGeneration};
+use snail::dns::DnsCache;
 use snail::dhcp;
-use snail::errors::{Result, ResultExt};
-use snail::ipc::{Server, Client, CtlRequest, CtlReply};
+use snail::dnsd;
+use snail::errors::{Result, Error, ResultExt};
+use snail::ipc::{Server, Client, CtlRequest, CtlReply, DecapEvent};
+use snail::knowledge::{self, KnowledgeFile};
+use snail::mac;
 use snail::sandbox;
-use snail::scripts::Loader;
-use snail::wifi::NetworkStatus;
+use snail::scripts::{Loader, DetectInfo};
+use snail::wifi::{self, NetworkStatus, ConnectProfile, WpaSupplicant};
 
+use std::collections::BTreeMap;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::thread;
 use std::time::Duration;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
 use std::process::{Command, Child, Stdio};
 use std::io::{self, BufReader};
 
 
-fn dhcp_thread(interface: &str, hook: &str) -> Result<()> {
+fn dhcp_thread(interface: &str, hook: &str, config: &Config) -> Result<()> {
     let dir = tempfile::tempdir()?;
     let conf = dir.path().join("snaild-dhcpcd.conf");
 
     let mut f = File::create(&conf)?;
-    f.write(br#"
-# A sample configuration for dhcpcd.
-# See dhcpcd.conf(5) for details.
-
-# Allow users of this group to interact with dhcpcd via the control socket.
-#controlgroup wheel
-
-# Inform the DHCP server of our hostname for DDNS.
-hostname
-
-# Use the hardware address of the interface for the Client ID.
-#clientid
-# or
-# Use the same DUID + IAID as set in DHCPv6 for DHCPv4 ClientID as per RFC4361.
-# Some non-RFC compliant DHCP servers do not reply with this set.
-# In this case, comment out duid and enable clientid above.
-duid
-
-# Persist interface configuration when dhcpcd exits.
-#persistent
-
-# Rapid commit support.
-# Safe to enable by default because it requires the equivalent option set
-# on the server to actually work.
-option rapid_commit
-
-# A list of options to request from the DHCP server.
-option domain_name_servers, domain_name, domain_search, host_name
-option classless_static_routes
-# Respect the network MTU. This is applied to DHCP routes.
-option interface_mtu
-
-# Most distributions have NTP support.
-#option ntp_servers
-
-# A ServerID is required by RFC2131.
-require dhcp_server_identifier
-
-# Generate SLAAC address using the Hardware Address of the interface
-#slaac hwaddr
-# OR generate Stable Private IPv6 Addresses based from the DUID
-slaac private
-noipv4ll
-"#)?;
+    f.write_all(dhcp::dhcpcd_conf(config).as_bytes())?;
     f.flush()?;
 
     info!("starting dhcpcd");
@@ -85,13 +49,103 @@ noipv4ll
     Ok(())
 }
 
-fn decap_thread_loop(loader: &Loader, status: &mut Option<NetworkStatus>, msg: NetworkStatus) -> Result<()> {
+fn native_dhcp_thread(interface: &str, socket: &str, config: &Config) -> Result<()> {
+    let mut client = Client::connect(socket)?;
+
+    info!("starting dhcp client");
+    dhcp::client::run(interface, config, |message| {
+        let ssid = wifi::current_ssid(interface).ok();
+        let bssid = match ssid {
+            Some(_) => wifi::current_bssid(interface).ok(),
+            None => None,
+        };
+        let network = match ssid {
+            Some(_) => wifi::current_network(interface).ok().map(Box::new),
+            None => None,
+        };
+
+        let event = dhcp::NetworkUpdate {
+            interface: interface.to_string(),
+            ssid,
+            bssid,
+            wifi: network,
+            mac: dhcp::client::hardware_address(interface)
+                .map(mac::format_mac)
+                .ok(),
+            message: Some(message),
+            env: Vec::new(),
+        };
+        client.send(&CtlRequest::DhcpEvent(Box::new(event)))?;
+        Ok(())
+    })?;
+    info!("dhcp client exited");
+    Ok(())
+}
+
+fn find_connect_profile(interface: &str, config: &Config) -> Result<Option<ConnectProfile>> {
+    let scripts = Loader::init_all_scripts_default(config)?;
+
+    let mut networks = wifi::scan(interface)
+                        .context("wifi scan failed")?;
+    // prefer networks with a better signal
+    networks.sort_by_key(|network| -network.signal);
+
+    for network in &networks {
+        let info = DetectInfo::from(network);
+
+        for script in &scripts {
+            match script.detect_network(&info) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => {
+                    warn!("detect of {:?} failed: {}", script.descr(), err);
+                    continue;
+                },
+            }
+
+            match script.connect(&info) {
+                Ok(Some(credentials)) => {
+                    info!("connecting to {:?} with {:?}", network.essid, script.descr());
+                    return Ok(Some(ConnectProfile {
+                        ssid: network.essid.clone(),
+                        credentials,
+                    }));
+                },
+                Ok(None) => (),
+                Err(err) => {
+                    warn!("connect of {:?} failed: {}", script.descr(), err);
+                },
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// The config is removed after wpa_supplicant has been stopped
+fn connect_thread(interface: &str, profile: &ConnectProfile) -> Result<(WpaSupplicant, tempfile::TempDir)> {
+    let dir = tempfile::tempdir()?;
+    let conf = dir.path().join("snaild-wpa_supplicant.conf");
+
+    let mut f = File::create(&conf)?;
+    f.write_all(profile.to_wpa_supplicant()?.as_bytes())?;
+    f.flush()?;
+
+    info!("starting wpa_supplicant");
+    let wpa_supplicant = wifi::run_wpa_supplicant(&conf.to_str().unwrap(), interface)?;
+    Ok((wpa_supplicant, dir))
+}
+
+fn decap_thread_loop(loader: &Loader, config: &Config, status: &mut Option<NetworkStatus>, msg: NetworkStatus, opts: &DecapOptions) -> Result<()> {
     debug!("rx: {:?}", msg);
     thread::sleep(Duration::from_secs(1));
 
     if let Some(ref mut status) = status {
-        // TODO: there should be a way to force decap for some networks
-        decap::decap(loader, status, &msg.dns, false)?;
+        if let Some(profile) = config.network(status.ssid.as_ref().map(|x| x.as_str())) {
+            info!("using network profile: {:?}", profile);
+        }
+
+        decap::decap(loader, config, status, &msg.dns, opts)?;
     } else {
         warn!("not connected to a network");
     }
@@ -99,6 +153,49 @@ fn decap_thread_loop(loader: &Loader, status: &mut Option<NetworkStatus>, msg: N
     Ok(())
 }
 
+fn healthcheck_thread_loop(loader: &Loader, config: &Config, client: &mut Client, interval: &mut Option<Duration>, latest: &LatestGeneration) -> Result<()> {
+    let mut status = match client.status()? {
+        Some(status) => status,
+        None => return Ok(()),
+    };
+
+    // only check networks that are supposed to be working
+    if status.has_uplink != Some(true) {
+        return Ok(());
+    }
+
+    debug!("running healthcheck");
+    if decap::healthcheck(loader, config, &status)? {
+        return Ok(());
+    }
+
+    warn!("network connectivity lost, running decap again");
+    status.set_uplink_status(Some(false));
+    status.script_used = None;
+    if !client.set_status(Some(status.clone()))? {
+        info!("network changed during healthcheck");
+        return Ok(());
+    }
+
+    let opts = DecapOptions {
+        latest: latest.clone(),
+        knowledge: client.knowledge()?,
+        ..Default::default()
+    };
+
+    let recursors = status.dns.clone();
+    decap::decap(loader, config, &mut status, &recursors, &opts)?;
+
+    *interval = decap::healthcheck_interval(loader, config, &status)?;
+    if client.set_status(Some(status))? {
+        debug!("sent network status update");
+    } else {
+        info!("network changed during decap, discarding status");
+    }
+
+    Ok(())
+}
+
 fn decap_thread(socket: &str, config: &Config) -> Result<()> {
     if !config.security.danger_disable_seccomp_security {
         sandbox::decap_stage1()
@@ -107,9 +204,6 @@ fn decap_thread(socket: &str, config: &Config) -> Result<()> {
 
     let mut socket = socket.to_string();
 
-    let stdin = io::stdin();
-    let reader = BufReader::new(stdin);
-
     let mut loader = Loader::new();
     loader.load_all_scripts(config)?;
 
@@ -127,29 +221,158 @@ fn decap_thread(socket: &str, config: &Config) -> Result<()> {
             .context("sandbox decap_stage3 failed")?;
     }
 
-    for msg in reader.lines() {
+    // read events on a separate thread so we can run healthchecks in between,
+    // this thread also tracks the latest generation to abort stale decap runs
+    let latest = LatestGeneration::new();
+    // dns responses are shared between scripts until the network changes
+    let cache = DnsCache::default();
+    let (tx, rx) = mpsc::channel();
+    {
+        let latest = latest.clone();
+        let cache = cache.clone();
+        thread::spawn(move || {
+            let stdin = io::stdin();
+            let reader = BufReader::new(stdin);
+
+            for msg in reader.lines() {
+                let msg = msg.map_err(Error::from)
+                    .and_then(|msg| serde_json::from_str::<DecapEvent>(&msg).map_err(Error::from));
+
+                if let Ok(event) = &msg {
+                    if event.generation() != latest.get() {
+                        cache.flush();
+                    }
+                    latest.set(event.generation());
+                }
+
+                if tx.send(msg).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut interval = None;
+
+    loop {
+        let msg = match interval {
+            Some(timeout) => match rx.recv_timeout(timeout) {
+                Ok(msg) => msg,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Err(error) = healthcheck_thread_loop(&loader, config, &mut client, &mut interval, &latest) {
+                        error!("error in healthcheck: {:?}", error);
+                    }
+                    continue;
+                },
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+            None => match rx.recv() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            },
+        };
+
         debug!("got event for decap: {:?}", msg);
-        let msg = msg?;
-        let msg = serde_json::from_str(&msg)?;
+        let msg = match msg? {
+            DecapEvent::Network(msg) => *msg,
+            DecapEvent::Disconnected(generation) => {
+                debug!("network of generation {} is gone", generation);
+                interval = None;
+                continue;
+            },
+        };
+
+        if latest.is_stale(msg.generation) {
+            debug!("skipping stale event for generation {}", msg.generation);
+            continue;
+        }
 
         let mut status = client.status()?;
         debug!("got current network status");
 
-        if let Err(error) = decap_thread_loop(&loader, &mut status, msg) {
+        let opts = DecapOptions {
+            latest: latest.clone(),
+            knowledge: client.knowledge()?,
+            cache: cache.clone(),
+            ..Default::default()
+        };
+
+        let result = decap_thread_loop(&loader, config, &mut status, msg, &opts);
+        client.set_cache_stats("decap", cache.stats())?;
+
+        if let Err(error) = result {
             error!("error in decap thread: {:?}", error);
         } else {
-            client.set_status(status)?;
-            debug!("sent network status update");
+            interval = match &status {
+                Some(status) => decap::healthcheck_interval(&loader, config, status)?,
+                None => None,
+            };
+            if client.set_status(status)? {
+                debug!("sent network status update");
+            } else {
+                info!("network changed during decap, discarding status");
+                interval = None;
+            }
         }
     }
 
     Ok(())
 }
 
-fn send_to_child(child: &mut Child, status: NetworkStatus) -> Result<()> {
+fn dns_thread(socket: &str, config: &Config) -> Result<()> {
+    let dns_config = match &config.dns {
+        Some(dns_config) => dns_config,
+        None => bail!("dns section is missing in config"),
+    };
+
+    if !config.security.danger_disable_seccomp_security {
+        sandbox::dns_stage1()
+            .context("sandbox dns_stage1 failed")?;
+    }
+
+    let mut socket = socket.to_string();
+
+    let server = dnsd::Server::bind(&dns_config.bind)?;
+    let forwarder = Arc::new(dnsd::Forwarder::new(dns_config)?);
+
+    if !config.security.danger_disable_seccomp_security {
+        socket = sandbox::dns_stage2(&config, &socket)
+            .context("sandbox dns_stage2 failed")?;
+    }
+
+    let mut client = Client::connect(&socket)?;
+    // ensure the connection is fully setup
+    client.ping()?;
+
+    if !config.security.danger_disable_seccomp_security {
+        sandbox::dns_stage3()
+            .context("sandbox dns_stage3 failed")?;
+    }
+
+    // follow the network status so we know when to use the dhcp recursors
+    {
+        let forwarder = forwarder.clone();
+        thread::spawn(move || loop {
+            match client.status() {
+                Ok(status) => if let Err(err) = forwarder.update(status.as_ref()) {
+                    error!("failed to update dns recursors: {:?}", err);
+                },
+                Err(err) => warn!("failed to get network status: {:?}", err),
+            }
+            if let Err(err) = client.set_cache_stats("dns", forwarder.cache_stats()) {
+                warn!("failed to send dns cache stats: {:?}", err);
+            }
+            thread::sleep(dnsd::STATUS_INTERVAL);
+        });
+    }
+
+    server.run(forwarder)
+}
+
+fn send_to_child(child: &mut Child, event: DecapEvent) -> Result<()> {
     if let Some(stdin) = &mut child.stdin {
-        debug!("sending to child: {:?}", status);
-        let mut msg = serde_json::to_string(&status)?;
+        debug!("sending to child: {:?}", event);
+        let mut msg = serde_json::to_string(&event)?;
         msg += "\n";
         stdin.write_all(msg.as_bytes())?;
         stdin.flush()?;
@@ -166,12 +389,24 @@ fn zmq_thread(socket: &str, mut decap: Child, config: &mut Config) -> Result<()>
             .context("sandbox zmq_stage1 failed")?;
     }
 
-    let mut status = None;
+    let mut status: Option<NetworkStatus> = None;
+    let mut generation = 0;
+    // reported by the processes that cache dns responses
+    let mut cache_stats = BTreeMap::new();
     let mut socket = socket.to_string();
 
     // resolve gid before running chroot
     config.daemon.resolve_gid()?;
 
+    // the knowledge base is outside of the chroot, keep the file open
+    let mut knowledge = match KnowledgeFile::open(knowledge::PATH) {
+        Ok(knowledge) => Some(knowledge),
+        Err(err) => {
+            warn!("failed to open knowledge base: {}", err);
+            None
+        },
+    };
+
     if !config.security.danger_disable_seccomp_security {
         sandbox::zmq_stage2()
             .context("sandbox zmq_stage2 failed")?;
@@ -201,15 +436,25 @@ fn zmq_thread(socket: &str, mut decap: Child, config: &mut Config) -> Result<()>
                     },
                     Some(UpdateMessage::Bound(net)) => {
                         info!("successful dhcp bound");
-                        let network = NetworkStatus::new(event.ssid, net);
+                        generation += 1;
+                        let mut network = NetworkStatus::new(event.ssid, net);
+                        network.generation = generation;
+                        network.bssid = event.bssid;
+                        network.mac = event.mac;
+                        network.wifi = event.wifi;
                         status = Some(network.clone());
-                        send_to_child(&mut decap, network)?;
+                        send_to_child(&mut decap, DecapEvent::Network(Box::new(network)))?;
                     },
                     Some(UpdateMessage::Reboot(net)) => {
                         info!("successful dhcp reboot");
-                        let network = NetworkStatus::new(event.ssid, net);
+                        generation += 1;
+                        let mut network = NetworkStatus::new(event.ssid, net);
+                        network.generation = generation;
+                        network.bssid = event.bssid;
+                        network.mac = event.mac;
+                        network.wifi = event.wifi;
                         status = Some(network.clone());
-                        send_to_child(&mut decap, network)?;
+                        send_to_child(&mut decap, DecapEvent::Network(Box::new(network)))?;
                     },
                     Some(UpdateMessage::Renew(_net)) => {
                         // ignore
@@ -217,7 +462,16 @@ fn zmq_thread(socket: &str, mut decap: Child, config: &mut Config) -> Result<()>
                     },
                     Some(UpdateMessage::NoCarrier) => {
                         info!("carrier lost");
+                        // invalidate decap runs for the old network
+                        generation += 1;
+                        status = None;
+                        send_to_child(&mut decap, DecapEvent::Disconnected(generation))?;
+                    },
+                    Some(UpdateMessage::Expired) => {
+                        info!("dhcp lease expired");
+                        generation += 1;
                         status = None;
+                        send_to_child(&mut decap, DecapEvent::Disconnected(generation))?;
                     },
                     Some(UpdateMessage::Stopped) => {
                         // ignore
@@ -229,12 +483,44 @@ fn zmq_thread(socket: &str, mut decap: Child, config: &mut Config) -> Result<()>
                 CtlReply::Ack
             },
             CtlRequest::StatusRequest => {
-                CtlReply::Status(status.clone())
+                CtlReply::Status(status.clone().map(Box::new))
             },
-            CtlRequest::SetStatus(update) => {
-                status = update;
+            CtlRequest::CacheStatsRequest => {
+                CtlReply::CacheStats(cache_stats.clone())
+            },
+            CtlRequest::SetCacheStats(name, stats) => {
+                cache_stats.insert(name, stats);
                 CtlReply::Ack
             },
+            CtlRequest::KnowledgeRequest => {
+                CtlReply::Knowledge(knowledge.as_ref()
+                    .map(|knowledge| knowledge.knowledge.clone())
+                    .unwrap_or_default())
+            },
+            CtlRequest::SetStatus(update) => {
+                let current = status.as_ref().map(|status| status.generation);
+                let updated = update.as_ref().map(|status| status.generation);
+
+                if current == updated {
+                    let previous = status.as_ref().and_then(|status| status.decap_report.as_ref());
+                    let report = update.as_ref().and_then(|status| status.decap_report.as_ref());
+
+                    // only record new decap runs
+                    if let (Some(knowledge), Some(network)) = (&mut knowledge, &update) {
+                        if report.is_some() && report != previous {
+                            if let Err(err) = knowledge.record(network) {
+                                warn!("failed to update knowledge base: {}", err);
+                            }
+                        }
+                    }
+
+                    status = update.map(|update| *update);
+                    CtlReply::Ack
+                } else {
+                    warn!("rejecting stale status update: {:?} (current: {:?})", updated, current);
+                    CtlReply::Stale
+                }
+            },
         };
 
         server.reply(&reply)?;
@@ -254,8 +540,24 @@ fn notify_daemon() -> Result<()> {
                     .context("failed to load config")?;
     debug!("config: {:?}", config);
 
+    match event.message {
+        // dhcpcd waits for the hook before it starts on the new carrier
+        Some(dhcp::UpdateMessage::Carrier) => {
+            let ssid = event.ssid.as_ref().map(|x| x.as_str());
+            if mac::apply_on_carrier(&config, &event.interface, ssid)
+                    .context("failed to apply mac policy")? {
+                info!("mac address changed, waiting for the next carrier");
+                return Ok(());
+            }
+        },
+        Some(dhcp::UpdateMessage::NoCarrier) | Some(dhcp::UpdateMessage::Expired) => {
+            mac::release(&event.interface);
+        },
+        _ => (),
+    }
+
     let mut client = Client::connect(&config.daemon.socket)?;
-    client.send(&CtlRequest::DhcpEvent(event))?;
+    client.send(&CtlRequest::DhcpEvent(Box::new(event)))?;
 
     /*
     println!("{}", r"//////////////////////////////".red());
@@ -304,6 +606,31 @@ fn run() -> Result<()> {
 
                     // TODO: log level isn't forwarded to children
 
+                    let profile = if config.wifi.connect {
+                        let profile = find_connect_profile(&args.interface, &config)?;
+                        if profile.is_none() {
+                            info!("no script provided a connection profile");
+                        }
+                        profile
+                    } else {
+                        None
+                    };
+
+                    // the address has to be set before we associate with a network,
+                    // policies that depend on the network are applied again on carrier
+                    let ssid = match &profile {
+                        Some(profile) => Some(profile.ssid.clone()),
+                        None => wifi::current_ssid(&args.interface).ok(),
+                    };
+                    mac::assign(&config, &args.interface, ssid.as_ref().map(|x| x.as_str()))
+                        .context("failed to apply mac policy")?;
+
+                    // the config needs to stay around as long as wpa_supplicant is running
+                    let _wpa_supplicant = match &profile {
+                        Some(profile) => Some(connect_thread(&args.interface, profile)?),
+                        None => None,
+                    };
+
                     let _dhcp_child = Command::new(&myself)
                         .args(&["dhcp", &args.interface])
                         .stdin(Stdio::null())
@@ -320,17 +647,25 @@ fn run() -> Result<()> {
 
                     zmq_thread(&socket, decap_child, &mut config)
                 },
-                Some(SubCommand::Dhcp(args)) => {
-                    let hook = {
-                        let h = env::current_exe().unwrap();
-                        h.to_str().unwrap().to_string()
-                    };
+                Some(SubCommand::Dhcp(args)) => match config.dhcp.client {
+                    DhcpClient::Native => {
+                        native_dhcp_thread(&args.interface, &socket, &config)
+                    },
+                    DhcpClient::Dhcpcd => {
+                        let hook = {
+                            let h = env::current_exe().unwrap();
+                            h.to_str().unwrap().to_string()
+                        };
 
-                    dhcp_thread(&args.interface, &hook)
+                        dhcp_thread(&args.interface, &hook, &config)
+                    },
                 },
                 Some(SubCommand::Decap) => {
                     decap_thread(&socket, &config)
                 },
+                Some(SubCommand::Dns) => {
+                    dns_thread(&socket, &config)
+                },
                 None => {
                     error!("dhcp event expected but not found");
                     Ok(())
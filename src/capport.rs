@@ -0,0 +1,133 @@
+use dns::Resolver;
+use errors::Result;
+use structs::LuaMap;
+use web::{Client, HttpClient};
+
+use hlua::AnyLuaValue;
+use http::{Request, Uri};
+use hyper::Body;
+use serde_json;
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+
+/// The media type of the captive portal api (RFC 8908)
+pub const MEDIA_TYPE: &str = "application/captive+json";
+/// Sent by networks without a captive portal instead of an api url (RFC 8910)
+pub const UNRESTRICTED: &str = "urn:ietf:params:capport:unrestricted";
+
+/// The state of our session according to the captive portal api
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="kebab-case")]
+pub struct CaptivePortalStatus {
+    pub captive: bool,
+    pub user_portal_url: Option<String>,
+    pub venue_info_url: Option<String>,
+    pub can_extend_session: Option<bool>,
+    pub seconds_remaining: Option<u64>,
+    pub bytes_remaining: Option<u64>,
+}
+
+impl Into<AnyLuaValue> for CaptivePortalStatus {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        map.insert("captive", AnyLuaValue::LuaBoolean(self.captive));
+        if let Some(url) = self.user_portal_url {
+            map.insert_str("user_portal_url", url);
+        }
+        if let Some(url) = self.venue_info_url {
+            map.insert_str("venue_info_url", url);
+        }
+        if let Some(extend) = self.can_extend_session {
+            map.insert("can_extend_session", AnyLuaValue::LuaBoolean(extend));
+        }
+        if let Some(seconds) = self.seconds_remaining {
+            map.insert_num("seconds_remaining", seconds as f64);
+        }
+        if let Some(bytes) = self.bytes_remaining {
+            map.insert_num("bytes_remaining", bytes as f64);
+        }
+
+        map.into()
+    }
+}
+
+/// The api url from dhcp, None if the network announced that it's unrestricted
+pub fn api_url(uri: &str) -> Option<String> {
+    let uri = uri.trim();
+    if uri.is_empty() || uri == UNRESTRICTED {
+        None
+    } else {
+        Some(uri.to_string())
+    }
+}
+
+pub fn parse(body: &str) -> Result<CaptivePortalStatus> {
+    let status = serde_json::from_str(body)?;
+    Ok(status)
+}
+
+/// Query the api with the dhcp recursors, the api is required to use https
+pub fn query(url: &str, recursors: &[IpAddr], timeout: Duration) -> Result<CaptivePortalStatus> {
+    let uri = url.parse::<Uri>()?;
+    if uri.scheme_str() != Some("https") {
+        bail!("captive portal api has to use https: {:?}", url);
+    }
+
+    let mut client = Client::new(Resolver::with_udp(recursors)?);
+    client.set_timeout(timeout);
+
+    let request = Request::builder()
+        .uri(uri.clone())
+        .header("Accept", MEDIA_TYPE)
+        .body(Body::empty())?;
+    let res = client.request(&uri, request)?;
+
+    if res.status != 200 {
+        bail!("captive portal api returned status {}", res.status);
+    }
+
+    parse(&res.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        // RFC 8908, section 5
+        let status = parse(r#"{
+            "captive": true,
+            "user-portal-url": "https://example.org/portal.html",
+            "venue-info-url": "https://flight.example.com/entertainment",
+            "seconds-remaining": 326,
+            "can-extend-session": true,
+            "something-new": "ignored"
+        }"#).unwrap();
+
+        assert_eq!(status, CaptivePortalStatus {
+            captive: true,
+            user_portal_url: Some("https://example.org/portal.html".into()),
+            venue_info_url: Some("https://flight.example.com/entertainment".into()),
+            can_extend_session: Some(true),
+            seconds_remaining: Some(326),
+            bytes_remaining: None,
+        });
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(parse(r#"{"user-portal-url": "https://example.org/"}"#).is_err());
+        assert!(parse("<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_api_url() {
+        assert_eq!(api_url("https://example.org/api\n"), Some("https://example.org/api".into()));
+        assert_eq!(api_url(UNRESTRICTED), None);
+        assert_eq!(api_url(""), None);
+    }
+}
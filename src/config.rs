@@ -1,4 +1,5 @@
-use errors::Result;
+use dns::{self, normalize_name, in_zone};
+use errors::{Result, Error};
 use ipc;
 
 use toml;
@@ -6,8 +7,9 @@ use users;
 
 use std::fs;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
+use std::str::FromStr;
 
 
 pub const PATH: &str = "/etc/snail/snail.conf";
@@ -21,6 +23,56 @@ pub struct Config {
     pub dns: Option<DnsConfig>,
     #[serde(default)]
     pub scripts: ScriptConfig,
+    #[serde(default)]
+    pub decap: DecapConfig,
+    #[serde(default)]
+    pub wifi: WifiConfig,
+    #[serde(default)]
+    pub dhcp: DhcpConfig,
+    #[serde(default)]
+    pub networks: HashMap<String, NetworkProfile>,
+}
+
+impl Config {
+    /// The profile of a specific network, if there is one
+    pub fn network(&self, ssid: Option<&str>) -> Option<&NetworkProfile> {
+        ssid.and_then(|ssid| self.networks.get(ssid))
+    }
+
+    /// The mac policy for a specific network
+    pub fn mac_policy(&self, ssid: Option<&str>) -> MacPolicy {
+        self.network(ssid)
+            .and_then(|profile| profile.mac)
+            .unwrap_or(self.wifi.mac)
+    }
+
+    /// The dhcp settings for a specific network
+    pub fn dhcp_config(&self, ssid: Option<&str>) -> DhcpConfig {
+        match self.network(ssid) {
+            Some(profile) => self.dhcp.merge(&profile.dhcp),
+            None => self.dhcp.clone(),
+        }
+    }
+
+    /// Reject network profiles that can't be applied by the dhcp client
+    fn validate(&self) -> Result<()> {
+        if self.dhcp.client == DhcpClient::Dhcpcd && self.dhcp.send_hostname {
+            for (ssid, profile) in &self.networks {
+                if profile.dhcp.send_hostname == Some(false) {
+                    bail!("dhcpcd can't disable send_hostname for {:?}, it's enabled in the [dhcp] section", ssid);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The captive portal probes for a specific network
+    pub fn probes(&self, ssid: Option<&str>) -> &[Probe] {
+        match self.network(ssid).and_then(|profile| profile.probes.as_ref()) {
+            Some(probes) => probes,
+            None => &self.decap.probes,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -68,11 +120,134 @@ pub struct SecurityConfig {
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct DnsConfig {
+    #[serde(default="default_dns_bind")]
+    pub bind: SocketAddr,
+    /// shorthand for upstreams that share protocol, port and tls name
+    #[serde(default)]
     pub servers: Vec<IpAddr>,
-    pub port: u16,
-    pub sni: String,
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    /// defaults to the standard port of the protocol
+    pub port: Option<u16>,
+    /// the name in the certificate of the servers
+    #[serde(default)]
+    pub sni: Option<String>,
+    /// upstreams with their own protocol, port and tls name
+    #[serde(default)]
+    pub upstreams: Vec<DnsServer>,
+    /// static records, the name needs to match exactly
+    #[serde(default)]
+    pub records: HashMap<String, Vec<IpAddr>>,
+    /// forward names inside those zones to specific recursors
+    #[serde(default)]
+    pub zones: HashMap<String, Vec<IpAddr>>,
+    /// while a captive portal is active, only send the domains of the portal
+    /// to the dhcp recursors instead of all queries
+    #[serde(default)]
+    pub portal_domains_only: bool,
+    /// the number of dns responses that are cached, 0 disables the cache
+    #[serde(default="default_cache_size")]
+    pub cache_size: usize,
 }
 
+impl DnsConfig {
+    /// All upstreams, the servers from the shorthand first
+    pub fn upstreams(&self) -> Vec<DnsServer> {
+        self.servers.iter()
+            .map(|ip| DnsServer {
+                ip: *ip,
+                protocol: self.protocol,
+                port: self.port,
+                tls_name: self.sni.clone(),
+            })
+            .chain(self.upstreams.iter().cloned())
+            .collect()
+    }
+
+    /// The static records for a name, if there are any
+    pub fn record(&self, name: &str) -> Option<&[IpAddr]> {
+        let name = normalize_name(name);
+        self.records.iter()
+            .find(|(key, _)| normalize_name(key) == name)
+            .map(|(_, ips)| ips.as_slice())
+    }
+
+    /// The zone with the longest suffix that contains this name
+    pub fn zone(&self, name: &str) -> Option<(&str, &[IpAddr])> {
+        let name = normalize_name(name);
+        self.zones.iter()
+            .filter(|(zone, _)| in_zone(&name, &normalize_name(zone)))
+            .max_by_key(|(zone, _)| normalize_name(zone).len())
+            .map(|(zone, recursors)| (zone.as_str(), recursors.as_slice()))
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum DnsProtocol {
+    Udp,
+    Tcp,
+    Tls,
+    Https,
+}
+
+impl DnsProtocol {
+    pub fn default_port(self) -> u16 {
+        match self {
+            DnsProtocol::Udp | DnsProtocol::Tcp => 53,
+            DnsProtocol::Tls => 853,
+            DnsProtocol::Https => 443,
+        }
+    }
+
+    /// Encrypted protocols need the name of the server to verify the certificate
+    pub fn is_encrypted(self) -> bool {
+        match self {
+            DnsProtocol::Udp | DnsProtocol::Tcp => false,
+            DnsProtocol::Tls | DnsProtocol::Https => true,
+        }
+    }
+}
+
+impl Default for DnsProtocol {
+    fn default() -> DnsProtocol {
+        DnsProtocol::Https
+    }
+}
+
+impl FromStr for DnsProtocol {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DnsProtocol> {
+        match s {
+            "udp" => Ok(DnsProtocol::Udp),
+            "tcp" => Ok(DnsProtocol::Tcp),
+            "tls" => Ok(DnsProtocol::Tls),
+            "https" => Ok(DnsProtocol::Https),
+            _ => bail!("unknown dns protocol: {:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DnsServer {
+    pub ip: IpAddr,
+    #[serde(default)]
+    pub protocol: DnsProtocol,
+    /// defaults to the standard port of the protocol
+    pub port: Option<u16>,
+    /// the name in the certificate of the server, required for tls and https
+    pub tls_name: Option<String>,
+}
+
+impl DnsServer {
+    pub fn socket_addr(&self) -> SocketAddr {
+        let port = self.port.unwrap_or_else(|| self.protocol.default_port());
+        SocketAddr::new(self.ip, port)
+    }
+}
+
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct ScriptConfig {
     pub paths: HashMap<String, ScriptFolder>,
@@ -93,15 +268,357 @@ impl Default for ScriptConfig {
 pub struct ScriptFolder {
 }
 
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+pub struct WifiConfig {
+    /// use the connect hook of scripts to setup wpa_supplicant
+    #[serde(default)]
+    pub connect: bool,
+    /// the mac address that is used when snaild starts on the interface
+    #[serde(default)]
+    pub mac: MacPolicy,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum MacPolicy {
+    /// the hardware address of the interface
+    Permanent,
+    /// a random address that is kept until the next reboot
+    Boot,
+    /// a random address that is always the same for a network
+    Ssid,
+    /// a new random address every time we connect
+    Connect,
+}
+
+impl Default for MacPolicy {
+    fn default() -> MacPolicy {
+        MacPolicy::Permanent
+    }
+}
+
+/// The defaults are chosen so we don't identify ourselves to every network
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DhcpConfig {
+    /// the dhcp client that obtains the lease for the interface
+    #[serde(default)]
+    pub client: DhcpClient,
+    /// send our hostname to the dhcp server, eg. for ddns
+    #[serde(default)]
+    pub send_hostname: bool,
+    /// how we identify ourselves to the dhcp server, only used by dhcpcd
+    #[serde(default)]
+    pub client_id: ClientId,
+    /// enable slaac and dhcpv6, only used by dhcpcd
+    #[serde(default="default_true")]
+    pub ipv6: bool,
+    /// options that are requested from the server, in dhcpcd.conf syntax.
+    /// the native client always requests the options it understands.
+    #[serde(default="default_dhcp_options")]
+    pub options: Vec<String>,
+    /// configured if no dhcp server answered
+    pub fallback: Option<StaticAddress>,
+}
+
+impl Default for DhcpConfig {
+    fn default() -> DhcpConfig {
+        DhcpConfig {
+            client: DhcpClient::default(),
+            send_hostname: false,
+            client_id: ClientId::default(),
+            ipv6: true,
+            options: default_dhcp_options(),
+            fallback: None,
+        }
+    }
+}
+
+impl DhcpConfig {
+    /// Apply the overrides of a network profile
+    pub fn merge(&self, profile: &DhcpProfile) -> DhcpConfig {
+        let mut config = self.clone();
+        if let Some(send_hostname) = profile.send_hostname {
+            config.send_hostname = send_hostname;
+        }
+        if let Some(client_id) = profile.client_id {
+            config.client_id = client_id;
+        }
+        if let Some(ipv6) = profile.ipv6 {
+            config.ipv6 = ipv6;
+        }
+        for option in &profile.options {
+            if !config.options.contains(option) {
+                config.options.push(option.to_string());
+            }
+        }
+        if profile.fallback.is_some() {
+            config.fallback = profile.fallback.clone();
+        }
+        config
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum DhcpClient {
+    /// the builtin dhcpv4 client
+    Native,
+    /// run dhcpcd and receive the lease through its hook
+    Dhcpcd,
+}
+
+/// dhcpcd stays the default until the builtin client has seen more networks
+impl Default for DhcpClient {
+    fn default() -> DhcpClient {
+        DhcpClient::Dhcpcd
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum ClientId {
+    /// the hardware address of the interface, changes with the mac address
+    Hwaddr,
+    /// a DUID that is stored by dhcpcd and stays the same on every network (RFC 4361)
+    Duid,
+}
+
+impl Default for ClientId {
+    fn default() -> ClientId {
+        ClientId::Hwaddr
+    }
+}
+
+/// A static ipv4 configuration that is used if dhcp fails
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct StaticAddress {
+    pub address: Ipv4Addr,
+    #[serde(default="default_subnet_cidr")]
+    pub subnet_cidr: u8,
+    #[serde(default)]
+    pub routers: Vec<Ipv4Addr>,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+}
+
+/// Overrides for the [dhcp] section in a network profile
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+pub struct DhcpProfile {
+    pub send_hostname: Option<bool>,
+    pub client_id: Option<ClientId>,
+    pub ipv6: Option<bool>,
+    /// requested in addition to the options in the [dhcp] section
+    #[serde(default)]
+    pub options: Vec<String>,
+    pub fallback: Option<StaticAddress>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DecapConfig {
+    #[serde(default="default_probes")]
+    pub probes: Vec<Probe>,
+    /// timeout for each probe in seconds
+    #[serde(default="default_probe_timeout")]
+    pub probe_timeout: u64,
+    /// seconds between healthchecks if the script doesn't set an interval, 0 disables
+    #[serde(default="default_healthcheck_interval")]
+    pub healthcheck_interval: u64,
+    #[serde(default)]
+    pub dns_check: DnsCheckConfig,
+}
+
+impl Default for DecapConfig {
+    fn default() -> DecapConfig {
+        DecapConfig {
+            probes: default_probes(),
+            probe_timeout: default_probe_timeout(),
+            healthcheck_interval: default_healthcheck_interval(),
+            dns_check: DnsCheckConfig::default(),
+        }
+    }
+}
+
+/// Checks if the network rewrites dns answers or intercepts dns traffic
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DnsCheckConfig {
+    #[serde(default="default_true")]
+    pub enabled: bool,
+    /// names with known records, the dhcp recursors need to return one of them
+    #[serde(default="default_canaries")]
+    pub canaries: HashMap<String, Vec<IpAddr>>,
+    /// names that are compared with the answers of the [dns] upstreams
+    #[serde(default)]
+    pub compare: Vec<String>,
+    /// an address that doesn't run a dns server, any answer means udp/53 is
+    /// intercepted by the network
+    #[serde(default="default_intercept_addr")]
+    pub intercept_addr: SocketAddr,
+}
+
+impl Default for DnsCheckConfig {
+    fn default() -> DnsCheckConfig {
+        DnsCheckConfig {
+            enabled: true,
+            canaries: default_canaries(),
+            compare: Vec::new(),
+            intercept_addr: default_intercept_addr(),
+        }
+    }
+}
+
+/// Overrides for a specific network, selected by ssid
+#[derive(Debug, PartialEq, Default, Clone, Serialize, Deserialize)]
+pub struct NetworkProfile {
+    /// always run the scripts, even if the probes didn't detect a portal
+    #[serde(default)]
+    pub force_decap: bool,
+    /// never run any scripts on this network, eg. home or office networks
+    #[serde(default)]
+    pub disable_decap: bool,
+    /// only use the script with this descr and skip its detect functions
+    pub script: Option<String>,
+    /// use those dns servers instead of the ones provided by dhcp
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    /// replaces the probes in the [decap] section
+    pub probes: Option<Vec<Probe>>,
+    #[serde(default)]
+    pub dhcp: DhcpProfile,
+    /// replaces the mac policy in the [wifi] section
+    pub mac: Option<MacPolicy>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag="type", rename_all="lowercase")]
+pub enum Probe {
+    Http(HttpProbe),
+    Dns(DnsProbe),
+}
+
+impl Probe {
+    pub fn name(&self) -> &str {
+        match self {
+            Probe::Http(probe) => &probe.url,
+            Probe::Dns(probe) => &probe.name,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct HttpProbe {
+    pub url: String,
+    pub status: Option<u16>,
+    /// the response body needs to contain this string
+    pub body: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct DnsProbe {
+    pub name: String,
+    /// at least one of those needs to be returned
+    pub records: Vec<IpAddr>,
+}
+
 fn default_socket() -> String {
     ipc::SOCKET.to_string()
 }
 
+fn default_dns_bind() -> SocketAddr {
+    "127.0.0.1:53".parse().unwrap()
+}
+
+fn default_cache_size() -> usize {
+    dns::DEFAULT_CACHE_SIZE
+}
+
 fn default_agent() -> String {
     format!("snail/{}.{}", env!("CARGO_PKG_VERSION_MAJOR"),
                            env!("CARGO_PKG_VERSION_MINOR"))
 }
 
+fn default_probes() -> Vec<Probe> {
+    vec![
+        // android captive portal detection
+        Probe::Http(HttpProbe {
+            url: "http://clients3.google.com/generate_204".to_string(),
+            status: Some(204),
+            body: None,
+            headers: HashMap::new(),
+        }),
+        // apple hotspot detection
+        Probe::Http(HttpProbe {
+            url: "http://captive.apple.com/hotspot-detect.html".to_string(),
+            status: Some(200),
+            body: Some("<TITLE>Success</TITLE>".to_string()),
+            headers: HashMap::new(),
+        }),
+        // firefox captive portal detection
+        Probe::Http(HttpProbe {
+            url: "http://detectportal.firefox.com/success.txt".to_string(),
+            status: Some(200),
+            body: Some("success".to_string()),
+            headers: HashMap::new(),
+        }),
+        // windows network connectivity status indicator
+        Probe::Dns(DnsProbe {
+            name: "dns.msftncsi.com".to_string(),
+            records: vec!["131.107.255.255".parse().unwrap()],
+        }),
+    ]
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_dhcp_options() -> Vec<String> {
+    [
+        "domain_name_servers",
+        "domain_name",
+        "domain_search",
+        "classless_static_routes",
+        "interface_mtu",
+        // the captive portal api, RFC 8910
+        "captive_portal",
+    ].iter().map(|x| (*x).to_string()).collect()
+}
+
+fn default_subnet_cidr() -> u8 {
+    24
+}
+
+fn default_canaries() -> HashMap<String, Vec<IpAddr>> {
+    let mut canaries = HashMap::new();
+    canaries.insert("one.one.one.one".to_string(), vec![
+        "1.1.1.1".parse().unwrap(),
+        "1.0.0.1".parse().unwrap(),
+        "2606:4700:4700::1111".parse().unwrap(),
+        "2606:4700:4700::1001".parse().unwrap(),
+    ]);
+    canaries.insert("dns.google".to_string(), vec![
+        "8.8.8.8".parse().unwrap(),
+        "8.8.4.4".parse().unwrap(),
+        "2001:4860:4860::8888".parse().unwrap(),
+        "2001:4860:4860::8844".parse().unwrap(),
+    ]);
+    canaries
+}
+
+fn default_intercept_addr() -> SocketAddr {
+    // TEST-NET-1, there's never a dns server at this address
+    "192.0.2.53:53".parse().unwrap()
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+fn default_healthcheck_interval() -> u64 {
+    300
+}
+
 pub fn read_from(path: &str) -> Result<Config> {
     if Path::new(path).exists() {
         let text = fs::read_to_string(path)?;
@@ -113,7 +630,8 @@ pub fn read_from(path: &str) -> Result<Config> {
 
 #[inline]
 pub fn load(text: &str) -> Result<Config> {
-    let conf = toml::from_str(&text)?;
+    let conf: Config = toml::from_str(&text)?;
+    conf.validate()?;
     Ok(conf)
 }
 
@@ -128,7 +646,7 @@ mod tests {
 
     #[test]
     fn test_dns_config() {
-        let _config = load(r#"
+        let config = load(r#"
         [dns]
         bind = "127.0.0.1:53"
 
@@ -147,6 +665,100 @@ mod tests {
         "example.com" = ["192.0.2.2", "2001:DB8::2"]
         "corp.example.com" = ["192.0.2.3", "2001:DB8::3"]
         "#).expect("failed to load config");
+
+        let dns = config.dns.expect("dns section missing");
+        assert_eq!(dns.bind, "127.0.0.1:53".parse().unwrap());
+        assert_eq!(dns.port, Some(443));
+        assert_eq!(dns.records.len(), 2);
+        assert_eq!(dns.zones.len(), 2);
+        assert_eq!(dns.cache_size, 1024);
+    }
+
+    #[test]
+    fn test_dns_upstreams() {
+        let config = load(r#"
+        [dns]
+        servers = ["1.1.1.1"]
+        sni = "cloudflare-dns.com"
+
+        [[dns.upstreams]]
+        ip = "9.9.9.9"
+        protocol = "tls"
+        tls_name = "dns.quad9.net"
+
+        [[dns.upstreams]]
+        ip = "192.0.2.53"
+        protocol = "udp"
+        port = 5353
+        "#).expect("failed to load config");
+
+        let upstreams = config.dns.expect("dns section missing").upstreams();
+        assert_eq!(upstreams, vec![
+            DnsServer {
+                ip: "1.1.1.1".parse().unwrap(),
+                protocol: DnsProtocol::Https,
+                port: None,
+                tls_name: Some("cloudflare-dns.com".into()),
+            },
+            DnsServer {
+                ip: "9.9.9.9".parse().unwrap(),
+                protocol: DnsProtocol::Tls,
+                port: None,
+                tls_name: Some("dns.quad9.net".into()),
+            },
+            DnsServer {
+                ip: "192.0.2.53".parse().unwrap(),
+                protocol: DnsProtocol::Udp,
+                port: Some(5353),
+                tls_name: None,
+            },
+        ]);
+
+        assert_eq!(upstreams[0].socket_addr(), "1.1.1.1:443".parse().unwrap());
+        assert_eq!(upstreams[1].socket_addr(), "9.9.9.9:853".parse().unwrap());
+        assert_eq!(upstreams[2].socket_addr(), "192.0.2.53:5353".parse().unwrap());
+    }
+
+    fn dns_config() -> DnsConfig {
+        load(r#"
+        [dns]
+        servers = ["1.1.1.1"]
+        port = 443
+        sni = "cloudflare-dns.com"
+
+        [dns.records]
+        "foo.example.com" = ["192.0.2.10", "2001:DB8::10"]
+
+        [dns.zones]
+        "example.com" = ["192.0.2.2"]
+        "corp.example.com." = ["192.0.2.3"]
+        "#).expect("failed to load config").dns.unwrap()
+    }
+
+    #[test]
+    fn test_dns_records() {
+        let dns = dns_config();
+        let ips: Vec<IpAddr> = vec!["192.0.2.10".parse().unwrap(), "2001:DB8::10".parse().unwrap()];
+        assert_eq!(dns.record("foo.example.com"), Some(&ips[..]));
+        assert_eq!(dns.record("FOO.example.com."), Some(&ips[..]));
+        assert_eq!(dns.record("bar.foo.example.com"), None);
+        assert_eq!(dns.record("example.com"), None);
+    }
+
+    #[test]
+    fn test_dns_zones() {
+        let dns = dns_config();
+        let zone = |name| dns.zone(name).map(|(zone, recursors)| (zone.to_string(), recursors.to_vec()));
+
+        let example: Vec<IpAddr> = vec!["192.0.2.2".parse().unwrap()];
+        let corp: Vec<IpAddr> = vec!["192.0.2.3".parse().unwrap()];
+
+        assert_eq!(zone("example.com"), Some(("example.com".into(), example.clone())));
+        assert_eq!(zone("www.example.com."), Some(("example.com".into(), example)));
+        assert_eq!(zone("corp.example.com"), Some(("corp.example.com.".into(), corp.clone())));
+        assert_eq!(zone("git.Corp.Example.com"), Some(("corp.example.com.".into(), corp)));
+        assert_eq!(zone("notexample.com"), None);
+        assert_eq!(zone("example.org"), None);
     }
 
     #[test]
@@ -179,6 +791,196 @@ mod tests {
         "#).expect("failed to load config");
     }
 
+    #[test]
+    fn test_default_probes() {
+        let config = load("").expect("failed to load config");
+        assert_eq!(config.decap.probes.len(), 4);
+        assert_eq!(config.decap.probe_timeout, 5);
+    }
+
+    #[test]
+    fn test_decap_probes() {
+        let config = load(r#"
+        [decap]
+        probe_timeout = 3
+
+        [[decap.probes]]
+        type = "http"
+        url = "http://captive.apple.com/hotspot-detect.html"
+        status = 200
+        body = "Success"
+
+        [[decap.probes]]
+        type = "http"
+        url = "http://example.com/"
+        headers = { "X-Canary" = "1" }
+
+        [[decap.probes]]
+        type = "dns"
+        name = "dns.msftncsi.com"
+        records = ["131.107.255.255"]
+        "#).expect("failed to load config");
+
+        let mut headers = HashMap::new();
+        headers.insert("X-Canary".into(), "1".into());
+        assert_eq!(config.decap, DecapConfig {
+            probes: vec![
+                Probe::Http(HttpProbe {
+                    url: "http://captive.apple.com/hotspot-detect.html".into(),
+                    status: Some(200),
+                    body: Some("Success".into()),
+                    headers: HashMap::new(),
+                }),
+                Probe::Http(HttpProbe {
+                    url: "http://example.com/".into(),
+                    status: None,
+                    body: None,
+                    headers,
+                }),
+                Probe::Dns(DnsProbe {
+                    name: "dns.msftncsi.com".into(),
+                    records: vec!["131.107.255.255".parse().unwrap()],
+                }),
+            ],
+            probe_timeout: 3,
+            healthcheck_interval: 300,
+            dns_check: DnsCheckConfig::default(),
+        });
+    }
+
+    #[test]
+    fn test_dns_check_config() {
+        let config = load("").expect("failed to load config");
+        assert!(config.decap.dns_check.enabled);
+        assert_eq!(config.decap.dns_check.canaries.len(), 2);
+
+        let config = load(r#"
+        [decap.dns_check]
+        compare = ["example.com"]
+        intercept_addr = "198.51.100.53:53"
+
+        [decap.dns_check.canaries]
+        "canary.example.com" = ["192.0.2.1"]
+        "#).expect("failed to load config");
+
+        let mut canaries = HashMap::new();
+        canaries.insert("canary.example.com".to_string(), vec!["192.0.2.1".parse().unwrap()]);
+        assert_eq!(config.decap.dns_check, DnsCheckConfig {
+            enabled: true,
+            canaries,
+            compare: vec!["example.com".into()],
+            intercept_addr: "198.51.100.53:53".parse().unwrap(),
+        });
+    }
+
+    #[test]
+    fn test_dhcp_config() {
+        let config = load("").unwrap();
+        assert_eq!(config.dhcp.client, DhcpClient::Dhcpcd);
+
+        let config = load(r#"
+        [dhcp]
+        client = "native"
+        "#).unwrap();
+        assert_eq!(config.dhcp.client, DhcpClient::Native);
+    }
+
+    #[test]
+    fn test_mac_policy() {
+        let config = load("").unwrap();
+        assert_eq!(config.mac_policy(None), MacPolicy::Permanent);
+
+        let config = load(r#"
+        [wifi]
+        mac = "connect"
+
+        [networks."Office"]
+        mac = "permanent"
+
+        [networks."Hotel"]
+        mac = "ssid"
+
+        [networks."Airport WiFi"]
+        disable_decap = true
+        "#).unwrap();
+        assert_eq!(config.mac_policy(None), MacPolicy::Connect);
+        assert_eq!(config.mac_policy(Some("Office")), MacPolicy::Permanent);
+        assert_eq!(config.mac_policy(Some("Hotel")), MacPolicy::Ssid);
+        assert_eq!(config.mac_policy(Some("Airport WiFi")), MacPolicy::Connect);
+    }
+
+    #[test]
+    fn test_dhcp_defaults() {
+        let config = load("").unwrap();
+        assert!(!config.dhcp.send_hostname);
+        assert_eq!(config.dhcp.client_id, ClientId::Hwaddr);
+        assert!(config.dhcp.ipv6);
+        assert!(config.dhcp.options.contains(&"captive_portal".to_string()));
+        assert_eq!(config.dhcp.fallback, None);
+    }
+
+    #[test]
+    fn test_dhcp_network_overrides() {
+        let config = load(r#"
+        [dhcp]
+        client_id = "duid"
+        ipv6 = false
+        options = ["domain_name_servers"]
+
+        [networks."home"]
+        [networks."home".dhcp]
+        send_hostname = true
+        client_id = "hwaddr"
+        ipv6 = true
+        options = ["ntp_servers", "domain_name_servers"]
+
+        [networks."home".dhcp.fallback]
+        address = "192.168.1.23"
+        routers = ["192.168.1.1"]
+        dns = ["192.168.1.1"]
+        "#).unwrap();
+
+        let dhcp = config.dhcp_config(None);
+        assert_eq!(dhcp, DhcpConfig {
+            client: DhcpClient::Dhcpcd,
+            send_hostname: false,
+            client_id: ClientId::Duid,
+            ipv6: false,
+            options: vec!["domain_name_servers".into()],
+            fallback: None,
+        });
+        assert_eq!(config.dhcp_config(Some("hotel")), dhcp);
+
+        assert_eq!(config.dhcp_config(Some("home")), DhcpConfig {
+            client: DhcpClient::Dhcpcd,
+            send_hostname: true,
+            client_id: ClientId::Hwaddr,
+            ipv6: true,
+            options: vec!["domain_name_servers".into(), "ntp_servers".into()],
+            fallback: Some(StaticAddress {
+                address: "192.168.1.23".parse().unwrap(),
+                subnet_cidr: 24,
+                routers: vec!["192.168.1.1".parse().unwrap()],
+                dns: vec!["192.168.1.1".parse().unwrap()],
+            }),
+        });
+    }
+
+    #[test]
+    fn test_dhcpcd_disable_hostname() {
+        let config = r#"
+        [dhcp]
+        client = "dhcpcd"
+        send_hostname = true
+
+        [networks."hotel".dhcp]
+        send_hostname = false
+        "#;
+        assert!(load(config).is_err());
+        // the native client can disable it for a network
+        assert!(load(&config.replace("dhcpcd", "native")).is_ok());
+    }
+
     #[test]
     fn test_script_paths() {
         let config = load(r#"
@@ -195,4 +997,51 @@ mod tests {
             ..Default::default()
         });
     }
+
+    #[test]
+    fn test_network_profiles() {
+        let config = load(r#"
+        [networks."Office"]
+        disable_decap = true
+
+        [networks."Airport WiFi"]
+        force_decap = true
+        script = "airport"
+        dns = ["192.0.2.53"]
+
+        [[networks."Airport WiFi".probes]]
+        type = "http"
+        url = "http://example.com/"
+        status = 200
+        "#).expect("failed to load config");
+
+        assert_eq!(config.network(Some("Office")), Some(&NetworkProfile {
+            disable_decap: true,
+            ..Default::default()
+        }));
+        assert_eq!(config.probes(Some("Office")), &config.decap.probes[..]);
+
+        let probes = vec![
+            Probe::Http(HttpProbe {
+                url: "http://example.com/".into(),
+                status: Some(200),
+                body: None,
+                headers: HashMap::new(),
+            }),
+        ];
+        assert_eq!(config.network(Some("Airport WiFi")), Some(&NetworkProfile {
+            force_decap: true,
+            disable_decap: false,
+            script: Some("airport".into()),
+            dns: vec!["192.0.2.53".parse().unwrap()],
+            probes: Some(probes.clone()),
+            dhcp: DhcpProfile::default(),
+            mac: None,
+        }));
+        assert_eq!(config.probes(Some("Airport WiFi")), &probes[..]);
+
+        assert_eq!(config.network(Some("Hotel")), None);
+        assert_eq!(config.network(None), None);
+        assert_eq!(config.probes(None), &default_probes()[..]);
+    }
 }
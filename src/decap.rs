@@ -1,114 +1,885 @@
-// android captive portal detection url
-// https://stackoverflow.com/questions/13958614/how-to-check-for-unrestricted-internet-access-captive-portal-detection
-// TODO: this should be configurable
-const PROBE_WALLED_GARDEN_URL: &str = "http://clients3.google.com/generate_204";
-
-use dns::Resolver;
+use capport::{self, CaptivePortalStatus};
+use config::{Config, Probe, HttpProbe, DnsProbe};
+use dns::{DnsCache, Resolver, DnsResolver};
+use dnscheck::{self, DnsCheck};
 use errors::Result;
-use scripts::Loader;
-use web::Client;
+use html;
+use knowledge::KnowledgeBase;
+use scripts::{Loader, Script, DetectInfo, DecapInfo};
+use structs::{LuaMap, LuaList};
+use web::{Client, HttpClient, Response};
 use wifi::NetworkStatus;
 
+use hlua::AnyLuaValue;
+use sha2::{Sha256, Digest};
+use url::Url;
+
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// stop following redirects after this many requests
+const MAX_HOPS: usize = 10;
 
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
 pub struct WalledGardenFingerprint {
     // The redirect we got for our probe
     pub redirect: Option<String>,
     // The portal we arrived at after following redirects
-    // pub portal: Option<String>,
+    pub portal: Option<String>,
+    // Every request we've sent while following redirects
+    pub hops: Vec<RedirectHop>,
+    // The last page we've received
+    pub page: Option<PortalPage>,
+    // The probes that reported a working internet connection
+    pub probes_passed: Vec<String>,
+    // The probes that were intercepted or failed to execute
+    pub probes_failed: Vec<String>,
 }
 
-pub fn detect_walled_garden(resolver: Resolver, force_decap: bool) -> Result<Option<WalledGardenFingerprint>> {
-    if force_decap {
-        // TODO: log that this happend
-        return Ok(Some(WalledGardenFingerprint {
-            redirect: None,
-        }));
+impl WalledGardenFingerprint {
+    /// The host of the portal, or the host we've been redirected to
+    pub fn portal_host(&self) -> Option<String> {
+        self.portal.as_ref()
+            .or_else(|| self.redirect.as_ref())
+            .and_then(|url| Url::parse(url).ok())
+            .and_then(|url| url.host_str().map(|x| x.to_string()))
     }
 
-    let client = Client::new(resolver);
-    let req = client.get(PROBE_WALLED_GARDEN_URL)?;
+    /// Every host we've been sent to while following redirects
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts = Vec::new();
 
-    if req.status == 204 {
-        info!("got 204 reply");
-        Ok(None)
+        let urls = self.hops.iter()
+            .map(|hop| &hop.url)
+            .chain(self.redirect.iter())
+            .chain(self.portal.iter());
+
+        for url in urls {
+            if let Some(host) = Url::parse(url).ok().and_then(|url| url.host_str().map(|x| x.to_lowercase())) {
+                if !hosts.contains(&host) {
+                    hosts.push(host);
+                }
+            }
+        }
+
+        hosts
+    }
+}
+
+impl Into<AnyLuaValue> for WalledGardenFingerprint {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        if let Some(host) = self.portal_host() {
+            map.insert_str("host", host);
+        }
+        if let Some(redirect) = self.redirect {
+            map.insert_str("redirect", redirect);
+        }
+        if let Some(portal) = self.portal {
+            map.insert_str("portal", portal);
+        }
+        if let Some(page) = self.page {
+            if let Some(server) = page.server {
+                map.insert_str("server", server);
+            }
+            if let Some(powered_by) = page.powered_by {
+                map.insert_str("powered_by", powered_by);
+            }
+            if let Some(title) = page.title {
+                map.insert_str("title", title);
+            }
+            map.insert_str("sha256", page.sha256);
+        }
+
+        let mut hops = LuaList::new();
+        for hop in self.hops {
+            hops.push(hop);
+        }
+        map.insert("hops", hops);
+        map.insert("probes_passed", LuaList::from(self.probes_passed));
+        map.insert("probes_failed", LuaList::from(self.probes_failed));
+
+        map.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum HopKind {
+    Probe,
+    Location,
+    MetaRefresh,
+    Javascript,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RedirectHop {
+    pub url: String,
+    // How we've been sent to this url
+    pub kind: HopKind,
+    // None if the request failed
+    pub status: Option<u16>,
+}
+
+impl Into<AnyLuaValue> for RedirectHop {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        map.insert_str("url", self.url);
+        map.insert_str("kind", format!("{:?}", self.kind).to_lowercase());
+        if let Some(status) = self.status {
+            map.insert_num("status", f64::from(status));
+        }
+
+        map.into()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PortalPage {
+    pub server: Option<String>,
+    pub powered_by: Option<String>,
+    pub title: Option<String>,
+    pub sha256: String,
+}
+
+impl<'a> From<&'a Response> for PortalPage {
+    fn from(res: &Response) -> PortalPage {
+        let sha256 = Sha256::digest(res.body.as_bytes()).iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+
+        PortalPage {
+            server: res.headers.get("server").map(|x| x.to_string()),
+            powered_by: res.headers.get("x-powered-by").map(|x| x.to_string()),
+            title: html::html_title(&res.body),
+            sha256,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct RedirectChain {
+    pub hops: Vec<RedirectHop>,
+    pub page: Option<PortalPage>,
+}
+
+impl RedirectChain {
+    pub fn redirect(&self) -> Option<String> {
+        self.hops.get(1)
+            .map(|hop| hop.url.to_string())
+    }
+
+    pub fn portal(&self) -> Option<String> {
+        if self.hops.len() > 1 {
+            self.hops.last()
+                .map(|hop| hop.url.to_string())
+        } else {
+            None
+        }
+    }
+}
+
+/// Find the next url the portal wants to send us to
+pub fn next_hop(url: &str, res: &Response) -> Option<(String, HopKind)> {
+    let (target, kind) = if let (300..=399, Some(location)) = (res.status, res.headers.get("location")) {
+        (location.to_string(), HopKind::Location)
+    } else if let Some(target) = html::meta_refresh(&res.body) {
+        (target, HopKind::MetaRefresh)
+    } else if let Some(target) = html::js_redirect(&res.body) {
+        (target, HopKind::Javascript)
     } else {
-        let redirect = match req.headers.get("location") {
-            Some(redirect) => {
-                Some(redirect.to_string())
+        return None;
+    };
+
+    let target = match Url::parse(url).and_then(|base| base.join(&target)) {
+        Ok(target) => target.into_string(),
+        Err(err) => {
+            warn!("invalid redirect {:?}: {}", target, err);
+            return None;
+        },
+    };
+
+    Some((target, kind))
+}
+
+/// Follow the redirects of a probe, no new requests are started after the
+/// deadline and the chain is returned as far as we got
+pub fn follow_redirects<R: DnsResolver>(client: &Client<R>, url: &str, res: Response, deadline: Instant) -> RedirectChain {
+    let mut chain = RedirectChain::default();
+    chain.hops.push(RedirectHop {
+        url: url.to_string(),
+        kind: HopKind::Probe,
+        status: Some(res.status),
+    });
+
+    let mut url = url.to_string();
+    let mut res = res;
+
+    while let Some((next, kind)) = next_hop(&url, &res) {
+        if chain.hops.len() >= MAX_HOPS {
+            warn!("too many redirects, giving up");
+            break;
+        }
+
+        if chain.hops.iter().any(|hop| hop.url == next) {
+            warn!("redirect loop detected: {:?}", next);
+            break;
+        }
+
+        if Instant::now() >= deadline {
+            warn!("ran out of time while following redirects");
+            break;
+        }
+
+        debug!("following redirect to {:?} ({:?})", next, kind);
+        match client.get(&next) {
+            Ok(next_res) => {
+                chain.hops.push(RedirectHop {
+                    url: next.clone(),
+                    kind,
+                    status: Some(next_res.status),
+                });
+                url = next;
+                res = next_res;
             },
-            None => {
-                warn!("no redirect detected?!");
-                None
+            Err(err) => {
+                warn!("failed to follow redirect to {:?}: {}", next, err);
+                chain.hops.push(RedirectHop {
+                    url: next,
+                    kind,
+                    status: None,
+                });
+                break;
             },
-        };
+        }
+    }
+
+    chain.page = Some(PortalPage::from(&res));
+    chain
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProbeResult {
+    Passed,
+    Failed {
+        chain: Option<RedirectChain>,
+    },
+}
+
+impl HttpProbe {
+    pub fn verify(&self, res: &Response) -> bool {
+        if let Some(status) = self.status {
+            if res.status != status {
+                debug!("probe expected status {}, got {}", status, res.status);
+                return false;
+            }
+        }
 
-        Ok(Some(WalledGardenFingerprint {
-            redirect,
-            // portal: None,
-        }))
+        if let Some(body) = &self.body {
+            if !res.body.contains(body.as_str()) {
+                debug!("probe expected body to contain {:?}", body);
+                return false;
+            }
+        }
+
+        for (key, value) in &self.headers {
+            let found = res.headers.iter()
+                .any(|(k, v)| k.eq_ignore_ascii_case(key) && v == value);
+            if !found {
+                debug!("probe expected header {:?}: {:?}", key, value);
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Send the probe request, the response is returned if it didn't pass
+    pub fn request(&self, client: &Client<Resolver>) -> Result<Option<Response>> {
+        let res = client.get(&self.url)?;
+
+        if self.verify(&res) {
+            Ok(None)
+        } else {
+            Ok(Some(res))
+        }
+    }
+
+    /// The verdict is sent before the redirects are followed, the redirect
+    /// chain gets its own time budget
+    fn run(&self, recursors: &[IpAddr], timeout: Duration, tx: &mpsc::Sender<ProbeEvent>) -> Result<()> {
+        let mut client = Client::new(Resolver::with_udp(recursors)?);
+        client.set_timeout(timeout);
+
+        if let Some(res) = self.request(&client)? {
+            if tx.send(ProbeEvent::Failed).is_ok() {
+                let chain = follow_redirects(&client, &self.url, res, Instant::now() + timeout);
+                let _ = tx.send(ProbeEvent::Chain(chain));
+            }
+        } else {
+            let _ = tx.send(ProbeEvent::Done(Ok(ProbeResult::Passed)));
+        }
+
+        Ok(())
     }
 }
 
-pub fn decap(loader: &Loader, status: &mut NetworkStatus, recursors: &[IpAddr], force_decap: bool) -> Result<()> {
+impl DnsProbe {
+    pub fn verify(&self, records: &[IpAddr]) -> bool {
+        records.iter()
+            .any(|x| self.records.contains(x))
+    }
+
+    pub fn run(&self, recursors: &[IpAddr]) -> Result<ProbeResult> {
+        let resolver = Resolver::with_udp(recursors)?;
+        let records = resolver.resolve(&self.name)?;
+
+        if self.verify(&records) {
+            Ok(ProbeResult::Passed)
+        } else {
+            debug!("probe got unexpected records: {:?}", records);
+            Ok(ProbeResult::Failed {
+                chain: None,
+            })
+        }
+    }
+}
+
+/// Sent from the thread that executes a probe
+enum ProbeEvent {
+    Done(Result<ProbeResult>),
+    /// the probe didn't pass, the redirect chain follows
+    Failed,
+    Chain(RedirectChain),
+}
+
+impl Probe {
+    /// The probe runs in its own thread so the timeout covers the dns
+    /// resolution as well, a probe that doesn't finish in time is abandoned.
+    /// Following the redirects of a failed probe doesn't count towards the
+    /// timeout, if the chain takes too long the probe is still failed.
+    pub fn run(&self, recursors: &[IpAddr], timeout: Duration) -> Result<ProbeResult> {
+        let (tx, rx) = mpsc::channel();
+        let probe = self.clone();
+        let recursors = recursors.to_vec();
+
+        thread::spawn(move || {
+            let result = match probe {
+                Probe::Http(probe) => probe.run(&recursors, timeout, &tx),
+                Probe::Dns(probe) => probe.run(&recursors)
+                    .map(|result| {
+                        let _ = tx.send(ProbeEvent::Done(Ok(result)));
+                    }),
+            };
+            if let Err(err) = result {
+                let _ = tx.send(ProbeEvent::Done(Err(err)));
+            }
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok(ProbeEvent::Done(result)) => result,
+            Ok(ProbeEvent::Failed) => {
+                // the last request can start right before the deadline of the chain
+                let chain = match rx.recv_timeout(timeout * 2) {
+                    Ok(ProbeEvent::Chain(chain)) => Some(chain),
+                    _ => {
+                        warn!("failed to follow the redirects of the probe in time");
+                        None
+                    },
+                };
+                Ok(ProbeResult::Failed {
+                    chain,
+                })
+            },
+            Ok(ProbeEvent::Chain(_)) => bail!("probe thread sent chain without result"),
+            Err(RecvTimeoutError::Timeout) => bail!("probe timed out after {:?}", timeout),
+            Err(RecvTimeoutError::Disconnected) => bail!("probe thread crashed"),
+        }
+    }
+}
+
+/// Combine the probe results into a verdict. The network is considered a
+/// walled garden if any probe got an unexpected answer, since some portals
+/// whitelist well known probe urls. Probes that couldn't be executed are
+/// ignored, unless none of the probes could be executed, which is an error.
+pub fn verdict(results: Vec<(String, Result<ProbeResult>)>) -> Result<Option<WalledGardenFingerprint>> {
+    let mut portal: Option<RedirectChain> = None;
+    let mut probes_passed = Vec::new();
+    let mut probes_failed = Vec::new();
+    let mut errors = 0;
+
+    for (name, result) in results {
+        match result {
+            Ok(ProbeResult::Passed) => {
+                probes_passed.push(name);
+            },
+            Ok(ProbeResult::Failed { chain }) => {
+                // prefer the first chain that actually redirected us
+                let better = match (&portal, &chain) {
+                    (None, Some(_)) => true,
+                    (Some(old), Some(new)) => old.hops.len() < 2 && new.hops.len() >= 2,
+                    _ => false,
+                };
+                if better {
+                    portal = chain;
+                }
+                probes_failed.push(name);
+            },
+            Err(err) => {
+                warn!("probe {:?} failed: {}", name, err);
+                errors += 1;
+            },
+        }
+    }
+
+    if probes_passed.is_empty() && probes_failed.is_empty() && errors > 0 {
+        bail!("all probes failed to execute");
+    }
+
+    if probes_failed.is_empty() {
+        return Ok(None);
+    }
+
+    let portal = portal.unwrap_or_default();
+    let redirect = portal.redirect();
+    if redirect.is_none() {
+        warn!("no redirect detected?!");
+    }
+
+    Ok(Some(WalledGardenFingerprint {
+        redirect,
+        portal: portal.portal(),
+        hops: portal.hops,
+        page: portal.page,
+        probes_passed,
+        probes_failed,
+    }))
+}
+
+/// Query the captive portal api if the network announced one
+fn capport_status(config: &Config, status: &NetworkStatus, recursors: &[IpAddr]) -> Option<CaptivePortalStatus> {
+    let url = status.lease.captive_portal.as_ref()?;
+    let timeout = Duration::from_secs(config.decap.probe_timeout);
+
+    match capport::query(url, recursors, timeout) {
+        Ok(api) => {
+            info!("captive portal api: {:?}", api);
+            Some(api)
+        },
+        Err(err) => {
+            warn!("failed to query captive portal api: {}", err);
+            None
+        },
+    }
+}
+
+/// Run the captive portal probes, `ssid` is used to select the probes of a
+/// network profile. If the network has a captive portal api its answer is
+/// trusted and the probes only add details about the portal.
+pub fn detect_walled_garden(config: &Config, ssid: Option<&str>, recursors: &[IpAddr], api: Option<&CaptivePortalStatus>, force_decap: bool) -> Result<Option<WalledGardenFingerprint>> {
+    if force_decap {
+        info!("skipping captive portal test, decap is forced");
+        return Ok(Some(WalledGardenFingerprint::default()));
+    }
+
+    if let Some(api) = api {
+        if !api.captive {
+            info!("captive portal api reports no restrictions");
+            return Ok(None);
+        }
+    }
+
+    let timeout = Duration::from_secs(config.decap.probe_timeout);
+
+    let results = config.probes(ssid).iter()
+        .map(|probe| {
+            debug!("running probe: {:?}", probe.name());
+            (probe.name().to_string(), probe.run(recursors, timeout))
+        })
+        .collect();
+
+    let fingerprint = match api {
+        Some(api) => {
+            let mut fingerprint = verdict(results)
+                .unwrap_or_else(|err| {
+                    debug!("probes failed: {}", err);
+                    None
+                })
+                .unwrap_or_default();
+            if fingerprint.portal.is_none() {
+                fingerprint.portal = api.user_portal_url.clone();
+            }
+            Some(fingerprint)
+        },
+        None => verdict(results)?,
+    };
+
+    if fingerprint.is_none() {
+        info!("all probes passed");
+    }
+    Ok(fingerprint)
+}
+
+/// The overall result of a decap run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum DecapOutcome {
+    NoPortal,
+    Solved,
+    Unsolved,
+}
+
+impl DecapOutcome {
+    /// The exit code that is used by `snailctl decap`
+    pub fn exit_code(self) -> i32 {
+        match self {
+            DecapOutcome::Solved => 0,
+            DecapOutcome::NoPortal => 2,
+            DecapOutcome::Unsolved => 3,
+        }
+    }
+}
+
+/// The state of the network according to the captive portal probes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all="lowercase")]
+pub enum ProbeStatus {
+    Online,
+    Portal,
+    Error(String),
+}
+
+impl<'a> From<&'a Result<Option<WalledGardenFingerprint>>> for ProbeStatus {
+    fn from(result: &Result<Option<WalledGardenFingerprint>>) -> ProbeStatus {
+        match result {
+            Ok(None) => ProbeStatus::Online,
+            Ok(Some(_)) => ProbeStatus::Portal,
+            Err(err) => ProbeStatus::Error(err.to_string()),
+        }
+    }
+}
+
+/// A script that has been considered during a decap run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScriptReport {
+    pub script: String,
+    pub detected: bool,
+    /// Only set if decap has been executed, `Err` contains the script error
+    pub result: Option<::std::result::Result<(), String>>,
+    pub duration_ms: Option<u64>,
+    /// The result of the captive portal test after decap reported success
+    pub probe: Option<ProbeStatus>,
+}
+
+impl ScriptReport {
+    fn new<I: Into<String>>(script: I, detected: bool) -> ScriptReport {
+        ScriptReport {
+            script: script.into(),
+            detected,
+            result: None,
+            duration_ms: None,
+            probe: None,
+        }
+    }
+}
+
+/// Describes what happened during a decap run
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecapReport {
+    pub outcome: DecapOutcome,
+    pub fingerprint: Option<WalledGardenFingerprint>,
+    pub scripts: Vec<ScriptReport>,
+    /// The result of the last captive portal test
+    pub probe: ProbeStatus,
+    pub message: Option<String>,
+    /// The dns check before any scripts were executed
+    #[serde(default)]
+    pub dns_check: Option<DnsCheck>,
+}
+
+impl DecapReport {
+    fn new(probe: ProbeStatus) -> DecapReport {
+        DecapReport {
+            outcome: DecapOutcome::Unsolved,
+            fingerprint: None,
+            scripts: Vec::new(),
+            probe,
+            message: None,
+            dns_check: None,
+        }
+    }
+
+    /// The script that solved the captive portal
+    pub fn solved_by(&self) -> Option<&str> {
+        self.scripts.iter()
+            .find(|script| script.probe == Some(ProbeStatus::Online))
+            .map(|script| script.script.as_str())
+    }
+}
+
+#[inline]
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+/// The generation of the most recent network event. This is shared with the
+/// thread that receives the events, so a decap run for a network we've already
+/// left can be aborted.
+#[derive(Debug, Clone, Default)]
+pub struct LatestGeneration(Arc<AtomicU64>);
+
+impl LatestGeneration {
+    pub fn new() -> LatestGeneration {
+        LatestGeneration::default()
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn set(&self, generation: u64) {
+        self.0.store(generation, Ordering::SeqCst);
+    }
+
+    /// Check if a newer network event has been received
+    pub fn is_stale(&self, generation: u64) -> bool {
+        generation < self.get()
+    }
+
+    fn check(&self, status: &NetworkStatus) -> Result<()> {
+        if self.is_stale(status.generation) {
+            bail!("network changed, aborting decap for generation {}", status.generation);
+        }
+        Ok(())
+    }
+}
+
+/// Check if a script is responsible for a network, either by ssid or by the
+/// captive portal that has been detected
+fn detect_script(script: &Script<Client<Resolver>, Resolver>, detect_info: &DetectInfo, fingerprint: &WalledGardenFingerprint) -> Result<bool> {
+    if script.detect_network(detect_info)? {
+        return Ok(true);
+    }
+
+    match script.detect_portal(fingerprint) {
+        Ok(matched) => Ok(matched),
+        Err(err) => {
+            warn!("detect_portal of {:?} failed: {}", script.descr(), err);
+            Ok(false)
+        },
+    }
+}
+
+/// Execute the decap function of a script and run the captive portal test
+/// afterwards if the script reported success, the answer of the captive
+/// portal api after the login is returned as well
+fn run_script(script: &Script<Client<Resolver>, Resolver>, config: &Config, status: &NetworkStatus, recursors: &[IpAddr], decap_info: &DecapInfo) -> (ScriptReport, Option<CaptivePortalStatus>) {
+    let ssid = status.ssid.as_ref().map(|x| x.as_str());
+    let mut report = ScriptReport::new(script.descr(), true);
+    let mut api = None;
+    info!("trying {:?}", script.descr());
+
+    let start = Instant::now();
+    let result = script.decap(decap_info);
+    report.duration_ms = Some(millis(start.elapsed()));
+
+    match result {
+        Ok(_) => {
+            report.result = Some(Ok(()));
+
+            info!("script reported success, probing network");
+            api = capport_status(config, status, recursors);
+            let result = detect_walled_garden(config, ssid, recursors, api.as_ref(), false);
+            report.probe = Some(ProbeStatus::from(&result));
+
+            match result {
+                Ok(Some(_)) => warn!("captive portal is still active"),
+                Ok(None) => info!("working internet detected"),
+                Err(err) => warn!("captive portal test failed: {}", err),
+            }
+        },
+        Err(err) => {
+            warn!("script reported error: {}", err);
+            report.result = Some(Err(err.to_string()));
+        },
+    }
+
+    (report, api)
+}
+
+/// Settings for a single decap run
+#[derive(Debug, Default, Clone)]
+pub struct DecapOptions {
+    /// skip the captive portal test
+    pub force_decap: bool,
+    /// try every script without calling its detect functions, this is used
+    /// if a specific script has been selected by the user
+    pub skip_detect: bool,
+    /// the run is aborted as soon as this reports a newer network generation
+    /// than the one in the network status
+    pub latest: LatestGeneration,
+    /// scripts that solved the network before are tried first
+    pub knowledge: KnowledgeBase,
+    /// dns responses are cached while a script is running, the probes always
+    /// bypass the cache
+    pub cache: DnsCache,
+}
+
+/// Sort the scripts so the ranked ones are tried first, the remaining scripts
+/// are sorted by name so the order is stable
+fn sort_scripts<C: HttpClient + 'static, R: DnsResolver + 'static>(scripts: &mut Vec<Script<C, R>>, ranked: &[String]) {
+    scripts.sort_by_key(|script| {
+        let rank = ranked.iter()
+            .position(|x| x == script.descr())
+            .unwrap_or_else(|| ranked.len());
+        (rank, script.descr().to_string())
+    });
+}
+
+/// Run the dns check, unless it's disabled
+fn dns_check(config: &Config, recursors: &[IpAddr]) -> Option<DnsCheck> {
+    if config.decap.dns_check.enabled {
+        Some(dnscheck::run(config, recursors))
+    } else {
+        None
+    }
+}
+
+/// Describe the network for the detect() function of scripts
+fn detect_info(status: &NetworkStatus) -> DetectInfo {
+    match &status.wifi {
+        Some(network) => DetectInfo::from(&**network),
+        None => DetectInfo::new(status.ssid.clone().unwrap_or_default()),
+    }
+}
+
+/// Detect a captive portal and try to solve it with the loaded scripts.
+///
+/// If there's a profile for the network in the config, it can force decap,
+/// pin a script, overwrite the dns servers or disable the scripts entirely.
+///
+/// The report is also stored in the network status.
+pub fn decap(loader: &Loader, config: &Config, status: &mut NetworkStatus, recursors: &[IpAddr], opts: &DecapOptions) -> Result<DecapReport> {
+    let latest = &opts.latest;
+    let ssid = status.ssid.clone();
+    let ssid = ssid.as_ref().map(|x| x.as_str());
+    let profile = config.network(ssid)
+        .cloned()
+        .unwrap_or_default();
+
+    let recursors = if !profile.dns.is_empty() {
+        debug!("using dns servers from network profile: {:?}", profile.dns);
+        &profile.dns[..]
+    } else {
+        recursors
+    };
     // TODO: dns server could be empty
-    let resolver = Resolver::with_udp(recursors)?;
-    match detect_walled_garden(resolver, force_decap) {
+
+    let force_decap = opts.force_decap || (profile.force_decap && !profile.disable_decap);
+    let skip_detect = opts.skip_detect || profile.script.is_some();
+
+    let api = capport_status(config, status, recursors);
+    status.capport = api.clone().map(Box::new);
+    latest.check(status)?;
+
+    let result = detect_walled_garden(config, ssid, recursors, api.as_ref(), force_decap);
+    latest.check(status)?;
+    let mut report = DecapReport::new(ProbeStatus::from(&result));
+
+    report.dns_check = dns_check(config, recursors);
+    status.dns_check = report.dns_check.clone().map(Box::new);
+    latest.check(status)?;
+
+    match result {
         Ok(Some(fingerprint)) => {
             status.set_uplink_status(Some(false));
             info!("detected captive portal: {:?}", fingerprint);
 
-            if let Some(ssid) = status.ssid.clone() {
-                let scripts = loader.init_from_status(&status)?;
+            if profile.disable_decap {
+                info!("decap is disabled for this network");
+                report.message = Some("decap is disabled for this network".to_string());
+            } else if status.ssid.is_some() || skip_detect {
+                let mut scripts = loader.init_with_recursors(recursors, Some(&opts.cache))?;
+
+                if let Some(pinned) = &profile.script {
+                    scripts.retain(|script| script.descr() == pinned);
+                    if scripts.is_empty() {
+                        warn!("script from network profile not found: {:?}", pinned);
+                    }
+                }
+
+                let ranked = opts.knowledge.rank(ssid, status.bssid.as_ref().map(|x| x.as_str()), Some(&fingerprint));
+                if !ranked.is_empty() {
+                    info!("trying known solutions first: {:?}", ranked);
+                }
+                sort_scripts(&mut scripts, &ranked);
 
                 info!("loaded {} scripts", scripts.len());
 
-                let mut solved = false;
+                let detect_info = detect_info(status);
+                let decap_info = DecapInfo::new(&status, recursors, Some(&fingerprint));
+
                 for script in scripts {
-                    if script.detect_network(&ssid)? {
-                        info!("trying {:?}", script.descr());
-
-                        match script.decap() {
-                            Ok(_) => {
-                                info!("script reported success, probing network");
-                                status.set_uplink_status(Some(true));
-                                let resolver = Resolver::with_udp(recursors)?;
-                                match detect_walled_garden(resolver, false) {
-                                    Ok(Some(_)) => {
-                                        warn!("captive portal is still active");
-                                    },
-                                    Ok(None) => {
-                                        status.set_uplink_status(Some(true));
-                                        status.script_used = Some(script.descr().to_string());
-                                        info!("working internet detected");
-                                        solved = true;
-                                        break;
-                                    },
-                                    Err(err) => {
-                                        warn!("captive portal test failed: {}", err);
-                                    },
-                                }
-                            },
-                            Err(err) => {
-                                warn!("script reported error: {}", err);
-                            },
-                        };
+                    latest.check(status)?;
+
+                    let matched = if skip_detect {
+                        debug!("skipping detect for {:?}", script.descr());
+                        true
+                    } else {
+                        detect_script(&script, &detect_info, &fingerprint)?
+                    };
+
+                    if !matched {
+                        report.scripts.push(ScriptReport::new(script.descr(), false));
+                        continue;
+                    }
+
+                    let (script_report, session) = run_script(&script, config, status, recursors, &decap_info);
+                    // the portal might answer differently after a login attempt
+                    opts.cache.flush();
+                    latest.check(status)?;
+                    if let Some(probe) = &script_report.probe {
+                        report.probe = probe.clone();
                     }
+
+                    if script_report.probe == Some(ProbeStatus::Online) {
+                        status.set_uplink_status(Some(true));
+                        status.script_used = Some(script.descr().to_string());
+                        // keep the previous answer if the api wasn't queried again
+                        let previous = status.capport.take();
+                        status.capport = session.map(Box::new).or(previous);
+                        // check if the network keeps tampering after login
+                        status.dns_check = dns_check(config, recursors).map(Box::new);
+                        report.outcome = DecapOutcome::Solved;
+                        report.scripts.push(script_report);
+                        break;
+                    }
+
+                    report.scripts.push(script_report);
                 }
 
-                if !solved {
+                if report.outcome != DecapOutcome::Solved {
                     status.set_uplink_status(Some(false));
                     info!("no scripts left, giving up");
+                    report.message = Some("no scripts left".to_string());
                 }
             } else {
                 info!("decap engine is only enabled on wireless networks");
+                report.message = Some("decap engine is only enabled on wireless networks".to_string());
             }
+
+            report.fingerprint = Some(fingerprint);
         },
         Ok(None) => {
             status.set_uplink_status(Some(true));
             info!("working internet detected");
+            report.outcome = DecapOutcome::NoPortal;
         },
         Err(err) => {
             warn!("captive portal test failed: {}", err);
@@ -116,5 +887,380 @@ pub fn decap(loader: &Loader, status: &mut NetworkStatus, recursors: &[IpAddr],
         },
     }
 
-    Ok(())
+    status.decap_report = Some(Box::new(report.clone()));
+    Ok(report)
+}
+
+/// The dns servers of the network, unless the network profile overwrites them
+fn recursors<'a>(config: &'a Config, status: &'a NetworkStatus) -> &'a [IpAddr] {
+    match config.network(status.ssid.as_ref().map(|x| x.as_str())) {
+        Some(profile) if !profile.dns.is_empty() => &profile.dns,
+        _ => &status.dns,
+    }
+}
+
+/// The interval between healthchecks, the script that solved the network can
+/// overwrite the default interval
+pub fn healthcheck_interval(loader: &Loader, config: &Config, status: &NetworkStatus) -> Result<Option<Duration>> {
+    let mut interval = config.decap.healthcheck_interval;
+
+    if let Some(descr) = &status.script_used {
+        let scripts = loader.init_with_recursors(recursors(config, status), None)?;
+        let script_interval = scripts.into_iter()
+            .find(|script| script.descr() == descr)
+            .and_then(|script| script.healthcheck_interval());
+
+        if let Some(script_interval) = script_interval {
+            interval = script_interval;
+        }
+    }
+
+    if interval > 0 {
+        Ok(Some(Duration::from_secs(interval)))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Check if the network is still working. This uses the healthcheck of the
+/// script that solved the network if there is one, or falls back to the
+/// captive portal test.
+pub fn healthcheck(loader: &Loader, config: &Config, status: &NetworkStatus) -> Result<bool> {
+    let recursors = recursors(config, status);
+
+    if let Some(descr) = &status.script_used {
+        let scripts = loader.init_with_recursors(recursors, None)?;
+
+        if let Some(script) = scripts.into_iter().find(|script| script.descr() == descr) {
+            let network = DecapInfo::new(status, recursors, None);
+            if let Some(healthy) = script.healthcheck(&network)? {
+                info!("healthcheck of {:?} reported: {:?}", descr, healthy);
+                return Ok(healthy);
+            }
+        }
+    }
+
+    let ssid = status.ssid.as_ref().map(|x| x.as_str());
+    let api = capport_status(config, status, recursors);
+    match detect_walled_garden(config, ssid, recursors, api.as_ref(), false) {
+        Ok(None) => Ok(true),
+        Ok(Some(fingerprint)) => {
+            info!("captive portal is active: {:?}", fingerprint);
+            Ok(false)
+        },
+        Err(err) => {
+            warn!("captive portal test failed: {}", err);
+            Ok(false)
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn redirect(status: u16, location: &str) -> Response {
+        let mut res = response(status, "");
+        res.headers.insert("location".into(), location.into());
+        res
+    }
+
+    fn response(status: u16, body: &str) -> Response {
+        Response {
+            status,
+            headers: HashMap::new(),
+            cookies: Vec::new(),
+            body: body.to_string(),
+        }
+    }
+
+    fn http_probe(status: Option<u16>, body: Option<&str>) -> HttpProbe {
+        HttpProbe {
+            url: "http://example.com/".to_string(),
+            status,
+            body: body.map(|x| x.to_string()),
+            headers: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_http_probe_status() {
+        let probe = http_probe(Some(204), None);
+        assert!(probe.verify(&response(204, "")));
+        assert!(!probe.verify(&response(302, "")));
+    }
+
+    #[test]
+    fn test_http_probe_body() {
+        let probe = http_probe(Some(200), Some("Success"));
+        assert!(probe.verify(&response(200, "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>")));
+        assert!(!probe.verify(&response(200, "<html>please login</html>")));
+    }
+
+    #[test]
+    fn test_http_probe_headers() {
+        let mut probe = http_probe(None, None);
+        probe.headers.insert("X-Canary".into(), "1".into());
+
+        let mut res = response(200, "");
+        assert!(!probe.verify(&res));
+        res.headers.insert("x-canary".into(), "1".into());
+        assert!(probe.verify(&res));
+    }
+
+    #[test]
+    fn test_http_probe_slow_redirects() {
+        use std::io::prelude::*;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                // the portal is slow after the first redirect
+                if i > 0 {
+                    thread::sleep(Duration::from_secs(3));
+                }
+                let _ = stream.write_all(format!("HTTP/1.1 302 Found\r\nLocation: /hop{}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", i).as_bytes());
+            }
+        });
+
+        let mut probe = http_probe(Some(204), None);
+        probe.url = format!("http://{}/generate_204", addr);
+        let result = Probe::Http(probe).run(&["127.0.0.1".parse().unwrap()], Duration::from_secs(1));
+
+        match result {
+            Ok(ProbeResult::Failed { chain: Some(chain) }) => {
+                assert_eq!(chain.hops[0].status, Some(302));
+                assert_eq!(chain.hops[1].url, format!("http://{}/hop0", addr));
+                assert_eq!(chain.hops[1].status, None);
+            },
+            _ => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_dns_probe() {
+        let probe = DnsProbe {
+            name: "dns.msftncsi.com".to_string(),
+            records: vec!["131.107.255.255".parse().unwrap()],
+        };
+        assert!(probe.verify(&["131.107.255.255".parse().unwrap()]));
+        assert!(!probe.verify(&["192.0.2.1".parse().unwrap()]));
+        assert!(!probe.verify(&[]));
+    }
+
+    #[test]
+    fn test_verdict_all_passed() {
+        let fingerprint = verdict(vec![
+            ("a".into(), Ok(ProbeResult::Passed)),
+            ("b".into(), Ok(ProbeResult::Passed)),
+        ]).unwrap();
+        assert_eq!(fingerprint, None);
+    }
+
+    #[test]
+    fn test_verdict_partially_intercepted() {
+        let fingerprint = verdict(vec![
+            ("a".into(), Ok(ProbeResult::Passed)),
+            ("b".into(), Ok(ProbeResult::Failed { chain: Some(RedirectChain {
+                hops: vec![
+                    RedirectHop {
+                        url: "http://example.com/".into(),
+                        kind: HopKind::Probe,
+                        status: Some(302),
+                    },
+                    RedirectHop {
+                        url: "http://portal.example.com/".into(),
+                        kind: HopKind::Location,
+                        status: Some(302),
+                    },
+                    RedirectHop {
+                        url: "https://portal.example.com/login".into(),
+                        kind: HopKind::Location,
+                        status: None,
+                    },
+                ],
+                page: None,
+            })})),
+            ("c".into(), Err(format_err!("timeout"))),
+        ]).unwrap().expect("expected walled garden");
+        assert_eq!(fingerprint.redirect, Some("http://portal.example.com/".into()));
+        assert_eq!(fingerprint.portal, Some("https://portal.example.com/login".into()));
+        assert_eq!(fingerprint.hops.len(), 3);
+        assert_eq!(fingerprint.probes_passed, vec![String::from("a")]);
+        // probes that couldn't be executed aren't counted as failed
+        assert_eq!(fingerprint.probes_failed, vec![String::from("b")]);
+    }
+
+    #[test]
+    fn test_verdict_passed_and_errors() {
+        let fingerprint = verdict(vec![
+            ("a".into(), Err(format_err!("timeout"))),
+            ("b".into(), Ok(ProbeResult::Passed)),
+            ("c".into(), Err(format_err!("resolve error"))),
+        ]).unwrap();
+        assert_eq!(fingerprint, None);
+    }
+
+    #[test]
+    fn test_verdict_all_errors() {
+        let r = verdict(vec![
+            ("a".into(), Err(format_err!("timeout"))),
+            ("b".into(), Err(format_err!("timeout"))),
+        ]);
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn test_next_hop_location() {
+        let res = redirect(302, "/login?x=1");
+        let next = next_hop("http://portal.example.com/start", &res);
+        assert_eq!(next, Some(("http://portal.example.com/login?x=1".into(), HopKind::Location)));
+    }
+
+    #[test]
+    fn test_next_hop_ignores_location_without_redirect() {
+        let res = redirect(200, "/login");
+        assert_eq!(next_hop("http://portal.example.com/", &res), None);
+    }
+
+    #[test]
+    fn test_next_hop_meta_refresh() {
+        let res = response(200, r#"<meta http-equiv="refresh" content="0; url=https://portal.example.com/">"#);
+        let next = next_hop("http://example.com/", &res);
+        assert_eq!(next, Some(("https://portal.example.com/".into(), HopKind::MetaRefresh)));
+    }
+
+    #[test]
+    fn test_next_hop_javascript() {
+        let res = response(200, r#"<script>window.location="/portal/";</script>"#);
+        let next = next_hop("http://192.0.2.1/", &res);
+        assert_eq!(next, Some(("http://192.0.2.1/portal/".into(), HopKind::Javascript)));
+    }
+
+    #[test]
+    fn test_portal_page() {
+        let mut res = response(200, "<html><title>Guest WiFi</title></html>");
+        res.headers.insert("server".into(), "nginx".into());
+        res.headers.insert("x-powered-by".into(), "PHP/5.4".into());
+
+        let page = PortalPage::from(&res);
+        assert_eq!(page, PortalPage {
+            server: Some("nginx".into()),
+            powered_by: Some("PHP/5.4".into()),
+            title: Some("Guest WiFi".into()),
+            sha256: "b267dc943c66a4577c624036832592901175f14172449b285365e06750c7f07f".into(),
+        });
+    }
+
+    #[test]
+    fn test_portal_host() {
+        let mut fingerprint = WalledGardenFingerprint::default();
+        assert_eq!(fingerprint.portal_host(), None);
+        fingerprint.redirect = Some("http://192.0.2.1/start".into());
+        assert_eq!(fingerprint.portal_host(), Some("192.0.2.1".into()));
+        fingerprint.portal = Some("https://login.hotel.example.com/portal".into());
+        assert_eq!(fingerprint.portal_host(), Some("login.hotel.example.com".into()));
+    }
+
+    #[test]
+    fn test_fingerprint_hosts() {
+        let fingerprint = WalledGardenFingerprint {
+            redirect: Some("http://Portal.Example.com/start".into()),
+            portal: Some("https://login.example.com/".into()),
+            hops: vec![
+                RedirectHop {
+                    url: "http://detectportal.firefox.com/success.txt".into(),
+                    kind: HopKind::Probe,
+                    status: Some(302),
+                },
+                RedirectHop {
+                    url: "http://portal.example.com/start".into(),
+                    kind: HopKind::Location,
+                    status: Some(200),
+                },
+            ],
+            ..Default::default()
+        };
+        assert_eq!(fingerprint.hosts(), vec![
+            "detectportal.firefox.com",
+            "portal.example.com",
+            "login.example.com",
+        ]);
+    }
+
+    #[test]
+    fn test_decap_report_solved_by() {
+        let mut report = DecapReport::new(ProbeStatus::Portal);
+        report.scripts.push(ScriptReport::new("foo.lua", false));
+
+        let mut failed = ScriptReport::new("bar.lua", true);
+        failed.result = Some(Ok(()));
+        failed.probe = Some(ProbeStatus::Portal);
+        report.scripts.push(failed);
+        assert_eq!(report.solved_by(), None);
+
+        let mut solved = ScriptReport::new("moxy.lua", true);
+        solved.result = Some(Ok(()));
+        solved.probe = Some(ProbeStatus::Online);
+        report.scripts.push(solved);
+        assert_eq!(report.solved_by(), Some("moxy.lua"));
+    }
+
+    #[test]
+    fn test_decap_outcome_exit_codes() {
+        let codes = vec![
+            DecapOutcome::NoPortal.exit_code(),
+            DecapOutcome::Solved.exit_code(),
+            DecapOutcome::Unsolved.exit_code(),
+        ];
+        assert_eq!(codes, vec![2, 0, 3]);
+    }
+
+    #[test]
+    fn test_probe_status_from_result() {
+        assert_eq!(ProbeStatus::from(&Ok(None)), ProbeStatus::Online);
+        assert_eq!(ProbeStatus::from(&Ok(Some(WalledGardenFingerprint::default()))), ProbeStatus::Portal);
+        assert_eq!(ProbeStatus::from(&Err(format_err!("timeout"))), ProbeStatus::Error("timeout".into()));
+    }
+
+    #[test]
+    fn test_latest_generation() {
+        let latest = LatestGeneration::new();
+        let shared = latest.clone();
+        assert!(!latest.is_stale(0));
+
+        shared.set(3);
+        assert_eq!(latest.get(), 3);
+        assert!(latest.is_stale(2));
+        assert!(!latest.is_stale(3));
+
+        let mut status = NetworkStatus::empty();
+        status.generation = 2;
+        assert!(latest.check(&status).is_err());
+        status.generation = 3;
+        assert!(latest.check(&status).is_ok());
+    }
+
+    #[test]
+    fn test_sort_scripts() {
+        let mut scripts = ["c", "a", "known", "b"].iter()
+            .map(|descr| Loader::init_default(format!(r#"
+            descr = "{}"
+            function detect() return false end
+            function decap() end
+            "#, descr)).unwrap())
+            .collect::<Vec<_>>();
+
+        sort_scripts(&mut scripts, &["known".to_string(), "missing".to_string()]);
+        let order = scripts.iter()
+            .map(|script| script.descr())
+            .collect::<Vec<_>>();
+        assert_eq!(order, vec!["known", "a", "b", "c"]);
+    }
 }
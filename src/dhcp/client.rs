@@ -0,0 +1,1322 @@
+use capport;
+use config::Config;
+use dhcp::{self, NetworkConfig, LeaseOptions, Prefix, Route, UpdateMessage};
+use dhcp4r::{self, options, packet};
+use dhcp4r::packet::Packet;
+use errors::{Result, ResultExt};
+use mac;
+use netlink;
+use wifi;
+
+use nix::libc;
+use nix::net::if_::if_nametoindex;
+use nix::unistd;
+use nix::sys::signal::{self, SigAction, SigHandler, SaFlags, SigSet, Signal};
+use nix::sys::socket::{self, AddressFamily, SockType, SockFlag, SockProtocol,
+                       SockAddr, InetAddr, sockopt};
+use rand;
+
+use std::cmp;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+pub const CLIENT_PORT: u16 = 68;
+pub const SERVER_PORT: u16 = 67;
+
+/// dhcp4r panics while padding packets that are shorter than this
+const MIN_PACKET_SIZE: usize = 272;
+/// upper limit for the exponential backoff of DISCOVER and REQUEST
+const MAX_BACKOFF: u64 = 64;
+/// give up on an offer and start over after this many requests
+const MAX_REQUESTS: u32 = 4;
+/// RENEW and REBIND are retransmitted at most this often
+const MIN_RETRANSMIT: Duration = Duration::from_secs(60);
+/// Same as the default timeout of dhcpcd
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(30);
+/// how often the carrier of the interface is checked
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// smaller mtus from the server are ignored
+const MIN_MTU: u16 = 576;
+
+// not defined by dhcp4r
+const CAPTIVE_PORTAL: u8 = 114;
+const DOMAIN_SEARCH: u8 = 119;
+const CLASSLESS_STATIC_ROUTE: u8 = 121;
+
+const REQUESTED_OPTIONS: &[u8] = &[
+    options::SUBNET_MASK,
+    options::ROUTER,
+    options::DOMAIN_NAME_SERVER,
+    options::DOMAIN_NAME,
+    options::INTERFACE_MTU,
+    options::BROADCAST_ADDRESS,
+    options::IP_ADDRESS_LEASE_TIME,
+    options::RENEWAL_TIME_VALUE,
+    options::REBINDING_TIME_VALUE,
+    options::NETWORK_TIME_PROTOCOL_SERVERS,
+    CAPTIVE_PORTAL,
+    DOMAIN_SEARCH,
+    CLASSLESS_STATIC_ROUTE,
+];
+
+/// Set by SIGTERM and SIGINT, the lease is released before we exit
+static STOP: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lease {
+    pub config: NetworkConfig,
+    pub server: Ipv4Addr,
+    pub acquired: Instant,
+    /// T1, relative to acquired
+    pub renew: Duration,
+    /// T2, relative to acquired
+    pub rebind: Duration,
+    pub expire: Duration,
+}
+
+impl Lease {
+    #[inline]
+    pub fn renew_at(&self) -> Instant {
+        self.acquired + self.renew
+    }
+
+    #[inline]
+    pub fn rebind_at(&self) -> Instant {
+        self.acquired + self.rebind
+    }
+
+    #[inline]
+    pub fn expire_at(&self) -> Instant {
+        self.acquired + self.expire
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Destination {
+    Broadcast,
+    Server(Ipv4Addr),
+}
+
+impl Destination {
+    pub fn socket_addr(self) -> SocketAddr {
+        let ip = match self {
+            Destination::Broadcast => Ipv4Addr::BROADCAST,
+            Destination::Server(ip) => ip,
+        };
+        SocketAddr::new(IpAddr::V4(ip), SERVER_PORT)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Transmit {
+    pub destination: Destination,
+    pub packet: Vec<u8>,
+}
+
+/// What the caller has to do after feeding an event into the client
+#[derive(Debug, Default, PartialEq)]
+pub struct Step {
+    pub transmit: Option<Transmit>,
+    pub update: Option<UpdateMessage>,
+}
+
+impl Step {
+    fn transmit(transmit: Transmit) -> Step {
+        Step {
+            transmit: Some(transmit),
+            update: None,
+        }
+    }
+
+    fn update(update: UpdateMessage) -> Step {
+        Step {
+            transmit: None,
+            update: Some(update),
+        }
+    }
+}
+
+/// The dhcp state machine from RFC 2131, this doesn't do any io and is
+/// driven by `timeout` and `handle`.
+#[derive(Debug)]
+pub struct Client {
+    mac: [u8; 6],
+    xid: u32,
+    state: State,
+    /// the offered address and the server that offered it
+    offer: Option<(Ipv4Addr, Ipv4Addr)>,
+    lease: Option<Lease>,
+    /// start of the current exchange, reported in the secs field
+    started: Instant,
+    retries: u32,
+    deadline: Instant,
+    hostname: Option<String>,
+    fallback: Option<NetworkConfig>,
+    /// the fallback is configured until we get a lease
+    fallback_applied: bool,
+}
+
+impl Client {
+    pub fn new(mac: [u8; 6], now: Instant) -> Client {
+        Client {
+            mac,
+            xid: rand::random(),
+            state: State::Init,
+            offer: None,
+            lease: None,
+            started: now,
+            retries: 0,
+            deadline: now,
+            hostname: None,
+            fallback: None,
+            fallback_applied: false,
+        }
+    }
+
+    /// Send our hostname in discover and request messages
+    #[inline]
+    pub fn set_hostname(&mut self, hostname: Option<String>) {
+        self.hostname = hostname;
+    }
+
+    /// Reported as bound if no server answered within 30 seconds, we keep
+    /// looking for a server afterwards
+    #[inline]
+    pub fn set_fallback(&mut self, fallback: Option<NetworkConfig>) {
+        self.fallback = fallback;
+    }
+
+    /// If a lease or the fallback is configured on the interface
+    #[inline]
+    pub fn configured(&self) -> bool {
+        self.lease.is_some() || self.fallback_applied
+    }
+
+    #[inline]
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    #[inline]
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// `timeout` needs to be called once this has passed
+    #[inline]
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn reset(&mut self, now: Instant) {
+        self.state = State::Init;
+        self.offer = None;
+        self.lease = None;
+        self.retries = 0;
+        self.deadline = now;
+        self.fallback_applied = false;
+    }
+
+    fn start_exchange(&mut self, now: Instant) {
+        self.xid = rand::random();
+        self.started = now;
+        self.retries = 0;
+    }
+
+    pub fn timeout(&mut self, now: Instant) -> Step {
+        if now < self.deadline {
+            return Step::default();
+        }
+
+        match self.state {
+            State::Init => {
+                debug!("sending dhcp discover");
+                self.start_exchange(now);
+                self.state = State::Selecting;
+                self.deadline = now + backoff(0);
+                Step::transmit(self.discover(now))
+            },
+            State::Selecting => {
+                self.retries += 1;
+                self.deadline = now + backoff(self.retries);
+                let mut step = Step::transmit(self.discover(now));
+
+                if !self.fallback_applied && now.duration_since(self.started) >= FALLBACK_TIMEOUT {
+                    if let Some(fallback) = &self.fallback {
+                        info!("no dhcp server answered, using fallback address");
+                        self.fallback_applied = true;
+                        step.update = Some(UpdateMessage::Bound(fallback.clone()));
+                    }
+                }
+
+                step
+            },
+            State::Requesting => {
+                if self.retries + 1 >= MAX_REQUESTS {
+                    info!("dhcp request wasn't answered, starting over");
+                    self.reset(now);
+                    return Step::default();
+                }
+                self.retries += 1;
+                self.deadline = now + backoff(self.retries);
+                Step::transmit(self.request(now))
+            },
+            State::Bound => {
+                debug!("renewing dhcp lease");
+                self.start_exchange(now);
+                self.state = State::Renewing;
+                self.extend(now)
+            },
+            State::Renewing | State::Rebinding => self.extend(now),
+        }
+    }
+
+    /// Send a RENEW to our server, or a REBIND to any server after T2
+    fn extend(&mut self, now: Instant) -> Step {
+        let (server, ip, rebind_at, expire_at) = match &self.lease {
+            Some(lease) => (lease.server, lease.config.ip_address, lease.rebind_at(), lease.expire_at()),
+            None => {
+                self.reset(now);
+                return Step::default();
+            },
+        };
+
+        if now >= expire_at {
+            warn!("dhcp lease expired");
+            self.reset(now);
+            return Step::update(UpdateMessage::Expired);
+        }
+
+        let (destination, until) = if now >= rebind_at {
+            if self.state != State::Rebinding {
+                info!("dhcp server didn't renew our lease, rebinding");
+                self.state = State::Rebinding;
+            }
+            (Destination::Broadcast, expire_at)
+        } else {
+            (Destination::Server(server), rebind_at)
+        };
+        self.deadline = retransmit(now, until);
+
+        let ciaddr = match ip {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+        };
+        let packet = self.encode(dhcp4r::REQUEST, ciaddr, &[], now);
+        Step::transmit(Transmit {
+            destination,
+            packet,
+        })
+    }
+
+    fn discover(&self, now: Instant) -> Transmit {
+        Transmit {
+            destination: Destination::Broadcast,
+            packet: self.encode(dhcp4r::DISCOVER, Ipv4Addr::UNSPECIFIED, &[], now),
+        }
+    }
+
+    fn request(&self, now: Instant) -> Transmit {
+        let mut extra = Vec::new();
+        if let Some((ip, server)) = self.offer {
+            extra.push((options::REQUESTED_IP_ADDRESS, ip.octets().to_vec()));
+            extra.push((options::SERVER_IDENTIFIER, server.octets().to_vec()));
+        }
+        Transmit {
+            destination: Destination::Broadcast,
+            packet: self.encode(dhcp4r::REQUEST, Ipv4Addr::UNSPECIFIED, &extra, now),
+        }
+    }
+
+    /// Give the lease back to the server, the client starts over afterwards
+    pub fn release(&mut self, now: Instant) -> Option<Transmit> {
+        let lease = self.lease.take()?;
+        self.reset(now);
+        self.start_exchange(now);
+
+        let ciaddr = match lease.config.ip_address {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(_) => return None,
+        };
+        let extra = [(options::SERVER_IDENTIFIER, lease.server.octets().to_vec())];
+        Some(Transmit {
+            destination: Destination::Server(lease.server),
+            packet: self.encode(dhcp4r::RELEASE, ciaddr, &extra, now),
+        })
+    }
+
+    /// Process a packet that has been received on the client port
+    pub fn handle(&mut self, bytes: &[u8], now: Instant) -> Step {
+        let packet = match decode(bytes) {
+            Ok(packet) => packet,
+            Err(err) => {
+                debug!("ignoring invalid dhcp packet: {}", err);
+                return Step::default();
+            },
+        };
+
+        if !packet.reply || bytes_u32!(packet.xid) != self.xid || packet.chaddr != self.mac {
+            return Step::default();
+        }
+
+        match (self.state, packet.message_type()) {
+            (State::Selecting, dhcp4r::OFFER) => {
+                let server = match server_identifier(&packet) {
+                    Some(server) => server,
+                    None => {
+                        debug!("ignoring dhcp offer without server identifier");
+                        return Step::default();
+                    },
+                };
+                let ip = Ipv4Addr::from(packet.yiaddr);
+                info!("got dhcp offer for {} from {}", ip, server);
+
+                self.offer = Some((ip, server));
+                self.state = State::Requesting;
+                self.retries = 0;
+                self.deadline = now + backoff(0);
+                Step::transmit(self.request(now))
+            },
+            (State::Requesting, dhcp4r::ACK) |
+            (State::Renewing, dhcp4r::ACK) |
+            (State::Rebinding, dhcp4r::ACK) => {
+                let lease = match parse_lease(&packet, now) {
+                    Ok(lease) => lease,
+                    Err(err) => {
+                        warn!("ignoring invalid dhcp ack: {}", err);
+                        return Step::default();
+                    },
+                };
+
+                let config = lease.config.clone();
+                let renewed = self.state != State::Requesting;
+                self.deadline = lease.renew_at();
+                self.lease = Some(lease);
+                self.state = State::Bound;
+                self.offer = None;
+
+                if renewed {
+                    Step::update(UpdateMessage::Renew(config))
+                } else {
+                    Step::update(UpdateMessage::Bound(config))
+                }
+            },
+            (State::Requesting, dhcp4r::NAK) => {
+                info!("dhcp request has been rejected, starting over");
+                self.reset(now);
+                Step::default()
+            },
+            (State::Renewing, dhcp4r::NAK) |
+            (State::Rebinding, dhcp4r::NAK) => {
+                warn!("dhcp server revoked our lease");
+                self.reset(now);
+                Step::update(UpdateMessage::Expired)
+            },
+            _ => Step::default(),
+        }
+    }
+
+    fn encode(&self, message_type: u8, ciaddr: Ipv4Addr, extra: &[(u8, Vec<u8>)], now: Instant) -> Vec<u8> {
+        let message_type = [message_type];
+
+        let mut options = vec![
+            options::Option {
+                code: options::DHCP_MESSAGE_TYPE,
+                data: &message_type,
+            },
+        ];
+        for (code, data) in extra {
+            options.push(options::Option {
+                code: *code,
+                data: &data[..],
+            });
+        }
+        if message_type[0] != dhcp4r::RELEASE {
+            options.push(options::Option {
+                code: options::PARAMETER_REQUEST_LIST,
+                data: REQUESTED_OPTIONS,
+            });
+            if let Some(hostname) = &self.hostname {
+                options.push(options::Option {
+                    code: options::HOST_NAME,
+                    data: hostname.as_bytes(),
+                });
+            }
+        }
+
+        // header, cookie and end option
+        let mut length = 241 + options.iter()
+            .map(|option| 2 + option.data.len())
+            .sum::<usize>();
+        while length < MIN_PACKET_SIZE {
+            // encoded as two pad bytes
+            options.push(options::Option {
+                code: 0,
+                data: &[],
+            });
+            length += 2;
+        }
+
+        let secs = now.duration_since(self.started).as_secs();
+        let packet = Packet {
+            reply: false,
+            hops: 0,
+            xid: u32_bytes!(self.xid),
+            secs: cmp::min(secs, u64::from(u16::max_value())) as u16,
+            // we can't receive unicast until an address is configured
+            broadcast: ciaddr.is_unspecified(),
+            ciaddr: ciaddr.octets(),
+            yiaddr: [0; 4],
+            siaddr: [0; 4],
+            giaddr: [0; 4],
+            chaddr: self.mac,
+            options,
+        };
+
+        let mut buf = [0; 576];
+        packet.encode(&mut buf).to_vec()
+    }
+}
+
+/// dhcp4r panics on packets that are too short to contain the header
+fn decode(bytes: &[u8]) -> Result<Packet> {
+    if bytes.len() < 240 {
+        bail!("packet is too short");
+    }
+    packet::decode(bytes)
+        .map_err(|err| format_err!("{}", err))
+}
+
+#[inline]
+fn backoff(retries: u32) -> Duration {
+    Duration::from_secs(cmp::min(4 << cmp::min(retries, 4), MAX_BACKOFF))
+}
+
+/// Wait half of the remaining time, but at least a minute
+fn retransmit(now: Instant, until: Instant) -> Instant {
+    let remaining = until.duration_since(now);
+    cmp::min(now + cmp::max(remaining / 2, MIN_RETRANSMIT), until)
+}
+
+fn addresses(data: &[u8]) -> Vec<Ipv4Addr> {
+    data.chunks(4)
+        .filter(|chunk| chunk.len() == 4)
+        .map(|chunk| Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]))
+        .collect()
+}
+
+fn seconds(data: &[u8]) -> Option<Duration> {
+    if data.len() == 4 {
+        Some(Duration::from_secs(u64::from(bytes_u32!(data))))
+    } else {
+        None
+    }
+}
+
+fn server_identifier(packet: &Packet) -> Option<Ipv4Addr> {
+    packet.option(options::SERVER_IDENTIFIER)
+        .and_then(|data| addresses(data).into_iter().next())
+}
+
+fn address_list(packet: &Packet, code: u8) -> Vec<IpAddr> {
+    packet.option(code)
+        .map(addresses)
+        .unwrap_or_default()
+        .into_iter()
+        .map(IpAddr::V4)
+        .collect()
+}
+
+/// Decode a name in dns wire format, returns the name and the position after it
+fn read_name(data: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut next = None;
+
+    // compression pointers could point at each other
+    for _ in 0..data.len() {
+        let len = *data.get(pos)? as usize;
+        if len == 0 {
+            return Some((labels.join("."), next.unwrap_or(pos + 1)));
+        } else if len & 0xc0 == 0xc0 {
+            let offset = ((len & 0x3f) << 8) | *data.get(pos + 1)? as usize;
+            if next.is_none() {
+                next = Some(pos + 2);
+            }
+            pos = offset;
+        } else {
+            let label = data.get(pos + 1..pos + 1 + len)?;
+            labels.push(String::from_utf8_lossy(label).to_string());
+            pos += 1 + len;
+        }
+    }
+
+    None
+}
+
+/// The domain search list from RFC 3397
+fn domain_search(data: &[u8]) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        match read_name(data, pos) {
+            Some((name, next)) => {
+                if !name.is_empty() {
+                    names.push(name);
+                }
+                pos = next;
+            },
+            None => {
+                debug!("ignoring invalid domain search list");
+                break;
+            },
+        }
+    }
+
+    names
+}
+
+/// The classless static routes from RFC 3442
+fn classless_routes(data: &[u8]) -> Vec<Route> {
+    let mut routes = Vec::new();
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let length = data[pos];
+        let significant = (usize::from(length) + 7) / 8;
+        if length > 32 || pos + 5 + significant > data.len() {
+            debug!("ignoring invalid classless static route");
+            break;
+        }
+
+        let mut destination = [0; 4];
+        destination[..significant].copy_from_slice(&data[pos + 1..pos + 1 + significant]);
+        let gateway = &data[pos + 1 + significant..pos + 5 + significant];
+
+        routes.push(Route {
+            destination: Prefix {
+                address: IpAddr::V4(Ipv4Addr::from(destination)),
+                length,
+            },
+            gateway: IpAddr::V4(Ipv4Addr::new(gateway[0], gateway[1], gateway[2], gateway[3])),
+        });
+        pos += 5 + significant;
+    }
+
+    routes
+}
+
+fn lease_options(packet: &Packet, server: Ipv4Addr, expire: Duration) -> LeaseOptions {
+    let domain_name = packet.option(options::DOMAIN_NAME)
+        .map(|data| String::from_utf8_lossy(data).trim_end_matches('\0').to_string())
+        .filter(|name| !name.is_empty());
+
+    let mtu = packet.option(options::INTERFACE_MTU)
+        .filter(|data| data.len() == 2)
+        .map(|data| u16::from(data[0]) << 8 | u16::from(data[1]))
+        .filter(|mtu| *mtu >= MIN_MTU);
+
+    LeaseOptions {
+        domain_name,
+        domain_search: packet.option(DOMAIN_SEARCH)
+            .map(domain_search)
+            .unwrap_or_default(),
+        mtu,
+        lease_time: Some(cmp::min(expire.as_secs(), u64::from(u32::max_value())) as u32),
+        server_identifier: Some(IpAddr::V4(server)),
+        broadcast_address: address_list(packet, options::BROADCAST_ADDRESS).into_iter().next(),
+        classless_routes: packet.option(CLASSLESS_STATIC_ROUTE)
+            .map(classless_routes)
+            .unwrap_or_default(),
+        ntp_servers: address_list(packet, options::NETWORK_TIME_PROTOCOL_SERVERS),
+        captive_portal: packet.option(CAPTIVE_PORTAL)
+            .and_then(|data| capport::api_url(&String::from_utf8_lossy(data))),
+        ipv6: None,
+    }
+}
+
+/// The prefix length if the server didn't send a subnet mask
+fn classful_cidr(ip: Ipv4Addr) -> u8 {
+    match ip.octets()[0] {
+        0..=127 => 8,
+        128..=191 => 16,
+        _ => 24,
+    }
+}
+
+fn parse_lease(packet: &Packet, now: Instant) -> Result<Lease> {
+    let ip = Ipv4Addr::from(packet.yiaddr);
+    if ip.is_unspecified() {
+        bail!("no address assigned");
+    }
+
+    let server = match server_identifier(packet) {
+        Some(server) => server,
+        None => bail!("server identifier is missing"),
+    };
+
+    let expire = match packet.option(options::IP_ADDRESS_LEASE_TIME).and_then(seconds) {
+        Some(expire) => expire,
+        None => bail!("lease time is missing"),
+    };
+    let renew = packet.option(options::RENEWAL_TIME_VALUE)
+        .and_then(seconds)
+        .unwrap_or(expire / 2);
+    let rebind = packet.option(options::REBINDING_TIME_VALUE)
+        .and_then(seconds)
+        .unwrap_or(expire * 7 / 8);
+
+    let subnet_cidr = match packet.option(options::SUBNET_MASK).map(addresses) {
+        Some(ref mask) if !mask.is_empty() => u32::from(mask[0]).count_ones() as u8,
+        _ => classful_cidr(ip),
+    };
+
+    Ok(Lease {
+        config: NetworkConfig {
+            ip_address: IpAddr::V4(ip),
+            subnet_cidr,
+            network_number: IpAddr::V4(dhcp::network_number(ip, subnet_cidr)),
+
+            routers: address_list(packet, options::ROUTER),
+            dns_servers: address_list(packet, options::DOMAIN_NAME_SERVER),
+            options: Box::new(lease_options(packet, server, expire)),
+        },
+        server,
+        acquired: now,
+        renew,
+        rebind,
+        expire,
+    })
+}
+
+pub fn parse_mac(mac: &str) -> Result<[u8; 6]> {
+    let mut bytes = [0; 6];
+    let mut parts = mac.trim().split(':');
+
+    for byte in bytes.iter_mut() {
+        let part = match parts.next() {
+            Some(part) if part.len() == 2 => part,
+            _ => bail!("invalid mac address: {:?}", mac),
+        };
+        *byte = u8::from_str_radix(part, 16)
+            .map_err(|_| format_err!("invalid mac address: {:?}", mac))?;
+    }
+
+    if parts.next().is_some() {
+        bail!("invalid mac address: {:?}", mac);
+    }
+
+    Ok(bytes)
+}
+
+pub fn hardware_address(interface: &str) -> Result<[u8; 6]> {
+    let mac = fs::read_to_string(format!("/sys/class/net/{}/address", interface))?;
+    parse_mac(&mac)
+}
+
+/// Reading the carrier fails while the interface is down
+fn has_carrier(interface: &str) -> bool {
+    match fs::read_to_string(format!("/sys/class/net/{}/carrier", interface)) {
+        Ok(carrier) => carrier.trim() == "1",
+        Err(_) => false,
+    }
+}
+
+fn bind_to_device(fd: RawFd, interface: &str) -> Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, libc::SO_BINDTODEVICE,
+                         interface.as_ptr() as *const libc::c_void,
+                         interface.len() as libc::socklen_t)
+    };
+    if ret != 0 {
+        bail!("failed to bind socket to {:?}: {}", interface, io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub fn listen_socket(interface: &str) -> Result<UdpSocket> {
+    let fd = socket::socket(AddressFamily::Inet, SockType::Datagram, SockFlag::SOCK_CLOEXEC, SockProtocol::Udp)?;
+    // takes ownership of the fd so it's closed on error
+    let udp = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    socket::setsockopt(fd, sockopt::ReuseAddr, &true)?;
+    socket::setsockopt(fd, sockopt::Broadcast, &true)?;
+    bind_to_device(fd, interface)?;
+
+    let addr = InetAddr::new(socket::IpAddr::new_v4(0, 0, 0, 0), CLIENT_PORT);
+    socket::bind(udp.as_raw_fd(), &SockAddr::new_inet(addr))?;
+
+    Ok(udp)
+}
+
+fn configure(interface: &str, config: &NetworkConfig) -> Result<()> {
+    info!("configuring {}/{} on {}", config.ip_address, config.subnet_cidr, interface);
+    let index = if_nametoindex(interface)?;
+    let mut socket = netlink::Socket::connect()?;
+
+    socket.flush_addresses(index, false)?;
+    socket.add_address(index, config.ip_address, config.subnet_cidr)
+        .context("failed to add address")?;
+
+    if let Some(mtu) = config.options.mtu {
+        socket.set_link_mtu(index, u32::from(mtu))
+            .context("failed to set mtu")?;
+    }
+
+    // routers are ignored if the server sent classless routes
+    if config.options.classless_routes.is_empty() {
+        if let Some(router) = config.routers.first() {
+            let default = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+            socket.replace_route(index, default, 0, Some(*router))
+                .context("failed to add default route")?;
+        }
+    } else {
+        for route in &config.options.classless_routes {
+            let gateway = if route.gateway.is_unspecified() {
+                None
+            } else {
+                Some(route.gateway)
+            };
+            socket.replace_route(index, route.destination.address, route.destination.length, gateway)
+                .with_context(|_| format!("failed to add route {}", route))?;
+        }
+    }
+
+    Ok(())
+}
+
+fn deconfigure(interface: &str) -> Result<()> {
+    info!("removing addresses from {}", interface);
+    let index = if_nametoindex(interface)?;
+    let mut socket = netlink::Socket::connect()?;
+    socket.flush_addresses(index, false)
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0; 256];
+    match unistd::gethostname(&mut buf) {
+        Ok(hostname) => hostname.to_str().ok().map(|x| x.to_string()),
+        Err(err) => {
+            warn!("failed to read hostname: {}", err);
+            None
+        },
+    }
+}
+
+extern "C" fn request_stop(_: libc::c_int) {
+    STOP.store(true, Ordering::SeqCst);
+}
+
+fn setup_signal_handlers() -> Result<()> {
+    // no SA_RESTART so a blocking recv returns early
+    let action = SigAction::new(SigHandler::Handler(request_stop), SaFlags::empty(), SigSet::empty());
+    for sig in &[Signal::SIGTERM, Signal::SIGINT] {
+        unsafe { signal::sigaction(*sig, &action)? };
+    }
+    Ok(())
+}
+
+fn send(socket: &UdpSocket, transmit: &Transmit) {
+    let addr = transmit.destination.socket_addr();
+    if let Err(err) = socket.send_to(&transmit.packet, addr) {
+        warn!("failed to send dhcp packet to {}: {}", addr, err);
+    }
+}
+
+fn apply<F>(interface: &str, socket: &UdpSocket, step: Step, notify: &mut F) -> Result<()>
+    where F: FnMut(UpdateMessage) -> Result<()>
+{
+    if let Some(transmit) = &step.transmit {
+        send(socket, transmit);
+    }
+
+    if let Some(update) = step.update {
+        match &update {
+            UpdateMessage::Bound(config) => configure(interface, config)?,
+            UpdateMessage::Expired => {
+                deconfigure(interface)?;
+                mac::release(interface);
+            },
+            _ => (),
+        }
+        notify(update)?;
+    }
+
+    Ok(())
+}
+
+/// Apply the settings of the network we're connected to
+fn setup_client(client: &mut Client, config: &Config, ssid: Option<&str>) {
+    let settings = config.dhcp_config(ssid);
+
+    if settings.send_hostname {
+        client.set_hostname(hostname());
+    }
+    client.set_fallback(settings.fallback.as_ref().map(dhcp::fallback_config));
+}
+
+/// Run the dhcp client on an interface until we receive SIGTERM or SIGINT,
+/// the lease is applied to the interface and reported to `notify`. The
+/// settings from `config` are selected by ssid whenever we get a carrier.
+pub fn run<F>(interface: &str, config: &Config, mut notify: F) -> Result<()>
+    where F: FnMut(UpdateMessage) -> Result<()>
+{
+    setup_signal_handlers()?;
+
+    let mut mac = hardware_address(interface)?;
+    netlink::Socket::connect()?.set_link_up(if_nametoindex(interface)?, true)?;
+    let socket = listen_socket(interface)?;
+
+    let mut client = Client::new(mac, Instant::now());
+    let mut carrier = false;
+    let mut buf = [0; 1500];
+
+    loop {
+        let now = Instant::now();
+
+        if STOP.load(Ordering::SeqCst) {
+            let configured = client.configured();
+            if let Some(transmit) = client.release(now) {
+                info!("releasing dhcp lease");
+                send(&socket, &transmit);
+            }
+            if configured {
+                deconfigure(interface)?;
+            }
+            return Ok(());
+        }
+
+        if has_carrier(interface) != carrier {
+            carrier = !carrier;
+            if carrier {
+                info!("got carrier");
+                let ssid = wifi::current_ssid(interface).ok();
+                let ssid = ssid.as_ref().map(|x| x.as_str());
+                if mac::apply_on_carrier(config, interface, ssid)
+                        .context("failed to apply mac policy")? {
+                    // the link has been reset, wait for the next carrier
+                    carrier = false;
+                    continue;
+                }
+
+                mac = hardware_address(interface)?;
+                client = Client::new(mac, now);
+                setup_client(&mut client, config, ssid);
+                notify(UpdateMessage::Carrier)?;
+            } else {
+                info!("carrier lost");
+                if client.configured() {
+                    deconfigure(interface)?;
+                }
+                mac::release(interface);
+                notify(UpdateMessage::NoCarrier)?;
+            }
+        }
+
+        if !carrier {
+            thread::sleep(POLL_INTERVAL);
+            continue;
+        }
+
+        let step = if now >= client.deadline() {
+            client.timeout(now)
+        } else {
+            let wait = cmp::min(client.deadline() - now, POLL_INTERVAL);
+            socket.set_read_timeout(Some(wait))?;
+
+            match socket.recv_from(&mut buf) {
+                Ok((n, _)) => client.handle(&buf[..n], Instant::now()),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock ||
+                                err.kind() == io::ErrorKind::TimedOut ||
+                                err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        apply(interface, &socket, step, &mut notify)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x02, 0x00, 0x00, 0x00, 0x00, 0x01];
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 23);
+
+    fn reply(xid: [u8; 4], message_type: u8, lease_time: u32) -> Vec<u8> {
+        let message_type = [message_type];
+        let server = SERVER.octets();
+        let mask = [255, 255, 255, 0];
+        let lease_time = u32_bytes!(lease_time);
+
+        let packet = Packet {
+            reply: true,
+            hops: 0,
+            xid,
+            secs: 0,
+            broadcast: true,
+            ciaddr: [0; 4],
+            yiaddr: ADDRESS.octets(),
+            siaddr: [0; 4],
+            giaddr: [0; 4],
+            chaddr: MAC,
+            options: vec![
+                options::Option { code: options::DHCP_MESSAGE_TYPE, data: &message_type },
+                options::Option { code: options::SERVER_IDENTIFIER, data: &server },
+                options::Option { code: options::SUBNET_MASK, data: &mask },
+                options::Option { code: options::ROUTER, data: &server },
+                options::Option { code: options::DOMAIN_NAME_SERVER, data: &server },
+                options::Option { code: options::IP_ADDRESS_LEASE_TIME, data: &lease_time },
+            ],
+        };
+
+        let mut buf = [0; 576];
+        packet.encode(&mut buf).to_vec()
+    }
+
+    fn sent(step: &Step) -> (Destination, u8, [u8; 4], [u8; 4]) {
+        let transmit = step.transmit.as_ref().expect("nothing sent");
+        let packet = packet::decode(&transmit.packet).expect("invalid packet");
+        assert!(!packet.reply);
+        assert_eq!(packet.chaddr, MAC);
+        (transmit.destination, packet.message_type(), packet.xid, packet.ciaddr)
+    }
+
+    fn bind(client: &mut Client, now: Instant) {
+        let (_, _, xid, _) = sent(&client.timeout(now));
+        let (_, _, xid, _) = sent(&client.handle(&reply(xid, dhcp4r::OFFER, 3600), now));
+        let step = client.handle(&reply(xid, dhcp4r::ACK, 3600), now);
+        assert!(step.transmit.is_none());
+        assert_eq!(client.state(), State::Bound);
+    }
+
+    #[test]
+    fn test_parse_mac() {
+        assert_eq!(parse_mac("02:00:00:00:00:01\n").unwrap(), MAC);
+        assert_eq!(parse_mac("F0:de:F1:12:34:56").unwrap(), [0xf0, 0xde, 0xf1, 0x12, 0x34, 0x56]);
+        assert!(parse_mac("02:00:00:00:00").is_err());
+        assert!(parse_mac("02:00:00:00:00:01:02").is_err());
+        assert!(parse_mac("02:00:00:00:00:xx").is_err());
+    }
+
+    #[test]
+    fn test_discover_request_bound() {
+        let now = Instant::now();
+        let mut client = Client::new(MAC, now);
+        assert_eq!(client.state(), State::Init);
+
+        let (destination, message_type, xid, _) = sent(&client.timeout(now));
+        assert_eq!(destination, Destination::Broadcast);
+        assert_eq!(message_type, dhcp4r::DISCOVER);
+        assert_eq!(client.state(), State::Selecting);
+
+        // replies to other clients are ignored
+        let step = client.handle(&reply([1, 2, 3, 4], dhcp4r::OFFER, 3600), now);
+        assert_eq!(step, Step::default());
+        assert_eq!(client.state(), State::Selecting);
+
+        let step = client.handle(&reply(xid, dhcp4r::OFFER, 3600), now);
+        let (destination, message_type, request_xid, _) = sent(&step);
+        assert_eq!(destination, Destination::Broadcast);
+        assert_eq!(message_type, dhcp4r::REQUEST);
+        assert_eq!(request_xid, xid);
+        assert_eq!(client.state(), State::Requesting);
+
+        let transmit = step.transmit.unwrap();
+        let request = packet::decode(&transmit.packet).unwrap();
+        assert_eq!(request.option(options::REQUESTED_IP_ADDRESS), Some(&ADDRESS.octets()[..]));
+        assert_eq!(request.option(options::SERVER_IDENTIFIER), Some(&SERVER.octets()[..]));
+
+        let step = client.handle(&reply(xid, dhcp4r::ACK, 3600), now);
+        assert_eq!(step, Step::update(UpdateMessage::Bound(NetworkConfig {
+            ip_address: "192.168.1.23".parse().unwrap(),
+            subnet_cidr: 24,
+            network_number: "192.168.1.0".parse().unwrap(),
+            routers: vec!["192.168.1.1".parse().unwrap()],
+            dns_servers: vec!["192.168.1.1".parse().unwrap()],
+            options: Box::new(LeaseOptions {
+                lease_time: Some(3600),
+                server_identifier: Some("192.168.1.1".parse().unwrap()),
+                ..Default::default()
+            }),
+        })));
+        assert_eq!(client.state(), State::Bound);
+        assert_eq!(client.deadline(), now + Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn test_discover_backoff() {
+        let now = Instant::now();
+        let mut client = Client::new(MAC, now);
+
+        client.timeout(now);
+        assert_eq!(client.deadline(), now + Duration::from_secs(4));
+        assert_eq!(client.timeout(now), Step::default());
+
+        let now = client.deadline();
+        let (_, message_type, _, _) = sent(&client.timeout(now));
+        assert_eq!(message_type, dhcp4r::DISCOVER);
+        assert_eq!(client.deadline(), now + Duration::from_secs(8));
+
+        for _ in 0..10 {
+            let now = client.deadline();
+            client.timeout(now);
+        }
+        assert_eq!(client.state(), State::Selecting);
+    }
+
+    #[test]
+    fn test_hostname() {
+        let now = Instant::now();
+        let mut client = Client::new(MAC, now);
+
+        let step = client.timeout(now);
+        let discover = packet::decode(&step.transmit.as_ref().unwrap().packet).unwrap();
+        assert_eq!(discover.option(options::HOST_NAME), None);
+
+        client.set_hostname(Some("laptop".into()));
+        let (_, _, xid, _) = sent(&step);
+        let step = client.handle(&reply(xid, dhcp4r::OFFER, 3600), now);
+        let request = packet::decode(&step.transmit.as_ref().unwrap().packet).unwrap();
+        assert_eq!(request.option(options::HOST_NAME), Some(&b"laptop"[..]));
+    }
+
+    #[test]
+    fn test_fallback() {
+        let start = Instant::now();
+        let mut client = Client::new(MAC, start);
+        let fallback = dhcp::fallback_config(&::config::StaticAddress {
+            address: "169.254.0.2".parse().unwrap(),
+            subnet_cidr: 16,
+            routers: vec![],
+            dns: vec![],
+        });
+        client.set_fallback(Some(fallback.clone()));
+
+        let mut updates = Vec::new();
+        let mut now = start;
+        while now < start + Duration::from_secs(120) {
+            let step = client.timeout(now);
+            assert!(step.transmit.is_some());
+            if let Some(update) = step.update {
+                updates.push((now - start, update));
+            }
+            now = client.deadline();
+        }
+        // discovers at 0s, 4s, 12s, 28s and 60s
+        assert_eq!(updates, vec![
+            (Duration::from_secs(60), UpdateMessage::Bound(fallback)),
+        ]);
+        assert!(client.configured());
+        assert_eq!(client.state(), State::Selecting);
+
+        // a server showed up, the lease replaces the fallback
+        let (_, _, xid, _) = sent(&client.timeout(now));
+        let (_, _, xid, _) = sent(&client.handle(&reply(xid, dhcp4r::OFFER, 3600), now));
+        let step = client.handle(&reply(xid, dhcp4r::ACK, 3600), now);
+        match step.update {
+            Some(UpdateMessage::Bound(config)) => assert_eq!(config.ip_address, IpAddr::V4(ADDRESS)),
+            update => panic!("unexpected update: {:?}", update),
+        }
+    }
+
+    #[test]
+    fn test_request_nak() {
+        let now = Instant::now();
+        let mut client = Client::new(MAC, now);
+
+        let (_, _, xid, _) = sent(&client.timeout(now));
+        client.handle(&reply(xid, dhcp4r::OFFER, 3600), now);
+        assert_eq!(client.handle(&reply(xid, dhcp4r::NAK, 3600), now), Step::default());
+        assert_eq!(client.state(), State::Init);
+        assert_eq!(client.deadline(), now);
+    }
+
+    #[test]
+    fn test_renew_rebind_expire() {
+        let start = Instant::now();
+        let mut client = Client::new(MAC, start);
+        bind(&mut client, start);
+
+        // T1, renew with our server
+        let now = start + Duration::from_secs(1800);
+        let (destination, message_type, _, ciaddr) = sent(&client.timeout(now));
+        assert_eq!(destination, Destination::Server(SERVER));
+        assert_eq!(message_type, dhcp4r::REQUEST);
+        assert_eq!(ciaddr, ADDRESS.octets());
+        assert_eq!(client.state(), State::Renewing);
+        // half of the time until T2
+        assert_eq!(client.deadline(), now + Duration::from_secs(675));
+
+        // T2, ask any server
+        let now = start + Duration::from_secs(3150);
+        let (destination, message_type, _, ciaddr) = sent(&client.timeout(now));
+        assert_eq!(destination, Destination::Broadcast);
+        assert_eq!(message_type, dhcp4r::REQUEST);
+        assert_eq!(ciaddr, ADDRESS.octets());
+        assert_eq!(client.state(), State::Rebinding);
+        assert_eq!(client.deadline(), now + Duration::from_secs(225));
+
+        // retransmits don't go past the end of the lease
+        let now = start + Duration::from_secs(3560);
+        client.timeout(now);
+        assert_eq!(client.deadline(), start + Duration::from_secs(3600));
+
+        let now = client.deadline();
+        assert_eq!(client.timeout(now), Step::update(UpdateMessage::Expired));
+        assert_eq!(client.state(), State::Init);
+        assert!(client.lease().is_none());
+    }
+
+    #[test]
+    fn test_renew_ack() {
+        let start = Instant::now();
+        let mut client = Client::new(MAC, start);
+        bind(&mut client, start);
+
+        let now = start + Duration::from_secs(1800);
+        let (_, _, xid, _) = sent(&client.timeout(now));
+
+        let step = client.handle(&reply(xid, dhcp4r::ACK, 7200), now);
+        match step.update {
+            Some(UpdateMessage::Renew(config)) => assert_eq!(config.ip_address, IpAddr::V4(ADDRESS)),
+            update => panic!("unexpected update: {:?}", update),
+        }
+        assert_eq!(client.state(), State::Bound);
+        assert_eq!(client.deadline(), now + Duration::from_secs(3600));
+        assert_eq!(client.lease().unwrap().expire_at(), now + Duration::from_secs(7200));
+    }
+
+    #[test]
+    fn test_renew_nak() {
+        let start = Instant::now();
+        let mut client = Client::new(MAC, start);
+        bind(&mut client, start);
+
+        let now = start + Duration::from_secs(1800);
+        let (_, _, xid, _) = sent(&client.timeout(now));
+
+        let step = client.handle(&reply(xid, dhcp4r::NAK, 0), now);
+        assert_eq!(step, Step::update(UpdateMessage::Expired));
+        assert_eq!(client.state(), State::Init);
+    }
+
+    #[test]
+    fn test_release() {
+        let now = Instant::now();
+        let mut client = Client::new(MAC, now);
+        assert_eq!(client.release(now), None);
+
+        bind(&mut client, now);
+        let transmit = client.release(now).expect("no release sent");
+        assert_eq!(transmit.destination, Destination::Server(SERVER));
+
+        let packet = packet::decode(&transmit.packet).unwrap();
+        assert_eq!(packet.message_type(), dhcp4r::RELEASE);
+        assert_eq!(packet.ciaddr, ADDRESS.octets());
+        assert_eq!(packet.option(options::SERVER_IDENTIFIER), Some(&SERVER.octets()[..]));
+
+        assert_eq!(client.state(), State::Init);
+        assert!(client.lease().is_none());
+        assert_eq!(client.release(now), None);
+    }
+
+    #[test]
+    fn test_invalid_packets() {
+        let now = Instant::now();
+        let mut client = Client::new(MAC, now);
+        client.timeout(now);
+
+        assert_eq!(client.handle(&[], now), Step::default());
+        assert_eq!(client.handle(&[0; 239], now), Step::default());
+        assert_eq!(client.handle(&[0; 300], now), Step::default());
+        assert_eq!(client.state(), State::Selecting);
+    }
+
+    #[test]
+    fn test_domain_search() {
+        // RFC 3397, section 2
+        let data = [
+            3, b'e', b'n', b'g', 5, b'a', b'p', b'p', b'l', b'e', 3, b'c', b'o', b'm', 0,
+            6, b'm', b'a', b'r', b'k', b'e', b't', 0xc0, 4,
+        ];
+        assert_eq!(domain_search(&data), vec![
+            "eng.apple.com".to_string(),
+            "market.apple.com".to_string(),
+        ]);
+
+        // pointers to each other
+        assert_eq!(domain_search(&[0xc0, 2, 0xc0, 0]), Vec::<String>::new());
+        // truncated
+        assert_eq!(domain_search(&[3, b'c', b'o']), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_classless_routes() {
+        let data = [
+            8, 10, 192, 168, 1, 254,
+            24, 192, 0, 2, 0, 0, 0, 0,
+            0, 192, 168, 1, 1,
+            // truncated
+            16, 172,
+        ];
+        assert_eq!(classless_routes(&data), vec![
+            Route {
+                destination: "10.0.0.0/8".parse().unwrap(),
+                gateway: "192.168.1.254".parse().unwrap(),
+            },
+            Route {
+                destination: "192.0.2.0/24".parse().unwrap(),
+                gateway: "0.0.0.0".parse().unwrap(),
+            },
+            Route {
+                destination: "0.0.0.0/0".parse().unwrap(),
+                gateway: "192.168.1.1".parse().unwrap(),
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_classless_routes_invalid_length() {
+        let data = [
+            0xff, 10, 0, 0, 0, 192, 168, 1, 1,
+        ];
+        assert_eq!(classless_routes(&data), vec![]);
+        assert_eq!(classless_routes(&[33, 10, 0, 0, 0, 0, 192, 168, 1, 1]), vec![]);
+    }
+
+    #[test]
+    fn test_parse_lease_defaults() {
+        let now = Instant::now();
+        let data = reply([0; 4], dhcp4r::ACK, 1000);
+        let packet = packet::decode(&data).unwrap();
+        let lease = parse_lease(&packet, now).unwrap();
+
+        assert_eq!(lease.server, SERVER);
+        assert_eq!(lease.renew, Duration::from_secs(500));
+        assert_eq!(lease.rebind, Duration::from_secs(875));
+        assert_eq!(lease.expire, Duration::from_secs(1000));
+
+        assert_eq!(classful_cidr(Ipv4Addr::new(10, 1, 2, 3)), 8);
+        assert_eq!(classful_cidr(Ipv4Addr::new(172, 16, 0, 1)), 16);
+        assert_eq!(classful_cidr(Ipv4Addr::new(192, 168, 0, 1)), 24);
+    }
+}
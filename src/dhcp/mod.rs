@@ -0,0 +1,749 @@
+pub mod client;
+
+use capport;
+use config::{Config, ClientId, DhcpConfig, StaticAddress};
+use errors::Result;
+use mac;
+use wifi::{self, Network};
+
+use regex::Regex;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fmt::Write;
+use std::process::Command;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+
+/// An address with a prefix length, eg. `192.0.2.0/24`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Prefix {
+    pub address: IpAddr,
+    pub length: u8,
+}
+
+impl FromStr for Prefix {
+    type Err = ::errors::Error;
+
+    fn from_str(s: &str) -> Result<Prefix> {
+        let (address, length) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+
+        let address = address.parse::<IpAddr>()?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let length = match length {
+            Some(length) => length.parse()?,
+            None => max,
+        };
+
+        if length > max {
+            bail!("invalid prefix length: {:?}", s);
+        }
+
+        Ok(Prefix {
+            address,
+            length,
+        })
+    }
+}
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "{}/{}", self.address, self.length)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Route {
+    pub destination: Prefix,
+    /// unspecified if the destination is on-link
+    pub gateway: IpAddr,
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, w: &mut fmt::Formatter) -> fmt::Result {
+        write!(w, "{} via {}", self.destination, self.gateway)
+    }
+}
+
+/// Addresses and settings from dhcpv6 and router advertisements
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ipv6Config {
+    pub addresses: Vec<IpAddr>,
+    pub prefixes: Vec<Prefix>,
+    pub dns_servers: Vec<IpAddr>,
+    pub domain_search: Vec<String>,
+}
+
+impl Ipv6Config {
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty() &&
+        self.prefixes.is_empty() &&
+        self.dns_servers.is_empty() &&
+        self.domain_search.is_empty()
+    }
+}
+
+/// The optional parts of a lease, the server doesn't have to send any of those
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LeaseOptions {
+    pub domain_name: Option<String>,
+    pub domain_search: Vec<String>,
+    pub mtu: Option<u16>,
+    /// in seconds
+    pub lease_time: Option<u32>,
+    pub server_identifier: Option<IpAddr>,
+    pub broadcast_address: Option<IpAddr>,
+    /// if the server sends those, routers should be ignored (RFC 3442)
+    pub classless_routes: Vec<Route>,
+    pub ntp_servers: Vec<IpAddr>,
+    /// the captive portal api (RFC 8910)
+    pub captive_portal: Option<String>,
+    pub ipv6: Option<Ipv6Config>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkConfig {
+    pub ip_address: IpAddr,
+    pub subnet_cidr: u8,
+    pub network_number: IpAddr,
+
+    pub routers: Vec<IpAddr>,
+    pub dns_servers: Vec<IpAddr>,
+    #[serde(default)]
+    pub options: Box<LeaseOptions>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub enum UpdateMessage {
+    Carrier,
+    Bound(NetworkConfig),
+    Reboot(NetworkConfig),
+    Renew(NetworkConfig),
+    NoCarrier,
+    /// the lease couldn't be renewed
+    Expired,
+    Stopped,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct NetworkUpdate {
+    pub interface: String,
+    pub ssid: Option<String>,
+    #[serde(default)]
+    pub bssid: Option<String>,
+    #[serde(default)]
+    pub mac: Option<String>,
+    /// the scan result of the access point
+    #[serde(default)]
+    pub wifi: Option<Box<Network>>,
+    pub message: Option<UpdateMessage>,
+    pub env: Vec<(String, String)>,
+}
+
+/// The first address of the network an ipv4 address is in
+pub fn network_number(ip: Ipv4Addr, cidr: u8) -> Ipv4Addr {
+    let mask = u32::max_value().checked_shl(32 - u32::from(cidr)).unwrap_or(0);
+    Ipv4Addr::from(u32::from(ip) & mask)
+}
+
+/// dhcpcd exports empty variables for options the server didn't send
+fn var<'a>(env: &'a BTreeMap<String, String>, key: &str) -> Option<&'a str> {
+    env.get(key)
+        .map(|value| value.trim())
+        .filter(|value| !value.is_empty())
+}
+
+/// A broken optional value shouldn't discard the whole lease
+fn parse_var<T: FromStr>(env: &BTreeMap<String, String>, key: &str) -> Option<T> {
+    let value = var(env, key)?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            warn!("ignoring invalid value for {}: {:?}", key, value);
+            None
+        },
+    }
+}
+
+fn parse_list<T: FromStr>(env: &BTreeMap<String, String>, key: &str) -> Vec<T> {
+    var(env, key)
+        .map(|value| value.split_whitespace()
+            .filter_map(|item| match item.parse() {
+                Ok(item) => Some(item),
+                Err(_) => {
+                    warn!("ignoring invalid value in {}: {:?}", key, item);
+                    None
+                },
+            })
+            .collect())
+        .unwrap_or_default()
+}
+
+/// dhcpcd exports those as pairs of `destination/cidr gateway`
+fn parse_routes(env: &BTreeMap<String, String>, key: &str) -> Vec<Route> {
+    let items = parse_list::<String>(env, key);
+
+    items.chunks(2)
+        .filter_map(|pair| {
+            if pair.len() != 2 {
+                warn!("ignoring incomplete route in {}: {:?}", key, pair);
+                return None;
+            }
+            match (pair[0].parse(), pair[1].parse()) {
+                (Ok(destination), Ok(gateway)) => Some(Route {
+                    destination,
+                    gateway,
+                }),
+                _ => {
+                    warn!("ignoring invalid route in {}: {:?}", key, pair);
+                    None
+                },
+            }
+        })
+        .collect()
+}
+
+/// dhcpcd numbers some variables, like `ra1_prefix`, `#` matches that number
+fn numbered<'a>(env: &'a BTreeMap<String, String>, pattern: &str) -> Vec<&'a str> {
+    let pattern = format!("^{}$", pattern.replace("#", r"\d+"));
+    let re = Regex::new(&pattern).unwrap();
+
+    env.keys()
+        .filter(|key| re.is_match(key))
+        .map(|key| key.as_str())
+        .collect()
+}
+
+fn read_ipv6_config(env: &BTreeMap<String, String>) -> Option<Ipv6Config> {
+    let mut config = Ipv6Config {
+        dns_servers: parse_list(env, "new_dhcp6_name_servers"),
+        domain_search: parse_list(env, "new_dhcp6_domain_search"),
+        ..Default::default()
+    };
+
+    for key in numbered(env, "new_dhcp6_ia_na#_ia_addr#") {
+        config.addresses.extend(parse_list::<IpAddr>(env, key));
+    }
+    for key in numbered(env, "ra#_prefix") {
+        config.prefixes.extend(parse_list::<Prefix>(env, key));
+    }
+    for key in numbered(env, "ra#_rdnss") {
+        config.dns_servers.extend(parse_list::<IpAddr>(env, key));
+    }
+    for key in numbered(env, "ra#_dnssl") {
+        config.domain_search.extend(parse_list::<String>(env, key));
+    }
+
+    if config.is_empty() {
+        None
+    } else {
+        Some(config)
+    }
+}
+
+/// The network config of a static fallback address
+pub fn fallback_config(fallback: &StaticAddress) -> NetworkConfig {
+    NetworkConfig {
+        ip_address: IpAddr::V4(fallback.address),
+        subnet_cidr: fallback.subnet_cidr,
+        network_number: IpAddr::V4(network_number(fallback.address, fallback.subnet_cidr)),
+        routers: fallback.routers.iter()
+            .map(|ip| IpAddr::V4(*ip))
+            .collect(),
+        dns_servers: fallback.dns.clone(),
+        options: Box::default(),
+    }
+}
+
+pub fn read_network_config(env: &BTreeMap<String, String>) -> Result<NetworkConfig> {
+    let ip_address = match var(env, "new_ip_address") {
+        Some(ip) => ip.parse::<Ipv4Addr>()?,
+        None => bail!("new_ip_address is missing"),
+    };
+    let subnet_cidr = match var(env, "new_subnet_cidr") {
+        Some(cidr) => cidr.parse()?,
+        None => bail!("new_subnet_cidr is missing"),
+    };
+    if subnet_cidr > 32 {
+        bail!("invalid subnet cidr: {}", subnet_cidr);
+    }
+
+    let network_number = parse_var(env, "new_network_number")
+        .unwrap_or_else(|| IpAddr::V4(network_number(ip_address, subnet_cidr)));
+
+    Ok(NetworkConfig {
+        ip_address: IpAddr::V4(ip_address),
+        subnet_cidr,
+        network_number,
+
+        routers: parse_list(env, "new_routers"),
+        dns_servers: parse_list(env, "new_domain_name_servers"),
+        options: Box::new(LeaseOptions {
+            domain_name: var(env, "new_domain_name").map(|x| x.to_string()),
+            domain_search: parse_list(env, "new_domain_search"),
+            mtu: parse_var(env, "new_interface_mtu"),
+            lease_time: parse_var(env, "new_dhcp_lease_time"),
+            server_identifier: parse_var(env, "new_dhcp_server_identifier"),
+            broadcast_address: parse_var(env, "new_broadcast_address"),
+            classless_routes: parse_routes(env, "new_classless_static_routes"),
+            ntp_servers: parse_list(env, "new_ntp_servers"),
+            captive_portal: var(env, "new_captive_portal")
+                .or_else(|| var(env, "new_dhcp6_captive_portal"))
+                .and_then(capport::api_url),
+            ipv6: read_ipv6_config(env),
+        }),
+    })
+}
+
+
+pub fn read_dhcp_env() -> Result<NetworkUpdate> {
+    let interface = env::var("interface")?;
+    let ssid = match env::var("ifssid") {
+        Ok(ssid) => Some(ssid.to_string()),
+        Err(_) => None,
+    };
+    let bssid = match ssid {
+        Some(_) => wifi::current_bssid(&interface).ok(),
+        None => None,
+    };
+    let network = match ssid {
+        Some(_) => wifi::current_network(&interface).ok().map(Box::new),
+        None => None,
+    };
+    let mac = client::hardware_address(&interface)
+        .map(mac::format_mac)
+        .ok();
+    let vars = env::vars().collect::<BTreeMap<_, _>>();
+
+    Ok(NetworkUpdate {
+        interface,
+        ssid,
+        bssid,
+        mac,
+        wifi: network,
+        message: match env::var("reason")?.as_str() {
+            "CARRIER" => {
+                // TODO: set interface up?
+                Some(UpdateMessage::Carrier)
+            },
+            "BOUND" => {
+                Some(UpdateMessage::Bound(read_network_config(&vars)?))
+            },
+            "REBOOT" => {
+                // I only noticed those when running dhcpcd on an already configured interface
+                Some(UpdateMessage::Reboot(read_network_config(&vars)?))
+            },
+            "RENEW" => {
+                // we can probably ignore those
+                Some(UpdateMessage::Renew(read_network_config(&vars)?))
+            },
+            "NOCARRIER" => {
+                // the interface went down
+                // TODO: set interface down?
+                Some(UpdateMessage::NoCarrier)
+            },
+            "STATIC" => {
+                // no dhcp server answered and the fallback has been configured
+                Some(UpdateMessage::Bound(read_network_config(&vars)?))
+            },
+            "EXPIRE" => {
+                // we couldn't renew our ip
+                Some(UpdateMessage::Expired)
+            },
+            "PREINIT" => {
+                // dhcpcd started, nothing to do
+                None
+            },
+            "STOP" | "STOPPED" => {
+                // dhcpcd stopped, nothing to do
+                // TODO: set interface down?
+                // TODO: we have to signal this so the event can be safely discarded
+                Some(UpdateMessage::Stopped)
+            },
+            _ => None,
+        },
+        env: vars.into_iter().collect(),
+    })
+}
+
+/// Quote a string for dhcpcd.conf
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn join<T: ToString>(items: &[T]) -> String {
+    items.iter()
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn write_fallback_profile(conf: &mut String, name: &str, fallback: &StaticAddress) {
+    writeln!(conf, "\nprofile {}", name).unwrap();
+    writeln!(conf, "static ip_address={}/{}", fallback.address, fallback.subnet_cidr).unwrap();
+    if !fallback.routers.is_empty() {
+        writeln!(conf, "static routers={}", join(&fallback.routers)).unwrap();
+    }
+    if !fallback.dns.is_empty() {
+        writeln!(conf, "static domain_name_servers={}", join(&fallback.dns)).unwrap();
+    }
+}
+
+fn write_client_id(conf: &mut String, client_id: ClientId) {
+    match client_id {
+        ClientId::Hwaddr => conf.push_str("clientid\n"),
+        ClientId::Duid => conf.push_str("duid\n"),
+    }
+}
+
+/// The settings of a network that differ from the [dhcp] section. dhcpcd
+/// applies those on top of the global settings, so sending the hostname
+/// can't be disabled for a single network, this is rejected when loading
+/// the config.
+fn write_network_block(conf: &mut String, ssid: &str, global: &DhcpConfig, network: &DhcpConfig, fallback: &str) {
+    writeln!(conf, "\nssid {}", quote(ssid)).unwrap();
+
+    if network.send_hostname && !global.send_hostname {
+        conf.push_str("hostname\n");
+    }
+    if network.client_id != global.client_id {
+        write_client_id(conf, network.client_id);
+    }
+
+    if network.ipv6 && !global.ipv6 {
+        conf.push_str("ipv6\nslaac private\n");
+    } else if !network.ipv6 && global.ipv6 {
+        conf.push_str("noipv6\n");
+    }
+
+    let options = network.options.iter()
+        .filter(|option| !global.options.contains(option))
+        .map(|option| option.as_str())
+        .collect::<Vec<_>>();
+    if !options.is_empty() {
+        writeln!(conf, "option {}", options.join(", ")).unwrap();
+    }
+
+    if network.fallback.is_some() && network.fallback != global.fallback {
+        writeln!(conf, "fallback {}", fallback).unwrap();
+    }
+}
+
+/// Generate the dhcpcd.conf for the [dhcp] section and the network profiles
+pub fn dhcpcd_conf(config: &Config) -> String {
+    let global = &config.dhcp;
+    let mut conf = String::from("# generated by snaild from snail.conf, see dhcpcd.conf(5)\n\n");
+
+    // safe to enable, this only works if the server supports it too
+    conf.push_str("option rapid_commit\n");
+    // required by RFC 2131
+    conf.push_str("require dhcp_server_identifier\n");
+    conf.push_str("noipv4ll\n");
+
+    write_client_id(&mut conf, global.client_id);
+    if global.send_hostname {
+        conf.push_str("hostname\n");
+    }
+    if global.ipv6 {
+        // stable private addresses that differ between networks (RFC 7217)
+        conf.push_str("slaac private\n");
+    } else {
+        conf.push_str("noipv6\n");
+    }
+    if !global.options.is_empty() {
+        writeln!(conf, "option {}", global.options.join(", ")).unwrap();
+    }
+    if global.fallback.is_some() {
+        conf.push_str("fallback snail_fallback\n");
+    }
+
+    let mut ssids = config.networks.keys().collect::<Vec<_>>();
+    ssids.sort();
+
+    let mut profiles = Vec::new();
+    for ssid in ssids {
+        let network = config.dhcp_config(Some(ssid));
+        if network == *global {
+            continue;
+        }
+
+        let name = format!("snail_fallback_{}", profiles.len());
+        write_network_block(&mut conf, ssid, global, &network, &name);
+        if network.fallback != global.fallback {
+            if let Some(fallback) = network.fallback {
+                profiles.push((name, fallback));
+            }
+        }
+    }
+
+    // profiles have to be at the end, everything after them belongs to the profile
+    if let Some(fallback) = &global.fallback {
+        write_fallback_profile(&mut conf, "snail_fallback", fallback);
+    }
+    for (name, fallback) in &profiles {
+        write_fallback_profile(&mut conf, name, fallback);
+    }
+
+    conf
+}
+
+pub fn run_dhcpcd(conf: &str, interface: &str, hook: &str) -> Result<()> {
+
+    // TODO: if hook is not absolute, we need to resolve this
+    // let hook = env::current_exe().unwrap().to_str().unwrap(),
+
+    // TODO: this should be replaced by a builtin dhcpcd eventually
+    // XXX: maybe set -K, --nolink
+    let mut child = Command::new("dhcpcd")
+                    .args(&["-f", conf,
+                            "-c", hook,
+                            "-m", "2048", // ensure ethernet is prefered
+                            "-B", // no background
+                            interface])
+                    .spawn()?;
+
+    // wait until interface goes down
+    // TODO: verify this returns when the interface goes down
+    child.wait()?;
+
+    // TODO: we can't get the info here because it is sent to the hook instead :/
+    // TODO: might need snaild to relay to info for us
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(vars: &[(&str, &str)]) -> BTreeMap<String, String> {
+        vars.iter()
+            .map(|(key, value)| ((*key).to_string(), (*value).to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_prefix() {
+        let prefix = "192.0.2.0/24".parse::<Prefix>().unwrap();
+        assert_eq!(prefix, Prefix {
+            address: "192.0.2.0".parse().unwrap(),
+            length: 24,
+        });
+        assert_eq!(prefix.to_string(), "192.0.2.0/24");
+
+        assert_eq!("2001:db8::1".parse::<Prefix>().unwrap().length, 128);
+        assert!("192.0.2.0/33".parse::<Prefix>().is_err());
+        assert!("192.0.2.0/".parse::<Prefix>().is_err());
+        assert!("example.com/24".parse::<Prefix>().is_err());
+    }
+
+    #[test]
+    fn test_network_number() {
+        let ip = "192.168.1.23".parse().unwrap();
+        assert_eq!(network_number(ip, 24), Ipv4Addr::new(192, 168, 1, 0));
+        assert_eq!(network_number(ip, 32), ip);
+        assert_eq!(network_number(ip, 0), Ipv4Addr::UNSPECIFIED);
+    }
+
+    #[test]
+    fn test_read_network_config_minimal() {
+        // no routers and no dns servers
+        let config = read_network_config(&env(&[
+            ("new_ip_address", "10.13.37.5"),
+            ("new_subnet_cidr", "16"),
+            ("new_domain_name_servers", ""),
+        ])).unwrap();
+
+        assert_eq!(config, NetworkConfig {
+            ip_address: "10.13.37.5".parse().unwrap(),
+            subnet_cidr: 16,
+            network_number: "10.13.0.0".parse().unwrap(),
+            routers: vec![],
+            dns_servers: vec![],
+            options: Box::new(LeaseOptions::default()),
+        });
+    }
+
+    #[test]
+    fn test_read_network_config_unrestricted() {
+        let config = read_network_config(&env(&[
+            ("new_ip_address", "10.13.37.5"),
+            ("new_subnet_cidr", "16"),
+            ("new_captive_portal", "urn:ietf:params:capport:unrestricted"),
+        ])).unwrap();
+        assert_eq!(config.options.captive_portal, None);
+    }
+
+    #[test]
+    fn test_read_network_config_missing_address() {
+        assert!(read_network_config(&env(&[
+            ("new_subnet_cidr", "24"),
+        ])).is_err());
+        assert!(read_network_config(&env(&[
+            ("new_ip_address", "192.0.2.23"),
+        ])).is_err());
+    }
+
+    #[test]
+    fn test_read_network_config() {
+        let config = read_network_config(&env(&[
+            ("new_ip_address", "192.168.1.23"),
+            ("new_subnet_cidr", "24"),
+            ("new_network_number", "192.168.1.0"),
+            ("new_routers", "192.168.1.1 192.168.1.254"),
+            ("new_domain_name_servers", "192.168.1.1 9.9.9.9"),
+            ("new_domain_name", "hotel.example"),
+            ("new_domain_search", "hotel.example guests.hotel.example"),
+            ("new_interface_mtu", "1400"),
+            ("new_dhcp_lease_time", "86400"),
+            ("new_dhcp_server_identifier", "192.168.1.1"),
+            ("new_broadcast_address", "192.168.1.255"),
+            ("new_classless_static_routes", "10.0.0.0/8 192.168.1.254 0.0.0.0/0 192.168.1.1"),
+            ("new_ntp_servers", "192.168.1.1 invalid"),
+            ("new_captive_portal", "https://portal.hotel.example/api"),
+            ("new_dhcp6_ia_na1_ia_addr1", "2001:db8::23"),
+            ("new_dhcp6_name_servers", "2001:db8::1"),
+            ("ra1_prefix", "2001:db8::/64"),
+            ("ra1_rdnss", "2001:db8::53"),
+        ])).unwrap();
+
+        assert_eq!(config, NetworkConfig {
+            ip_address: "192.168.1.23".parse().unwrap(),
+            subnet_cidr: 24,
+            network_number: "192.168.1.0".parse().unwrap(),
+            routers: vec![
+                "192.168.1.1".parse().unwrap(),
+                "192.168.1.254".parse().unwrap(),
+            ],
+            dns_servers: vec![
+                "192.168.1.1".parse().unwrap(),
+                "9.9.9.9".parse().unwrap(),
+            ],
+            options: Box::new(LeaseOptions {
+                domain_name: Some("hotel.example".into()),
+                domain_search: vec!["hotel.example".into(), "guests.hotel.example".into()],
+                mtu: Some(1400),
+                lease_time: Some(86400),
+                server_identifier: Some("192.168.1.1".parse().unwrap()),
+                broadcast_address: Some("192.168.1.255".parse().unwrap()),
+                classless_routes: vec![
+                    Route {
+                        destination: "10.0.0.0/8".parse().unwrap(),
+                        gateway: "192.168.1.254".parse().unwrap(),
+                    },
+                    Route {
+                        destination: "0.0.0.0/0".parse().unwrap(),
+                        gateway: "192.168.1.1".parse().unwrap(),
+                    },
+                ],
+                ntp_servers: vec!["192.168.1.1".parse().unwrap()],
+                captive_portal: Some("https://portal.hotel.example/api".into()),
+                ipv6: Some(Ipv6Config {
+                    addresses: vec!["2001:db8::23".parse().unwrap()],
+                    prefixes: vec!["2001:db8::/64".parse().unwrap()],
+                    dns_servers: vec![
+                        "2001:db8::1".parse().unwrap(),
+                        "2001:db8::53".parse().unwrap(),
+                    ],
+                    domain_search: vec![],
+                }),
+            }),
+        });
+    }
+
+    #[test]
+    fn test_fallback_config() {
+        let config = fallback_config(&StaticAddress {
+            address: "192.168.1.23".parse().unwrap(),
+            subnet_cidr: 24,
+            routers: vec!["192.168.1.1".parse().unwrap()],
+            dns: vec![],
+        });
+        assert_eq!(config, NetworkConfig {
+            ip_address: "192.168.1.23".parse().unwrap(),
+            subnet_cidr: 24,
+            network_number: "192.168.1.0".parse().unwrap(),
+            routers: vec!["192.168.1.1".parse().unwrap()],
+            dns_servers: vec![],
+            options: Box::default(),
+        });
+    }
+
+    #[test]
+    fn test_dhcpcd_conf_default() {
+        let conf = dhcpcd_conf(&Config::default());
+        let lines = conf.lines().collect::<Vec<_>>();
+
+        assert!(lines.contains(&"clientid"));
+        assert!(lines.contains(&"slaac private"));
+        assert!(lines.contains(&"option domain_name_servers, domain_name, domain_search, classless_static_routes, interface_mtu, captive_portal"));
+        assert!(!lines.contains(&"hostname"));
+        assert!(!lines.contains(&"duid"));
+        assert!(!conf.contains("ssid"));
+        assert!(!conf.contains("fallback"));
+    }
+
+    #[test]
+    fn test_dhcpcd_conf_networks() {
+        let config = ::config::load(r#"
+        [dhcp]
+        client = "dhcpcd"
+        client_id = "duid"
+        ipv6 = false
+        options = ["domain_name_servers"]
+
+        [dhcp.fallback]
+        address = "169.254.0.2"
+        subnet_cidr = 16
+
+        [networks."Hotel"]
+        disable_decap = true
+
+        [networks."Home \"5G\""]
+        [networks."Home \"5G\"".dhcp]
+        send_hostname = true
+        client_id = "hwaddr"
+        ipv6 = true
+        options = ["ntp_servers"]
+
+        [networks."Home \"5G\"".dhcp.fallback]
+        address = "192.168.1.23"
+        routers = ["192.168.1.1"]
+        dns = ["192.168.1.1"]
+        "#).unwrap();
+
+        assert_eq!(dhcpcd_conf(&config), r#"# generated by snaild from snail.conf, see dhcpcd.conf(5)
+
+option rapid_commit
+require dhcp_server_identifier
+noipv4ll
+duid
+noipv6
+option domain_name_servers
+fallback snail_fallback
+
+ssid "Home \"5G\""
+hostname
+clientid
+ipv6
+slaac private
+option ntp_servers
+fallback snail_fallback_0
+
+profile snail_fallback
+static ip_address=169.254.0.2/16
+
+profile snail_fallback_0
+static ip_address=192.168.1.23/24
+static routers=192.168.1.1
+static domain_name_servers=192.168.1.1
+"#);
+    }
+}
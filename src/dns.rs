@@ -1,12 +1,20 @@
+use config::{DnsProtocol, DnsServer};
 use errors::Result;
-use std::time::Duration;
-use std::net::IpAddr;
+use structs::LuaMap;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
 use futures::Future;
 use futures::Poll;
+use hlua::AnyLuaValue;
 use tokio_core::reactor;
+use trust_dns_proto::rr::{Name, Record, RecordType, RData};
 use trust_dns_resolver as tdr;
+use trust_dns_resolver::error::ResolveErrorKind;
 use trust_dns_resolver::lookup_ip::LookupIp;
 use trust_dns_resolver::config::{ResolverConfig,
                                  ResolverOpts,
@@ -18,8 +26,187 @@ use std::io;
 use std::net::SocketAddr;
 
 
+/// The number of responses that are cached by default
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+/// How long a missing record is cached if the response didn't include a SOA
+const NEGATIVE_TTL: Duration = Duration::from_secs(30);
+/// Responses are never cached longer than this
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+/// Lowercase a name and remove the trailing dot
+pub fn normalize_name(name: &str) -> String {
+    name.trim_end_matches('.').to_lowercase()
+}
+
+/// Check if a normalized name is inside of a normalized zone
+pub fn in_zone(name: &str, zone: &str) -> bool {
+    zone.is_empty() || name == zone || name.ends_with(&format!(".{}", zone))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum CacheKey {
+    Ip(String),
+    Lookup(String, RecordType),
+}
+
+/// An empty answer means the name doesn't exist or doesn't have records of
+/// this type
+#[derive(Debug, Clone)]
+enum CacheValue {
+    Ips(Vec<IpAddr>),
+    Records(Vec<Record>),
+}
+
+impl CacheValue {
+    fn is_empty(&self) -> bool {
+        match self {
+            CacheValue::Ips(ips) => ips.is_empty(),
+            CacheValue::Records(records) => records.is_empty(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    value: CacheValue,
+    expires: Instant,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub capacity: usize,
+    pub hits: u64,
+    pub negative_hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub flushes: u64,
+}
+
+#[derive(Debug)]
+struct CacheState {
+    entries: HashMap<CacheKey, CacheEntry>,
+    stats: CacheStats,
+}
+
+/// A cache for dns responses that can be shared between resolvers. Entries
+/// expire according to their ttl, missing records are cached as well.
+#[derive(Debug, Clone)]
+pub struct DnsCache {
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl DnsCache {
+    pub fn new(capacity: usize) -> DnsCache {
+        DnsCache {
+            state: Arc::new(Mutex::new(CacheState {
+                entries: HashMap::new(),
+                stats: CacheStats {
+                    capacity,
+                    ..Default::default()
+                },
+            })),
+        }
+    }
+
+    fn get(&self, key: &CacheKey, now: Instant) -> Option<CacheValue> {
+        let mut state = self.state.lock().unwrap();
+
+        let expired = match state.entries.get(key) {
+            Some(entry) => entry.expires <= now,
+            None => {
+                state.stats.misses += 1;
+                return None;
+            },
+        };
+
+        if expired {
+            state.entries.remove(key);
+            state.stats.entries = state.entries.len();
+            state.stats.misses += 1;
+            return None;
+        }
+
+        let entry = &state.entries[key];
+        let ttl = (entry.expires - now).as_secs() as u32;
+        let value = match &entry.value {
+            CacheValue::Ips(ips) => CacheValue::Ips(ips.clone()),
+            CacheValue::Records(records) => CacheValue::Records(records.iter()
+                .cloned()
+                .map(|mut record| {
+                    record.set_ttl(ttl);
+                    record
+                })
+                .collect()),
+        };
+
+        if value.is_empty() {
+            state.stats.negative_hits += 1;
+        } else {
+            state.stats.hits += 1;
+        }
+
+        Some(value)
+    }
+
+    fn insert(&self, key: CacheKey, value: CacheValue, valid_until: Instant, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.stats.capacity == 0 || valid_until <= now {
+            return;
+        }
+
+        if !state.entries.contains_key(&key) && state.entries.len() >= state.stats.capacity {
+            state.entries.retain(|_, entry| entry.expires > now);
+        }
+
+        while !state.entries.contains_key(&key) && state.entries.len() >= state.stats.capacity {
+            let oldest = state.entries.iter()
+                .min_by_key(|(_, entry)| entry.expires)
+                .map(|(key, _)| key.clone());
+
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+                state.stats.evictions += 1;
+            }
+        }
+
+        let expires = if valid_until - now > MAX_TTL {
+            now + MAX_TTL
+        } else {
+            valid_until
+        };
+
+        state.entries.insert(key, CacheEntry {
+            value,
+            expires,
+        });
+        state.stats.entries = state.entries.len();
+    }
+
+    /// Remove all entries, this is done when the network changes
+    pub fn flush(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.entries.clear();
+        state.stats.entries = 0;
+        state.stats.flushes += 1;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        state.stats.clone()
+    }
+}
+
+impl Default for DnsCache {
+    fn default() -> DnsCache {
+        DnsCache::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
 pub struct Resolver {
     resolver: tdr::Resolver,
+    cache: Option<DnsCache>,
 }
 
 impl Resolver {
@@ -47,6 +234,7 @@ impl Resolver {
 
         Ok(Resolver {
             resolver,
+            cache: None,
         })
     }
 
@@ -57,6 +245,42 @@ impl Resolver {
         Resolver::with_udp_addr(&recursors)
     }
 
+    /// Use a list of upstreams, each with its own protocol
+    pub fn with_servers(servers: &[DnsServer]) -> Result<Resolver> {
+        if servers.is_empty() {
+            bail!("no dns servers configured");
+        }
+
+        let mut config = ResolverConfig::new();
+
+        for server in servers {
+            if server.protocol.is_encrypted() && server.tls_name.is_none() {
+                bail!("dns server {} needs a tls name for {:?}", server.ip, server.protocol);
+            }
+
+            config.add_name_server(NameServerConfig {
+                socket_addr: server.socket_addr(),
+                protocol: match server.protocol {
+                    DnsProtocol::Udp => Protocol::Udp,
+                    DnsProtocol::Tcp => Protocol::Tcp,
+                    DnsProtocol::Tls => Protocol::Tls,
+                    DnsProtocol::Https => Protocol::Https,
+                },
+                tls_dns_name: server.tls_name.clone(),
+            });
+        }
+
+        let mut opts = ResolverOpts::default();
+        opts.use_hosts_file = false;
+
+        let resolver = tdr::Resolver::new(config, opts)?;
+
+        Ok(Resolver {
+            resolver,
+            cache: None,
+        })
+    }
+
     pub fn with_https(servers: &[IpAddr], port: u16, sni: String) -> Result<Resolver> {
         let name_servers = NameServerConfigGroup::from_ips_https(
             servers,
@@ -77,9 +301,53 @@ impl Resolver {
 
         Ok(Resolver {
             resolver,
+            cache: None,
         })
     }
 
+    /// Use a cache that might be shared with other resolvers
+    pub fn with_cache(mut self, cache: DnsCache) -> Resolver {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<CacheValue> {
+        self.cache.as_ref()
+            .and_then(|cache| cache.get(key, Instant::now()))
+    }
+
+    fn cache(&self, key: CacheKey, value: CacheValue, valid_until: Option<Instant>) {
+        if let Some(cache) = &self.cache {
+            let now = Instant::now();
+            let valid_until = valid_until.unwrap_or_else(|| now + NEGATIVE_TTL);
+            cache.insert(key, value, valid_until, now);
+        }
+    }
+
+    /// Lookup the records of a specific type, if the name doesn't have any
+    /// records of this type an empty list is returned
+    pub fn lookup(&self, name: &str, record_type: RecordType) -> Result<Vec<Record>> {
+        let key = CacheKey::Lookup(normalize_name(name), record_type);
+        if let Some(CacheValue::Records(records)) = self.cached(&key) {
+            return Ok(records);
+        }
+
+        match self.resolver.lookup(name, record_type) {
+            Ok(lookup) => {
+                let records = lookup.record_iter().cloned().collect::<Vec<_>>();
+                self.cache(key, CacheValue::Records(records.clone()), Some(lookup.valid_until()));
+                Ok(records)
+            },
+            Err(err) => match err.kind() {
+                ResolveErrorKind::NoRecordsFound { valid_until, .. } => {
+                    self.cache(key, CacheValue::Records(Vec::new()), *valid_until);
+                    Ok(Vec::new())
+                },
+                _ => bail!("resolve error: {}", err),
+            },
+        }
+    }
+
     #[inline]
     pub fn transform(lookup: LookupIp) -> Vec<IpAddr> {
         lookup.iter().collect()
@@ -92,17 +360,221 @@ impl fmt::Debug for Resolver {
     }
 }
 
+/// The record types that can be queried with `DnsResolver::query`
+pub const RECORD_TYPES: &[RecordType] = &[
+    RecordType::A,
+    RecordType::AAAA,
+    RecordType::CNAME,
+    RecordType::MX,
+    RecordType::NS,
+    RecordType::PTR,
+    RecordType::SOA,
+    RecordType::SRV,
+    RecordType::TXT,
+];
+
+/// Parse a record type like "txt" or "SRV", unsupported types are rejected
+pub fn parse_record_type(s: &str) -> Result<RecordType> {
+    let record_type = RecordType::from_str(&s.to_uppercase())
+        .map_err(|_| format_err!("unknown record type: {:?}", s))?;
+
+    if !RECORD_TYPES.contains(&record_type) {
+        bail!("unsupported record type: {:?}", s);
+    }
+
+    Ok(record_type)
+}
+
+/// PTR queries for an ip address are sent to the reverse name of the address
+pub fn query_name(name: &str, record_type: RecordType) -> String {
+    match (record_type, name.parse::<IpAddr>()) {
+        (RecordType::PTR, Ok(ip)) => Name::from(ip).to_ascii(),
+        _ => name.to_string(),
+    }
+}
+
+/// A record from a typed lookup
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum DnsRecord {
+    A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    CNAME(String),
+    MX {
+        preference: u16,
+        exchange: String,
+    },
+    NS(String),
+    PTR(String),
+    SOA {
+        mname: String,
+        rname: String,
+        serial: u32,
+        refresh: i32,
+        retry: i32,
+        expire: i32,
+        minimum: u32,
+    },
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    /// the character strings of the record are concatenated
+    TXT(String),
+}
+
+impl DnsRecord {
+    pub fn from_rdata(rdata: &RData) -> Option<DnsRecord> {
+        let record = match rdata {
+            RData::A(ip) => DnsRecord::A(*ip),
+            RData::AAAA(ip) => DnsRecord::AAAA(*ip),
+            RData::CNAME(name) => DnsRecord::CNAME(name.to_ascii()),
+            RData::MX(mx) => DnsRecord::MX {
+                preference: mx.preference(),
+                exchange: mx.exchange().to_ascii(),
+            },
+            RData::NS(name) => DnsRecord::NS(name.to_ascii()),
+            RData::PTR(name) => DnsRecord::PTR(name.to_ascii()),
+            RData::SOA(soa) => DnsRecord::SOA {
+                mname: soa.mname().to_ascii(),
+                rname: soa.rname().to_ascii(),
+                serial: soa.serial(),
+                refresh: soa.refresh(),
+                retry: soa.retry(),
+                expire: soa.expire(),
+                minimum: soa.minimum(),
+            },
+            RData::SRV(srv) => DnsRecord::SRV {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().to_ascii(),
+            },
+            RData::TXT(txt) => DnsRecord::TXT(txt.iter()
+                .map(|x| String::from_utf8_lossy(x))
+                .collect()),
+            _ => return None,
+        };
+        Some(record)
+    }
+
+    pub fn record_type(&self) -> RecordType {
+        match self {
+            DnsRecord::A(_) => RecordType::A,
+            DnsRecord::AAAA(_) => RecordType::AAAA,
+            DnsRecord::CNAME(_) => RecordType::CNAME,
+            DnsRecord::MX { .. } => RecordType::MX,
+            DnsRecord::NS(_) => RecordType::NS,
+            DnsRecord::PTR(_) => RecordType::PTR,
+            DnsRecord::SOA { .. } => RecordType::SOA,
+            DnsRecord::SRV { .. } => RecordType::SRV,
+            DnsRecord::TXT(_) => RecordType::TXT,
+        }
+    }
+}
+
+/// Formats the record data like in a zone file
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsRecord::A(ip) => write!(f, "{}", ip),
+            DnsRecord::AAAA(ip) => write!(f, "{}", ip),
+            DnsRecord::CNAME(name) => write!(f, "{}", name),
+            DnsRecord::MX { preference, exchange } => write!(f, "{} {}", preference, exchange),
+            DnsRecord::NS(name) => write!(f, "{}", name),
+            DnsRecord::PTR(name) => write!(f, "{}", name),
+            DnsRecord::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+                write!(f, "{} {} {} {} {} {} {}", mname, rname, serial, refresh, retry, expire, minimum)
+            },
+            DnsRecord::SRV { priority, weight, port, target } => write!(f, "{} {} {} {}", priority, weight, port, target),
+            DnsRecord::TXT(text) => write!(f, "{:?}", text),
+        }
+    }
+}
+
+impl Into<AnyLuaValue> for DnsRecord {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        map.insert_str("type", self.record_type().to_string());
+        map.insert_str("value", self.to_string());
+
+        match self {
+            DnsRecord::A(ip) => map.insert_str("ip", ip.to_string()),
+            DnsRecord::AAAA(ip) => map.insert_str("ip", ip.to_string()),
+            DnsRecord::CNAME(name) | DnsRecord::NS(name) | DnsRecord::PTR(name) => map.insert_str("name", name),
+            DnsRecord::MX { preference, exchange } => {
+                map.insert_num("preference", f64::from(preference));
+                map.insert_str("exchange", exchange);
+            },
+            DnsRecord::SOA { mname, rname, serial, refresh, retry, expire, minimum } => {
+                map.insert_str("mname", mname);
+                map.insert_str("rname", rname);
+                map.insert_num("serial", f64::from(serial));
+                map.insert_num("refresh", f64::from(refresh));
+                map.insert_num("retry", f64::from(retry));
+                map.insert_num("expire", f64::from(expire));
+                map.insert_num("minimum", f64::from(minimum));
+            },
+            DnsRecord::SRV { priority, weight, port, target } => {
+                map.insert_num("priority", f64::from(priority));
+                map.insert_num("weight", f64::from(weight));
+                map.insert_num("port", f64::from(port));
+                map.insert_str("target", target);
+            },
+            DnsRecord::TXT(text) => map.insert_str("text", text),
+        }
+
+        map.into()
+    }
+}
+
+/// Convert records of a lookup to typed records, records of other types like
+/// the CNAMEs that were followed are skipped
+pub fn typed_records(records: &[Record], record_type: RecordType) -> Vec<DnsRecord> {
+    records.iter()
+        .filter(|record| record.rr_type() == record_type)
+        .filter_map(|record| DnsRecord::from_rdata(record.rdata()))
+        .collect()
+}
+
 pub trait DnsResolver {
     fn resolve(&self, name: &str) -> Result<Vec<IpAddr>>;
+
+    /// Lookup the records of a specific type
+    fn query(&self, name: &str, record_type: RecordType) -> Result<Vec<DnsRecord>>;
 }
 
 impl DnsResolver for Resolver {
     fn resolve(&self, name: &str) -> Result<Vec<IpAddr>> {
-        let response = match self.resolver.lookup_ip(name) {
-            Ok(response) => Resolver::transform(response),
-            Err(err) => bail!("resolve error: {}", err),
-        };
-        Ok(response)
+        let key = CacheKey::Ip(normalize_name(name));
+        match self.cached(&key) {
+            Some(CacheValue::Ips(ref ips)) if ips.is_empty() => bail!("resolve error: no records found for {:?} (cached)", name),
+            Some(CacheValue::Ips(ips)) => return Ok(ips),
+            _ => (),
+        }
+
+        match self.resolver.lookup_ip(name) {
+            Ok(response) => {
+                let valid_until = response.valid_until();
+                let ips = Resolver::transform(response);
+                self.cache(key, CacheValue::Ips(ips.clone()), Some(valid_until));
+                Ok(ips)
+            },
+            Err(err) => {
+                if let ResolveErrorKind::NoRecordsFound { valid_until, .. } = err.kind() {
+                    self.cache(key, CacheValue::Ips(Vec::new()), *valid_until);
+                }
+                bail!("resolve error: {}", err)
+            },
+        }
+    }
+
+    fn query(&self, name: &str, record_type: RecordType) -> Result<Vec<DnsRecord>> {
+        let name = query_name(name, record_type);
+        let records = self.lookup(&name, record_type)?;
+        Ok(typed_records(&records, record_type))
     }
 }
 
@@ -176,3 +648,159 @@ impl Future for Resolving {
         self.0.poll()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use trust_dns_proto::rr::rdata;
+
+    fn ips(ips: &[&str]) -> CacheValue {
+        CacheValue::Ips(ips.iter().map(|x| x.parse().unwrap()).collect())
+    }
+
+    fn key(name: &str) -> CacheKey {
+        CacheKey::Ip(name.to_string())
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+
+        cache.insert(key("example.com"), ips(&["192.0.2.1"]), now + Duration::from_secs(60), now);
+        assert!(cache.get(&key("example.com"), now + Duration::from_secs(59)).is_some());
+        assert!(cache.get(&key("example.com"), now + Duration::from_secs(60)).is_none());
+        assert!(cache.get(&key("example.com"), now).is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.entries, 0);
+    }
+
+    #[test]
+    fn test_cache_max_ttl() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+
+        cache.insert(key("example.com"), ips(&["192.0.2.1"]), now + MAX_TTL * 24, now);
+        assert!(cache.get(&key("example.com"), now + MAX_TTL).is_none());
+    }
+
+    #[test]
+    fn test_cache_negative() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+
+        cache.insert(key("nx.example.com"), ips(&[]), now + NEGATIVE_TTL, now);
+        match cache.get(&key("nx.example.com"), now) {
+            Some(CacheValue::Ips(ips)) => assert!(ips.is_empty()),
+            x => panic!("unexpected cache value: {:?}", x),
+        }
+        assert_eq!(cache.stats().negative_hits, 1);
+    }
+
+    #[test]
+    fn test_cache_record_ttl() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+        let name = "example.com.".parse().unwrap();
+        let record = Record::from_rdata(name, 300, RData::A("192.0.2.1".parse().unwrap()));
+        let key = CacheKey::Lookup("example.com".into(), RecordType::A);
+
+        cache.insert(key.clone(), CacheValue::Records(vec![record]), now + Duration::from_secs(300), now);
+        match cache.get(&key, now + Duration::from_secs(100)) {
+            Some(CacheValue::Records(records)) => assert_eq!(records[0].ttl(), 200),
+            x => panic!("unexpected cache value: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_cache_size_limit() {
+        let cache = DnsCache::new(2);
+        let now = Instant::now();
+
+        cache.insert(key("a.example.com"), ips(&["192.0.2.1"]), now + Duration::from_secs(10), now);
+        cache.insert(key("b.example.com"), ips(&["192.0.2.2"]), now + Duration::from_secs(30), now);
+        cache.insert(key("c.example.com"), ips(&["192.0.2.3"]), now + Duration::from_secs(20), now);
+
+        // the entry that expires first is evicted
+        assert!(cache.get(&key("a.example.com"), now).is_none());
+        assert!(cache.get(&key("b.example.com"), now).is_some());
+        assert!(cache.get(&key("c.example.com"), now).is_some());
+
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 2);
+        assert_eq!(stats.evictions, 1);
+    }
+
+    #[test]
+    fn test_cache_disabled() {
+        let cache = DnsCache::new(0);
+        let now = Instant::now();
+        cache.insert(key("example.com"), ips(&["192.0.2.1"]), now + Duration::from_secs(60), now);
+        assert!(cache.get(&key("example.com"), now).is_none());
+    }
+
+    #[test]
+    fn test_cache_flush() {
+        let cache = DnsCache::new(16);
+        let now = Instant::now();
+
+        cache.insert(key("example.com"), ips(&["192.0.2.1"]), now + Duration::from_secs(60), now);
+        let other = cache.clone();
+        other.flush();
+
+        assert!(cache.get(&key("example.com"), now).is_none());
+        let stats = cache.stats();
+        assert_eq!(stats.entries, 0);
+        assert_eq!(stats.flushes, 1);
+    }
+
+    #[test]
+    fn test_parse_record_type() {
+        assert_eq!(parse_record_type("A").unwrap(), RecordType::A);
+        assert_eq!(parse_record_type("txt").unwrap(), RecordType::TXT);
+        assert_eq!(parse_record_type("Srv").unwrap(), RecordType::SRV);
+        assert!(parse_record_type("AXFR").is_err());
+        assert!(parse_record_type("foo").is_err());
+    }
+
+    #[test]
+    fn test_query_name() {
+        assert_eq!(query_name("192.0.2.1", RecordType::PTR), "1.2.0.192.in-addr.arpa.");
+        assert_eq!(query_name("1.2.0.192.in-addr.arpa.", RecordType::PTR), "1.2.0.192.in-addr.arpa.");
+        assert_eq!(query_name("192.0.2.1", RecordType::A), "192.0.2.1");
+    }
+
+    #[test]
+    fn test_typed_records() {
+        let name = Name::from_str("www.example.com.").unwrap();
+        let target = Name::from_str("example.com.").unwrap();
+        let records = vec![
+            Record::from_rdata(name, 300, RData::CNAME(target.clone())),
+            Record::from_rdata(target, 300, RData::A("192.0.2.1".parse().unwrap())),
+        ];
+
+        assert_eq!(typed_records(&records, RecordType::A), vec![
+            DnsRecord::A("192.0.2.1".parse().unwrap()),
+        ]);
+        assert_eq!(typed_records(&records, RecordType::CNAME), vec![
+            DnsRecord::CNAME("example.com.".into()),
+        ]);
+    }
+
+    #[test]
+    fn test_dns_record_display() {
+        let txt = RData::TXT(rdata::TXT::new(vec!["v=spf1 ".into(), "-all".into()]));
+        let txt = DnsRecord::from_rdata(&txt).unwrap();
+        assert_eq!(txt, DnsRecord::TXT("v=spf1 -all".into()));
+        assert_eq!(txt.to_string(), "\"v=spf1 -all\"");
+
+        let srv = RData::SRV(rdata::SRV::new(10, 100, 389, Name::from_str("ldap.example.com.").unwrap()));
+        assert_eq!(DnsRecord::from_rdata(&srv).unwrap().to_string(), "10 100 389 ldap.example.com.");
+
+        let mx = RData::MX(rdata::MX::new(10, Name::from_str("mail.example.com.").unwrap()));
+        assert_eq!(DnsRecord::from_rdata(&mx).unwrap().to_string(), "10 mail.example.com.");
+    }
+}
@@ -0,0 +1,268 @@
+use config::{Config, DnsCheckConfig};
+use dns::{DnsResolver, Resolver};
+use structs::{LuaMap, LuaList};
+
+use hlua::AnyLuaValue;
+use trust_dns_proto::rr::RecordType;
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+
+
+/// The name that is sent to the intercept address, the answer doesn't matter
+const INTERCEPT_QUERY: &str = "example.com";
+
+/// A name that resolved to different addresses than expected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DnsMismatch {
+    pub name: String,
+    pub expected: Vec<IpAddr>,
+    pub received: Vec<IpAddr>,
+}
+
+impl Into<AnyLuaValue> for DnsMismatch {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        map.insert_str("name", self.name);
+        map.insert("expected", LuaList::from(self.expected.iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()));
+        map.insert("received", LuaList::from(self.received.iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()));
+
+        map.into()
+    }
+}
+
+/// Compares the answers of the dhcp recursors with answers we trust
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DnsCheck {
+    /// None if none of the names could be compared
+    pub hijacked: Option<bool>,
+    /// None if the test couldn't be run
+    pub intercepted: Option<bool>,
+    /// the names that have been compared
+    pub checked: Vec<String>,
+    pub mismatches: Vec<DnsMismatch>,
+}
+
+impl Into<AnyLuaValue> for DnsCheck {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        if let Some(hijacked) = self.hijacked {
+            map.insert("hijacked", AnyLuaValue::LuaBoolean(hijacked));
+        }
+        if let Some(intercepted) = self.intercepted {
+            map.insert("intercepted", AnyLuaValue::LuaBoolean(intercepted));
+        }
+
+        let mut mismatches = LuaList::new();
+        for mismatch in self.mismatches {
+            mismatches.push(mismatch);
+        }
+        map.insert("mismatches", mismatches);
+
+        map.into()
+    }
+}
+
+/// Answers are allowed to differ because of load balancing, they only
+/// mismatch if they don't have any address in common
+pub fn compare(name: &str, expected: &[IpAddr], received: &[IpAddr]) -> Option<DnsMismatch> {
+    if received.iter().any(|ip| expected.contains(ip)) {
+        None
+    } else {
+        Some(DnsMismatch {
+            name: name.to_string(),
+            expected: expected.to_vec(),
+            received: received.to_vec(),
+        })
+    }
+}
+
+/// Resolve the canaries and the names from the config with the dhcp
+/// recursors and test if an address without a dns server answers queries.
+pub fn check<L, T, O>(config: &DnsCheckConfig, local: &L, trusted: Option<&T>, outside: Option<&O>) -> DnsCheck
+    where L: DnsResolver, T: DnsResolver, O: DnsResolver
+{
+    let mut expected = BTreeMap::new();
+
+    for (name, records) in &config.canaries {
+        expected.insert(name.to_string(), records.clone());
+    }
+
+    if let Some(trusted) = trusted {
+        for name in &config.compare {
+            match trusted.resolve(name) {
+                Ok(records) => {
+                    expected.insert(name.to_string(), records);
+                },
+                Err(err) => debug!("failed to resolve {:?} with trusted resolver: {}", name, err),
+            }
+        }
+    }
+
+    let mut result = DnsCheck::default();
+
+    for (name, expected) in &expected {
+        let received = match local.resolve(name) {
+            Ok(received) => received,
+            Err(err) => {
+                debug!("failed to resolve {:?} with dhcp recursors: {}", name, err);
+                continue;
+            },
+        };
+
+        result.checked.push(name.to_string());
+        if let Some(mismatch) = compare(name, expected, &received) {
+            info!("dns answer for {:?} has been modified: {:?}", name, mismatch);
+            result.mismatches.push(mismatch);
+        }
+    }
+
+    if !result.checked.is_empty() {
+        result.hijacked = Some(!result.mismatches.is_empty());
+    }
+
+    if let Some(outside) = outside {
+        // any answer, even an empty one, means somebody intercepted the query
+        result.intercepted = Some(outside.query(INTERCEPT_QUERY, RecordType::A).is_ok());
+    }
+
+    result
+}
+
+/// Run the check with the dhcp recursors of the network
+pub fn run(config: &Config, recursors: &[IpAddr]) -> DnsCheck {
+    let check_config = &config.decap.dns_check;
+
+    let local = match Resolver::with_udp(recursors) {
+        Ok(local) => local,
+        Err(err) => {
+            warn!("failed to setup resolver for dns check: {}", err);
+            return DnsCheck::default();
+        },
+    };
+
+    let trusted = match &config.dns {
+        Some(dns) if !check_config.compare.is_empty() => Resolver::with_servers(&dns.upstreams())
+            .map_err(|err| warn!("failed to setup trusted resolver for dns check: {}", err))
+            .ok(),
+        _ => None,
+    };
+
+    let outside = Resolver::with_udp_addr(&[check_config.intercept_addr])
+        .map_err(|err| warn!("failed to setup resolver for intercept check: {}", err))
+        .ok();
+
+    let result = check(check_config, &local, trusted.as_ref(), outside.as_ref());
+    info!("dns check: {:?}", result);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dns::DnsRecord;
+    use errors::Result;
+    use std::collections::HashMap;
+
+    struct Static(HashMap<String, Vec<IpAddr>>);
+
+    impl Static {
+        fn new(records: &[(&str, &str)]) -> Static {
+            let mut map = HashMap::new();
+            for (name, ip) in records {
+                map.entry((*name).to_string())
+                    .or_insert_with(Vec::new)
+                    .push(ip.parse().unwrap());
+            }
+            Static(map)
+        }
+    }
+
+    impl DnsResolver for Static {
+        fn resolve(&self, name: &str) -> Result<Vec<IpAddr>> {
+            match self.0.get(name) {
+                Some(ips) => Ok(ips.clone()),
+                None => bail!("resolve error: timeout"),
+            }
+        }
+
+        fn query(&self, name: &str, _record_type: RecordType) -> Result<Vec<DnsRecord>> {
+            self.resolve(name)?;
+            Ok(Vec::new())
+        }
+    }
+
+    fn config() -> DnsCheckConfig {
+        let mut canaries = HashMap::new();
+        canaries.insert("canary.example.com".to_string(), vec![
+            "192.0.2.1".parse().unwrap(),
+            "192.0.2.2".parse().unwrap(),
+        ]);
+
+        DnsCheckConfig {
+            canaries,
+            compare: vec!["example.com".to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_clean() {
+        let local = Static::new(&[("canary.example.com", "192.0.2.2"), ("example.com", "198.51.100.1")]);
+        let trusted = Static::new(&[("example.com", "198.51.100.1"), ("example.com", "198.51.100.2")]);
+
+        let result = check(&config(), &local, Some(&trusted), None::<&Static>);
+        assert_eq!(result, DnsCheck {
+            hijacked: Some(false),
+            intercepted: None,
+            checked: vec!["canary.example.com".into(), "example.com".into()],
+            mismatches: vec![],
+        });
+    }
+
+    #[test]
+    fn test_check_hijacked() {
+        let local = Static::new(&[("canary.example.com", "10.0.0.1"), ("example.com", "10.0.0.1")]);
+        let trusted = Static::new(&[("example.com", "198.51.100.1")]);
+        let outside = Static::new(&[("example.com", "10.0.0.1")]);
+
+        let result = check(&config(), &local, Some(&trusted), Some(&outside));
+        assert_eq!(result.hijacked, Some(true));
+        assert_eq!(result.intercepted, Some(true));
+        assert_eq!(result.mismatches, vec![
+            DnsMismatch {
+                name: "canary.example.com".into(),
+                expected: vec!["192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap()],
+                received: vec!["10.0.0.1".parse().unwrap()],
+            },
+            DnsMismatch {
+                name: "example.com".into(),
+                expected: vec!["198.51.100.1".parse().unwrap()],
+                received: vec!["10.0.0.1".parse().unwrap()],
+            },
+        ]);
+    }
+
+    #[test]
+    fn test_check_unreachable() {
+        // the trusted resolver is blocked by the portal and the dhcp
+        // recursors don't answer, the intercept address doesn't answer either
+        let local = Static::new(&[]);
+        let trusted = Static::new(&[]);
+        let outside = Static::new(&[]);
+
+        let result = check(&config(), &local, Some(&trusted), Some(&outside));
+        assert_eq!(result, DnsCheck {
+            hijacked: None,
+            intercepted: Some(false),
+            checked: vec![],
+            mismatches: vec![],
+        });
+    }
+}
@@ -0,0 +1,628 @@
+use config::DnsConfig;
+use dns::{self, CacheStats, DnsCache, DnsRecord, Resolver, DnsResolver};
+use errors::Result;
+use wifi::NetworkStatus;
+
+use trust_dns_proto::op::{Message, MessageType, OpCode, Query, ResponseCode};
+use trust_dns_proto::rr::{Name, Record, RecordType, RData};
+use trust_dns_proto::serialize::binary::{BinEncodable, BinEncoder};
+
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::net::{IpAddr, SocketAddr, UdpSocket, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+
+/// Queries from tcp clients that don't send anything are dropped after this
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Every tcp connection is served by its own thread, further connections
+/// are closed right away
+const MAX_TCP_CLIENTS: usize = 32;
+/// The ttl of records from the [dns.records] section
+const STATIC_TTL: u32 = 60;
+/// How often the dns server asks snaild for the status of the network
+pub const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Something that can answer dns queries for the server
+pub trait Upstream: Send + Sync {
+    fn lookup(&self, query: &Query) -> Result<Vec<Record>>;
+}
+
+impl Upstream for Resolver {
+    fn lookup(&self, query: &Query) -> Result<Vec<Record>> {
+        let name = query.name().to_ascii();
+        Resolver::lookup(self, &name, query.query_type())
+    }
+}
+
+/// Tracks the captive portal of the current network. While decap reports that
+/// we don't have an uplink the dhcp recursors are used, once decap succeeded
+/// they are never used again until the network changes.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PortalState {
+    generation: Option<u64>,
+    online: bool,
+    recursors: Vec<IpAddr>,
+    hosts: Vec<String>,
+}
+
+impl PortalState {
+    pub fn update(&mut self, status: Option<&NetworkStatus>) {
+        let status = match status {
+            Some(status) => status,
+            None => {
+                *self = PortalState::default();
+                return;
+            },
+        };
+
+        if self.generation != Some(status.generation) {
+            *self = PortalState {
+                generation: Some(status.generation),
+                ..Default::default()
+            };
+        }
+
+        if status.has_uplink == Some(true) {
+            self.online = true;
+        }
+
+        if !self.online && status.has_uplink == Some(false) {
+            self.recursors = status.dns.clone();
+            self.hosts = status.decap_report.as_ref()
+                .and_then(|report| report.fingerprint.as_ref())
+                .map(|fingerprint| fingerprint.hosts())
+                .unwrap_or_default();
+        } else {
+            self.recursors.clear();
+            self.hosts.clear();
+        }
+    }
+
+    /// The dhcp recursors, if they are currently in use
+    pub fn recursors(&self) -> Option<&[IpAddr]> {
+        if self.recursors.is_empty() {
+            None
+        } else {
+            Some(&self.recursors)
+        }
+    }
+
+    /// Check if a name should be sent to the dhcp recursors
+    pub fn use_dhcp(&self, name: &str, portal_domains_only: bool) -> bool {
+        if self.recursors.is_empty() {
+            return false;
+        }
+
+        if !portal_domains_only {
+            return true;
+        }
+
+        let name = dns::normalize_name(name);
+        self.hosts.iter().any(|host| dns::in_zone(&name, host))
+    }
+}
+
+#[derive(Default)]
+struct Portal {
+    state: PortalState,
+    resolver: Option<Arc<Resolver>>,
+}
+
+/// Resolves names according to the [dns] section. Static records are
+/// answered directly, names inside of a zone are sent to the recursors of the
+/// zone with the longest matching suffix. While a captive portal is active
+/// the remaining names (or only the names of the portal) are sent to the dhcp
+/// recursors, everything else is sent to the configured upstreams.
+pub struct Forwarder {
+    config: DnsConfig,
+    zones: HashMap<String, Arc<Resolver>>,
+    upstream: Arc<Resolver>,
+    portal: Mutex<Portal>,
+    cache: DnsCache,
+}
+
+impl Forwarder {
+    pub fn new(config: &DnsConfig) -> Result<Forwarder> {
+        let cache = DnsCache::new(config.cache_size);
+
+        let upstream = Resolver::with_servers(&config.upstreams())?
+                        .with_cache(cache.clone());
+
+        let mut zones = HashMap::new();
+        for (zone, recursors) in &config.zones {
+            let resolver = Resolver::with_udp(recursors)?
+                            .with_cache(cache.clone());
+            zones.insert(zone.to_string(), Arc::new(resolver));
+        }
+
+        Ok(Forwarder {
+            config: config.clone(),
+            zones,
+            upstream: Arc::new(upstream),
+            portal: Mutex::new(Portal::default()),
+            cache,
+        })
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Switch between the dhcp and the configured upstreams based on the
+    /// status of the network
+    pub fn update(&self, status: Option<&NetworkStatus>) -> Result<()> {
+        let mut portal = self.portal.lock().unwrap();
+
+        let mut state = portal.state.clone();
+        state.update(status);
+        if state == portal.state {
+            return Ok(());
+        }
+
+        // responses from a different network or from the portal can't be reused
+        if state.generation != portal.state.generation || state.recursors != portal.state.recursors {
+            debug!("flushing dns cache");
+            self.cache.flush();
+        }
+
+        let resolver = match state.recursors() {
+            Some(recursors) if state.recursors != portal.state.recursors => {
+                info!("captive portal detected, using dhcp recursors: {:?}", recursors);
+                let resolver = Resolver::with_udp(recursors)?
+                                .with_cache(self.cache.clone());
+                Some(Arc::new(resolver))
+            },
+            Some(_) => portal.resolver.clone(),
+            None => {
+                if portal.resolver.is_some() {
+                    info!("captive portal is gone, using the configured upstreams");
+                }
+                None
+            },
+        };
+
+        *portal = Portal {
+            state,
+            resolver,
+        };
+
+        Ok(())
+    }
+
+    fn resolver(&self, name: &str) -> Arc<Resolver> {
+        if let Some((zone, _)) = self.config.zone(name) {
+            if let Some(resolver) = self.zones.get(zone) {
+                debug!("forwarding {:?} to zone {:?}", name, zone);
+                return resolver.clone();
+            }
+        }
+
+        let portal = self.portal.lock().unwrap();
+        if portal.state.use_dhcp(name, self.config.portal_domains_only) {
+            if let Some(resolver) = &portal.resolver {
+                debug!("forwarding {:?} to dhcp recursors", name);
+                return resolver.clone();
+            }
+        }
+
+        self.upstream.clone()
+    }
+}
+
+/// Convert the static ips of a name to records for a query
+fn static_records(name: &Name, record_type: RecordType, ips: &[IpAddr]) -> Vec<Record> {
+    ips.iter()
+        .filter_map(|ip| match (record_type, ip) {
+            (RecordType::A, IpAddr::V4(ip)) => Some(RData::A(*ip)),
+            (RecordType::AAAA, IpAddr::V6(ip)) => Some(RData::AAAA(*ip)),
+            _ => None,
+        })
+        .map(|rdata| Record::from_rdata(name.clone(), STATIC_TTL, rdata))
+        .collect()
+}
+
+impl Upstream for Forwarder {
+    fn lookup(&self, query: &Query) -> Result<Vec<Record>> {
+        let name = query.name().to_ascii();
+
+        if let Some(ips) = self.config.record(&name) {
+            return Ok(static_records(query.name(), query.query_type(), ips));
+        }
+
+        Resolver::lookup(&self.resolver(&name), &name, query.query_type())
+    }
+}
+
+impl DnsResolver for Forwarder {
+    fn resolve(&self, name: &str) -> Result<Vec<IpAddr>> {
+        if let Some(ips) = self.config.record(name) {
+            return Ok(ips.to_vec());
+        }
+
+        self.resolver(name).resolve(name)
+    }
+
+    fn query(&self, name: &str, record_type: RecordType) -> Result<Vec<DnsRecord>> {
+        let name = dns::query_name(name, record_type);
+
+        if let Some(ips) = self.config.record(&name) {
+            let fqdn = Name::from_ascii(&name)?;
+            let records = static_records(&fqdn, record_type, ips);
+            return Ok(dns::typed_records(&records, record_type));
+        }
+
+        self.resolver(&name).query(&name, record_type)
+    }
+}
+
+/// Answer a dns query, errors of the upstream are reported as SERVFAIL
+pub fn handle<U: Upstream + ?Sized>(upstream: &U, request: &Message) -> Message {
+    if request.message_type() != MessageType::Query {
+        return error(request, ResponseCode::FormErr);
+    }
+
+    if request.op_code() != OpCode::Query {
+        return error(request, ResponseCode::NotImp);
+    }
+
+    let query = match request.queries() {
+        [query] => query,
+        _ => return error(request, ResponseCode::FormErr),
+    };
+
+    let mut response = Message::new();
+    response.set_id(request.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_recursion_desired(request.recursion_desired())
+        .set_recursion_available(true)
+        .add_query(query.clone());
+
+    match upstream.lookup(query) {
+        Ok(records) => {
+            debug!("resolved {} {:?}: {:?}", query.name(), query.query_type(), records);
+            response.set_response_code(ResponseCode::NoError)
+                .add_answers(records);
+        },
+        Err(err) => {
+            warn!("failed to resolve {} {:?}: {}", query.name(), query.query_type(), err);
+            response.set_response_code(ResponseCode::ServFail);
+        },
+    }
+
+    response
+}
+
+fn error(request: &Message, code: ResponseCode) -> Message {
+    let mut response = Message::error_msg(request.id(), request.op_code(), code);
+    response.add_queries(request.queries().to_vec());
+    response
+}
+
+/// Serialize a response, if it doesn't fit into `max_size` only some of the
+/// records are included and the truncated flag is set so the client retries
+/// with tcp
+pub fn encode(response: &Message, max_size: u16) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(512);
+    {
+        let mut encoder = BinEncoder::new(&mut bytes);
+        encoder.set_max_size(max_size);
+        response.emit(&mut encoder)?;
+    }
+    Ok(bytes)
+}
+
+pub struct Server {
+    udp: UdpSocket,
+    tcp: TcpListener,
+}
+
+impl Server {
+    /// Bind the udp and tcp sockets, this needs to happen before privileges
+    /// are dropped
+    pub fn bind(addr: &SocketAddr) -> Result<Server> {
+        let udp = UdpSocket::bind(addr)?;
+        let tcp = TcpListener::bind(addr)?;
+        info!("dns server listening on {}", addr);
+
+        Ok(Server {
+            udp,
+            tcp,
+        })
+    }
+
+    pub fn run<U: Upstream + 'static>(self, upstream: Arc<U>) -> Result<()> {
+        let tcp = self.tcp;
+        let tcp_upstream = upstream.clone();
+        thread::spawn(move || serve_tcp(&tcp, &tcp_upstream));
+
+        serve_udp(&self.udp, &*upstream)
+    }
+}
+
+fn serve_udp<U: Upstream + ?Sized>(socket: &UdpSocket, upstream: &U) -> Result<()> {
+    let mut buf = [0; 4096];
+
+    loop {
+        let (n, src) = socket.recv_from(&mut buf)?;
+
+        let request = match Message::from_vec(&buf[..n]) {
+            Ok(request) => request,
+            Err(err) => {
+                debug!("invalid dns query from {}: {}", src, err);
+                continue;
+            },
+        };
+
+        let response = handle(upstream, &request);
+        match encode(&response, request.max_payload()) {
+            Ok(bytes) => {
+                if let Err(err) = socket.send_to(&bytes, src) {
+                    warn!("failed to send dns response to {}: {}", src, err);
+                }
+            },
+            Err(err) => warn!("failed to encode dns response: {}", err),
+        }
+    }
+}
+
+fn serve_tcp<U: Upstream + 'static>(listener: &TcpListener, upstream: &Arc<U>) {
+    let clients = Arc::new(AtomicUsize::new(0));
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("failed to accept dns connection: {}", err);
+                continue;
+            },
+        };
+
+        if clients.fetch_add(1, Ordering::SeqCst) >= MAX_TCP_CLIENTS {
+            clients.fetch_sub(1, Ordering::SeqCst);
+            debug!("too many dns connections, closing new connection");
+            continue;
+        }
+
+        // a slow client must not block the others
+        let clients = clients.clone();
+        let upstream = upstream.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve_tcp_client(stream, &*upstream) {
+                debug!("dns connection closed: {}", err);
+            }
+            clients.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn serve_tcp_client<U: Upstream + ?Sized>(mut stream: TcpStream, upstream: &U) -> Result<()> {
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+
+    loop {
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let len = u16::from_be_bytes(len) as usize;
+
+        let mut buf = vec![0; len];
+        stream.read_exact(&mut buf)?;
+
+        let request = Message::from_vec(&buf)?;
+        let response = handle(upstream, &request);
+        let bytes = encode(&response, u16::max_value())?;
+
+        stream.write_all(&(bytes.len() as u16).to_be_bytes())?;
+        stream.write_all(&bytes)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decap::{DecapOutcome, DecapReport, ProbeStatus, WalledGardenFingerprint};
+    use std::str::FromStr;
+
+    struct Static(Vec<Record>);
+
+    impl Upstream for Static {
+        fn lookup(&self, query: &Query) -> Result<Vec<Record>> {
+            if query.name().to_ascii() == "fail.example.com." {
+                bail!("upstream failed");
+            }
+
+            Ok(self.0.iter()
+                .filter(|record| record.name() == query.name())
+                .filter(|record| record.rr_type() == query.query_type())
+                .cloned()
+                .collect())
+        }
+    }
+
+    fn upstream() -> Static {
+        let name = Name::from_str("example.com.").unwrap();
+        Static(vec![
+            Record::from_rdata(name, 300, RData::A("192.0.2.1".parse().unwrap())),
+        ])
+    }
+
+    fn request(name: &str, record_type: RecordType) -> Message {
+        let mut request = Message::new();
+        request.set_id(1337)
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Query)
+            .set_recursion_desired(true)
+            .add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+        request
+    }
+
+    #[test]
+    fn test_handle_query() {
+        let response = handle(&upstream(), &request("example.com.", RecordType::A));
+        assert_eq!(response.id(), 1337);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.recursion_available());
+        assert_eq!(response.queries().len(), 1);
+        assert_eq!(response.answers().len(), 1);
+        assert_eq!(response.answers()[0].rdata(), &RData::A("192.0.2.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_handle_no_records() {
+        let response = handle(&upstream(), &request("example.com.", RecordType::AAAA));
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+    }
+
+    #[test]
+    fn test_handle_upstream_error() {
+        let response = handle(&upstream(), &request("fail.example.com.", RecordType::A));
+        assert_eq!(response.id(), 1337);
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+    }
+
+    #[test]
+    fn test_handle_invalid_opcode() {
+        let mut request = request("example.com.", RecordType::A);
+        request.set_op_code(OpCode::Update);
+        let response = handle(&upstream(), &request);
+        assert_eq!(response.response_code(), ResponseCode::NotImp);
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let response = handle(&upstream(), &request("example.com.", RecordType::A));
+        let bytes = encode(&response, 512).unwrap();
+        let decoded = Message::from_vec(&bytes).unwrap();
+        assert!(!decoded.truncated());
+        assert_eq!(decoded.answers(), response.answers());
+    }
+
+    #[test]
+    fn test_encode_truncated() {
+        let name = Name::from_str("example.com.").unwrap();
+        let records = (0..64)
+            .map(|i| Record::from_rdata(name.clone(), 300, RData::A([192, 0, 2, i].into())))
+            .collect();
+
+        let response = handle(&Static(records), &request("example.com.", RecordType::A));
+        let bytes = encode(&response, 512).unwrap();
+        assert!(bytes.len() <= 512);
+
+        let decoded = Message::from_vec(&bytes).unwrap();
+        assert!(decoded.truncated());
+        assert!(decoded.answers().len() < 64);
+        assert_eq!(decoded.queries().len(), 1);
+    }
+
+    fn tcp_query(stream: &mut TcpStream, name: &str) -> Message {
+        let bytes = request(name, RecordType::A).to_vec().unwrap();
+        stream.write_all(&(bytes.len() as u16).to_be_bytes()).unwrap();
+        stream.write_all(&bytes).unwrap();
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len).unwrap();
+        let mut buf = vec![0; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut buf).unwrap();
+        Message::from_vec(&buf).unwrap()
+    }
+
+    #[test]
+    fn test_serve_tcp_concurrent() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let upstream = Arc::new(upstream());
+        thread::spawn(move || serve_tcp(&listener, &upstream));
+
+        // an idle client doesn't delay the next one until it times out
+        let mut idle = TcpStream::connect(addr).unwrap();
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.set_read_timeout(Some(TCP_TIMEOUT / 5)).unwrap();
+
+        let response = tcp_query(&mut client, "example.com.");
+        assert_eq!(response.answers().len(), 1);
+        let response = tcp_query(&mut idle, "example.com.");
+        assert_eq!(response.answers().len(), 1);
+    }
+
+    #[test]
+    fn test_static_records() {
+        let name = Name::from_str("foo.example.com.").unwrap();
+        let ips = vec!["192.0.2.10".parse().unwrap(), "2001:DB8::10".parse().unwrap()];
+
+        let records = static_records(&name, RecordType::A, &ips);
+        assert_eq!(records, vec![
+            Record::from_rdata(name.clone(), STATIC_TTL, RData::A("192.0.2.10".parse().unwrap())),
+        ]);
+
+        let records = static_records(&name, RecordType::AAAA, &ips);
+        assert_eq!(records, vec![
+            Record::from_rdata(name.clone(), STATIC_TTL, RData::AAAA("2001:DB8::10".parse().unwrap())),
+        ]);
+
+        assert!(static_records(&name, RecordType::MX, &ips).is_empty());
+    }
+
+    fn network(generation: u64, has_uplink: Option<bool>) -> NetworkStatus {
+        let mut status = NetworkStatus::empty();
+        status.generation = generation;
+        status.dns = vec!["192.168.1.1".parse().unwrap()];
+        status.has_uplink = has_uplink;
+        status.decap_report = Some(Box::new(DecapReport {
+            outcome: DecapOutcome::Unsolved,
+            fingerprint: Some(WalledGardenFingerprint {
+                portal: Some("http://portal.example.com/login".into()),
+                ..Default::default()
+            }),
+            scripts: Vec::new(),
+            probe: ProbeStatus::Portal,
+            message: None,
+            dns_check: None,
+        }));
+        status
+    }
+
+    #[test]
+    fn test_portal_state_captive() {
+        let mut state = PortalState::default();
+        assert!(!state.use_dhcp("example.com", false));
+
+        // decap hasn't finished yet
+        state.update(Some(&network(1, None)));
+        assert_eq!(state.recursors(), None);
+        assert!(!state.use_dhcp("example.com", false));
+
+        state.update(Some(&network(1, Some(false))));
+        assert_eq!(state.recursors(), Some(&["192.168.1.1".parse().unwrap()][..]));
+        assert!(state.use_dhcp("example.com", false));
+        assert!(!state.use_dhcp("example.com", true));
+        assert!(state.use_dhcp("portal.example.com.", true));
+        assert!(state.use_dhcp("cdn.Portal.example.com", true));
+    }
+
+    #[test]
+    fn test_portal_state_never_leaks_after_decap() {
+        let mut state = PortalState::default();
+        state.update(Some(&network(1, Some(false))));
+        assert!(state.use_dhcp("example.com", false));
+
+        state.update(Some(&network(1, Some(true))));
+        assert_eq!(state.recursors(), None);
+        assert!(!state.use_dhcp("portal.example.com", false));
+
+        // a failed healthcheck on the same network doesn't switch back
+        state.update(Some(&network(1, Some(false))));
+        assert!(!state.use_dhcp("portal.example.com", false));
+
+        // a new network starts over
+        state.update(Some(&network(2, Some(false))));
+        assert!(state.use_dhcp("portal.example.com", false));
+
+        state.update(None);
+        assert_eq!(state, PortalState::default());
+    }
+}
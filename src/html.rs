@@ -2,6 +2,7 @@ use errors::Result;
 
 use kuchiki;
 use kuchiki::traits::TendrilSink;
+use regex::Regex;
 use std::collections::HashMap;
 use hlua::AnyLuaValue;
 use structs::LuaMap;
@@ -98,6 +99,63 @@ pub fn html_form(html: &str) -> Result<HashMap<String, String>> {
     Ok(form)
 }
 
+pub fn html_title(html: &str) -> Option<String> {
+    let title = html_select(html, "title").ok()?;
+    let title = title.text.trim();
+
+    if title.is_empty() {
+        None
+    } else {
+        Some(title.to_string())
+    }
+}
+
+pub fn meta_refresh(html: &str) -> Option<String> {
+    let metas = html_select_list(html, "meta").ok()?;
+
+    for meta in metas {
+        let is_refresh = meta.attrs.get("http-equiv")
+            .map(|x| x.eq_ignore_ascii_case("refresh"))
+            .unwrap_or(false);
+        if !is_refresh {
+            continue;
+        }
+
+        let content = match meta.attrs.get("content") {
+            Some(content) => content,
+            None => continue,
+        };
+
+        let url = match content.find(';') {
+            Some(idx) => content[idx+1..].trim(),
+            None => continue,
+        };
+
+        if url.len() > 4 && url.get(..4).map_or(false, |p| p.eq_ignore_ascii_case("url=")) {
+            let url = url[4..].trim()
+                .trim_matches(|c| c == '\'' || c == '"');
+            return Some(url.to_string());
+        }
+    }
+
+    None
+}
+
+lazy_static! {
+    /// The regex crate doesn't support lookbehind, `location` must not be
+    /// part of another identifier or a property of something that isn't
+    /// the window. A declaration of a local `location` is captured so it
+    /// can be skipped.
+    static ref JS_REDIRECT: Regex = Regex::new(r#"(?:^|[^\w.$])((?:var|let|const)\s+)?(?:(?:window|document|top|self)\.)?location(?:(?:\.href)?\s*=\s*["']([^"']+)["']|\.(?:replace|assign)\(\s*["']([^"']+)["']\s*\))"#).unwrap();
+}
+
+pub fn js_redirect(html: &str) -> Option<String> {
+    JS_REDIRECT.captures_iter(html)
+        .filter(|cap| cap.get(1).is_none())
+        .filter_map(|cap| cap.get(2).or_else(|| cap.get(3)))
+        .map(|x| x.as_str().to_string())
+        .next()
+}
 
 #[cfg(test)]
 mod tests {
@@ -126,4 +184,52 @@ mod tests {
             }
         ]);
     }
+
+    #[test]
+    fn test_html_title() {
+        let title = html_title("<html><head><title>\n  Welcome to the hotel wifi </title></head></html>");
+        assert_eq!(title, Some("Welcome to the hotel wifi".into()));
+        assert_eq!(html_title("<html><title></title></html>"), None);
+        assert_eq!(html_title("no html"), None);
+    }
+
+    #[test]
+    fn test_meta_refresh() {
+        let url = meta_refresh(r#"<meta http-equiv="refresh" content="2;url=https://example.com/foo" />"#);
+        assert_eq!(url, Some("https://example.com/foo".into()));
+        let url = meta_refresh(r#"<META HTTP-EQUIV="Refresh" CONTENT="0; URL='/login?x=1'">"#);
+        assert_eq!(url, Some("/login?x=1".into()));
+        assert_eq!(meta_refresh(r#"<meta http-equiv="refresh" content="2" />"#), None);
+        assert_eq!(meta_refresh(r#"<meta charset="utf-8" />"#), None);
+        // the prefix ends inside a multibyte character
+        assert_eq!(meta_refresh(r#"<meta http-equiv="refresh" content="0;aüü" />"#), None);
+        assert_eq!(meta_refresh(r#"<meta http-equiv="refresh" content="0;url" />"#), None);
+    }
+
+    #[test]
+    fn test_js_redirect() {
+        let url = js_redirect(r#"<script>window.location = "http://192.0.2.1/login";</script>"#);
+        assert_eq!(url, Some("http://192.0.2.1/login".into()));
+        let url = js_redirect(r#"<script>document.location.href='/portal';</script>"#);
+        assert_eq!(url, Some("/portal".into()));
+        let url = js_redirect(r#"<script>location.replace("https://portal.example.com/")</script>"#);
+        assert_eq!(url, Some("https://portal.example.com/".into()));
+        assert_eq!(js_redirect("<script>var x = 1;</script>"), None);
+    }
+
+    #[test]
+    fn test_js_redirect_boundary() {
+        assert_eq!(js_redirect(r#"<script>var location = "/nope";</script>"#), None);
+        assert_eq!(js_redirect(r#"<script>xlocation="/nope";</script>"#), None);
+        assert_eq!(js_redirect(r#"<script>map.location = '/nope';</script>"#), None);
+        assert_eq!(js_redirect(r#"<script>$location.assign('/nope')</script>"#), None);
+        assert_eq!(js_redirect(r#"<script>if (location == "/nope") {}</script>"#), None);
+
+        let url = js_redirect(r#"location='/portal'"#);
+        assert_eq!(url, Some("/portal".into()));
+        let url = js_redirect(r#"<script>let location = "/nope"; top.location = "/portal";</script>"#);
+        assert_eq!(url, Some("/portal".into()));
+        let url = js_redirect(r#"<script>if (x){self.location.assign("/portal")}</script>"#);
+        assert_eq!(url, Some("/portal".into()));
+    }
 }
@@ -5,9 +5,12 @@ use nix::unistd::Gid;
 
 use config::Config;
 use dhcp::NetworkUpdate;
+use dns::CacheStats;
 use errors::Result;
+use knowledge::KnowledgeBase;
 use wifi::NetworkStatus;
 
+use std::collections::BTreeMap;
 use std::fs::{self, Permissions};
 use std::os::unix::fs::PermissionsExt;
 
@@ -17,16 +20,41 @@ pub const SOCKET: &str = "ipc:///run/snail/snail.sock";
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CtlRequest {
     Ping,
-    DhcpEvent(NetworkUpdate),
+    DhcpEvent(Box<NetworkUpdate>),
     StatusRequest,
-    SetStatus(Option<NetworkStatus>),
+    SetStatus(Option<Box<NetworkStatus>>),
+    KnowledgeRequest,
+    CacheStatsRequest,
+    SetCacheStats(String, CacheStats),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum CtlReply {
     Pong,
     Ack,
-    Status(Option<NetworkStatus>),
+    Status(Option<Box<NetworkStatus>>),
+    /// the status update belongs to a network we've already left
+    Stale,
+    Knowledge(KnowledgeBase),
+    /// dns cache statistics, keyed by the process that reported them
+    CacheStats(BTreeMap<String, CacheStats>),
+}
+
+/// Sent from snaild to the decap child, one json object per line
+#[derive(Debug, Serialize, Deserialize)]
+pub enum DecapEvent {
+    Network(Box<NetworkStatus>),
+    /// the network is gone, decap runs of older generations are aborted
+    Disconnected(u64),
+}
+
+impl DecapEvent {
+    pub fn generation(&self) -> u64 {
+        match self {
+            DecapEvent::Network(status) => status.generation,
+            DecapEvent::Disconnected(generation) => *generation,
+        }
+    }
 }
 
 
@@ -113,7 +141,31 @@ impl Client {
 
     pub fn status(&mut self) -> Result<Option<NetworkStatus>> {
         if let CtlReply::Status(status) = self.send(&CtlRequest::StatusRequest)? {
-            Ok(status)
+            Ok(status.map(|status| *status))
+        } else {
+            bail!("Wrong ctl reply");
+        }
+    }
+
+    pub fn knowledge(&mut self) -> Result<KnowledgeBase> {
+        if let CtlReply::Knowledge(knowledge) = self.send(&CtlRequest::KnowledgeRequest)? {
+            Ok(knowledge)
+        } else {
+            bail!("Wrong ctl reply");
+        }
+    }
+
+    pub fn cache_stats(&mut self) -> Result<BTreeMap<String, CacheStats>> {
+        if let CtlReply::CacheStats(stats) = self.send(&CtlRequest::CacheStatsRequest)? {
+            Ok(stats)
+        } else {
+            bail!("Wrong ctl reply");
+        }
+    }
+
+    pub fn set_cache_stats(&mut self, name: &str, stats: CacheStats) -> Result<()> {
+        if let CtlReply::Ack = self.send(&CtlRequest::SetCacheStats(name.to_string(), stats))? {
+            Ok(())
         } else {
             bail!("Wrong ctl reply");
         }
@@ -127,8 +179,13 @@ impl Client {
         }
     }
 
-    pub fn set_status(&mut self, status: Option<NetworkStatus>) -> Result<()> {
-        self.send(&CtlRequest::SetStatus(status))?;
-        Ok(())
+    /// Update the network status, returns false if the update has been
+    /// rejected because the network changed in the meantime
+    pub fn set_status(&mut self, status: Option<NetworkStatus>) -> Result<bool> {
+        match self.send(&CtlRequest::SetStatus(status.map(Box::new)))? {
+            CtlReply::Ack => Ok(true),
+            CtlReply::Stale => Ok(false),
+            _ => bail!("Wrong ctl reply"),
+        }
     }
 }
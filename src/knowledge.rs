@@ -0,0 +1,359 @@
+use decap::{DecapOutcome, ProbeStatus, WalledGardenFingerprint};
+use errors::{Result, ResultExt};
+use wifi::NetworkStatus;
+
+use serde_json;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+pub const PATH: &str = "/var/lib/snail/knowledge.json";
+
+/// Only keep the most recent solutions for each network
+const MAX_SOLUTIONS: usize = 16;
+
+/// Everything snail remembers about the networks it has seen, keyed by ssid
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KnowledgeBase {
+    #[serde(default)]
+    pub networks: BTreeMap<String, NetworkKnowledge>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NetworkKnowledge {
+    #[serde(default)]
+    pub bssids: BTreeSet<String>,
+    pub last_seen: u64,
+    pub last_outcome: Option<DecapOutcome>,
+    /// most recent first
+    #[serde(default)]
+    pub solutions: Vec<Solution>,
+    #[serde(default)]
+    pub scripts: BTreeMap<String, ScriptStats>,
+}
+
+/// A script that solved the captive portal of a network
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Solution {
+    pub script: String,
+    pub bssid: Option<String>,
+    pub portal: Option<String>,
+    pub sha256: Option<String>,
+    pub solved_at: u64,
+}
+
+impl Solution {
+    /// How well this solution matches the current network
+    fn score(&self, bssid: Option<&str>, fingerprint: Option<&WalledGardenFingerprint>) -> u8 {
+        let mut score = 0;
+
+        if bssid.is_some() && self.bssid.as_ref().map(|x| x.as_str()) == bssid {
+            score += 2;
+        }
+
+        if let Some(fingerprint) = fingerprint {
+            if self.portal.is_some() && self.portal == fingerprint.portal_host() {
+                score += 1;
+            }
+            if let (Some(a), Some(page)) = (&self.sha256, &fingerprint.page) {
+                if *a == page.sha256 {
+                    score += 1;
+                }
+            }
+        }
+
+        score
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScriptStats {
+    pub successes: u64,
+    pub failures: u64,
+}
+
+impl KnowledgeBase {
+    /// Update the knowledge base with the decap report of a network
+    pub fn record(&mut self, status: &NetworkStatus, now: u64) {
+        let (ssid, report) = match (&status.ssid, &status.decap_report) {
+            (Some(ssid), Some(report)) => (ssid, report),
+            _ => return,
+        };
+
+        let network = self.networks.entry(ssid.to_string())
+            .or_insert_with(NetworkKnowledge::default);
+
+        network.last_seen = now;
+        network.last_outcome = Some(report.outcome);
+        if let Some(bssid) = &status.bssid {
+            network.bssids.insert(bssid.to_string());
+        }
+
+        for script in &report.scripts {
+            if script.result.is_none() {
+                continue;
+            }
+
+            let stats = network.scripts.entry(script.script.to_string())
+                .or_insert_with(ScriptStats::default);
+
+            if script.probe == Some(ProbeStatus::Online) {
+                stats.successes += 1;
+            } else {
+                stats.failures += 1;
+            }
+        }
+
+        if let Some(script) = report.solved_by() {
+            let fingerprint = report.fingerprint.as_ref();
+            let solution = Solution {
+                script: script.to_string(),
+                bssid: status.bssid.clone(),
+                portal: fingerprint.and_then(|x| x.portal_host()),
+                sha256: fingerprint.and_then(|x| x.page.as_ref())
+                                   .map(|x| x.sha256.clone()),
+                solved_at: now,
+            };
+
+            network.solutions.retain(|x| {
+                x.script != solution.script ||
+                x.bssid != solution.bssid ||
+                x.portal != solution.portal
+            });
+            network.solutions.insert(0, solution);
+            network.solutions.truncate(MAX_SOLUTIONS);
+        }
+    }
+
+    /// Scripts that solved this network in the past, the most promising first.
+    /// Solutions of other networks are considered if the portal matches.
+    pub fn rank(&self, ssid: Option<&str>, bssid: Option<&str>, fingerprint: Option<&WalledGardenFingerprint>) -> Vec<String> {
+        let mut candidates = Vec::new();
+
+        for (name, network) in &self.networks {
+            let same_network = Some(name.as_str()) == ssid;
+
+            for solution in &network.solutions {
+                let score = solution.score(bssid, fingerprint);
+
+                // solutions of other networks need a matching portal
+                if !same_network && score == 0 {
+                    continue;
+                }
+
+                candidates.push((same_network, score, solution.solved_at, &solution.script));
+            }
+        }
+
+        candidates.sort_by(|a, b| b.cmp(a));
+
+        let mut ranked: Vec<String> = Vec::new();
+        for (_, _, _, script) in candidates {
+            if !ranked.contains(script) {
+                ranked.push(script.to_string());
+            }
+        }
+        ranked
+    }
+}
+
+/// The knowledge base on disk. The file is opened once so it can be written
+/// from inside the sandbox.
+#[derive(Debug)]
+pub struct KnowledgeFile {
+    file: File,
+    pub knowledge: KnowledgeBase,
+}
+
+impl KnowledgeFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<KnowledgeFile> {
+        let path = path.as_ref();
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .context(format!("failed to create {:?}", parent))?;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open(path)
+            .context(format!("failed to open {:?}", path))?;
+
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+
+        let knowledge = if text.trim().is_empty() {
+            KnowledgeBase::default()
+        } else {
+            serde_json::from_str(&text)
+                .context("failed to parse knowledge base")?
+        };
+
+        Ok(KnowledgeFile {
+            file,
+            knowledge,
+        })
+    }
+
+    /// Record the decap report of a network and write the knowledge base to disk
+    pub fn record(&mut self, status: &NetworkStatus) -> Result<()> {
+        self.knowledge.record(status, now());
+        self.save()
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        let text = serde_json::to_string_pretty(&self.knowledge)?;
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.set_len(0)?;
+        self.file.write_all(text.as_bytes())?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Read the knowledge base without keeping the file open
+pub fn read_from<P: AsRef<Path>>(path: P) -> Result<KnowledgeBase> {
+    let path = path.as_ref();
+
+    if path.exists() {
+        let text = fs::read_to_string(path)?;
+        let knowledge = serde_json::from_str(&text)?;
+        Ok(knowledge)
+    } else {
+        Ok(KnowledgeBase::default())
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use decap::{DecapReport, ScriptReport, PortalPage};
+
+    fn script(name: &str, detected: bool, probe: Option<ProbeStatus>) -> ScriptReport {
+        ScriptReport {
+            script: name.to_string(),
+            detected,
+            result: if detected { Some(Ok(())) } else { None },
+            duration_ms: if detected { Some(1) } else { None },
+            probe,
+        }
+    }
+
+    fn status(ssid: &str, bssid: &str, portal: &str, scripts: Vec<ScriptReport>) -> NetworkStatus {
+        let mut status = NetworkStatus::empty();
+        status.ssid = Some(ssid.to_string());
+        status.bssid = Some(bssid.to_string());
+
+        let solved = scripts.iter().any(|x| x.probe == Some(ProbeStatus::Online));
+        status.decap_report = Some(Box::new(DecapReport {
+            outcome: if solved { DecapOutcome::Solved } else { DecapOutcome::Unsolved },
+            fingerprint: Some(WalledGardenFingerprint {
+                portal: Some(portal.to_string()),
+                page: Some(PortalPage {
+                    server: None,
+                    powered_by: None,
+                    title: None,
+                    sha256: format!("sha256 of {}", portal),
+                }),
+                ..Default::default()
+            }),
+            scripts,
+            probe: ProbeStatus::Online,
+            message: None,
+            dns_check: None,
+        }));
+        status
+    }
+
+    #[test]
+    fn test_record_solution() {
+        let mut kb = KnowledgeBase::default();
+        kb.record(&status("Hotel", "00:11:22:33:44:55", "http://portal.example.com/", vec![
+            script("generic.lua", true, Some(ProbeStatus::Portal)),
+            script("other.lua", false, None),
+            script("hotel.lua", true, Some(ProbeStatus::Online)),
+        ]), 1000);
+
+        let network = &kb.networks["Hotel"];
+        assert_eq!(network.last_seen, 1000);
+        assert_eq!(network.last_outcome, Some(DecapOutcome::Solved));
+        assert!(network.bssids.contains("00:11:22:33:44:55"));
+        assert_eq!(network.solutions, vec![Solution {
+            script: "hotel.lua".into(),
+            bssid: Some("00:11:22:33:44:55".into()),
+            portal: Some("portal.example.com".into()),
+            sha256: Some("sha256 of http://portal.example.com/".into()),
+            solved_at: 1000,
+        }]);
+        assert_eq!(network.scripts["generic.lua"], ScriptStats { successes: 0, failures: 1 });
+        assert_eq!(network.scripts["hotel.lua"], ScriptStats { successes: 1, failures: 0 });
+        assert!(!network.scripts.contains_key("other.lua"));
+    }
+
+    #[test]
+    fn test_record_replaces_solution() {
+        let mut kb = KnowledgeBase::default();
+        for now in &[1000, 2000] {
+            kb.record(&status("Hotel", "00:11:22:33:44:55", "http://portal.example.com/", vec![
+                script("hotel.lua", true, Some(ProbeStatus::Online)),
+            ]), *now);
+        }
+
+        let network = &kb.networks["Hotel"];
+        assert_eq!(network.solutions.len(), 1);
+        assert_eq!(network.solutions[0].solved_at, 2000);
+        assert_eq!(network.scripts["hotel.lua"], ScriptStats { successes: 2, failures: 0 });
+    }
+
+    #[test]
+    fn test_rank() {
+        let mut kb = KnowledgeBase::default();
+        kb.record(&status("Hotel", "00:00:00:00:00:01", "http://a.example.com/", vec![
+            script("a.lua", true, Some(ProbeStatus::Online)),
+        ]), 1000);
+        kb.record(&status("Hotel", "00:00:00:00:00:02", "http://b.example.com/", vec![
+            script("b.lua", true, Some(ProbeStatus::Online)),
+        ]), 2000);
+        kb.record(&status("Airport", "00:00:00:00:00:03", "http://c.example.com/", vec![
+            script("c.lua", true, Some(ProbeStatus::Online)),
+        ]), 3000);
+
+        // most recent solution of the network first
+        assert_eq!(kb.rank(Some("Hotel"), None, None), vec!["b.lua", "a.lua"]);
+        // prefer the solution for this access point
+        assert_eq!(kb.rank(Some("Hotel"), Some("00:00:00:00:00:01"), None), vec!["a.lua", "b.lua"]);
+
+        // a known portal on an unknown network
+        let fingerprint = WalledGardenFingerprint {
+            portal: Some("http://c.example.com/login".into()),
+            ..Default::default()
+        };
+        assert_eq!(kb.rank(Some("Cafe"), None, Some(&fingerprint)), vec!["c.lua"]);
+        assert!(kb.rank(Some("Cafe"), None, None).is_empty());
+        assert!(kb.rank(None, None, None).is_empty());
+    }
+
+    #[test]
+    fn test_record_ignores_unknown_ssid() {
+        let mut kb = KnowledgeBase::default();
+        let mut status = status("Hotel", "00:11:22:33:44:55", "http://portal.example.com/", vec![]);
+        status.ssid = None;
+        kb.record(&status, 1000);
+        assert_eq!(kb, KnowledgeBase::default());
+    }
+}
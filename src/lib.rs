@@ -7,8 +7,10 @@ extern crate hlua_badtouch as hlua;
 #[macro_use] extern crate failure;
 extern crate kuchiki;
 extern crate regex;
+#[macro_use] extern crate lazy_static;
 extern crate nix;
 extern crate zmq;
+extern crate serde;
 #[macro_use] extern crate serde_derive;
 extern crate serde_json;
 extern crate rand;
@@ -19,8 +21,12 @@ extern crate users;
 extern crate syscallz;
 extern crate caps;
 extern crate url;
+extern crate sha2;
+#[macro_use] extern crate dhcp4r;
+#[cfg(test)] extern crate tempfile;
 
 extern crate trust_dns_resolver;
+extern crate trust_dns_proto;
 
 extern crate hyper;
 extern crate http;
@@ -38,14 +44,21 @@ pub mod errors {
 pub use errors::Result;
 
 pub mod args;
+pub mod capport;
 pub mod config;
 pub mod connect;
 pub mod decap;
 pub mod dhcp;
 pub mod dns;
+pub mod dnscheck;
+pub mod dnsd;
 pub mod html;
 pub mod ipc;
 pub mod json;
+pub mod knowledge;
+pub mod mac;
+pub mod netlink;
+pub mod nl80211;
 pub mod runtime;
 pub mod sandbox;
 pub mod scripts;
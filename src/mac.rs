@@ -0,0 +1,472 @@
+use config::{Config, MacPolicy};
+use dhcp::client::hardware_address;
+use errors::{Result, ResultExt};
+use netlink;
+use serde_json;
+
+use nix::libc;
+use nix::net::if_::if_nametoindex;
+use nix::sys::socket::{self, AddressFamily, SockType, SockFlag};
+use nix::unistd;
+use rand::{self, Rng};
+use sha2::{Sha256, Digest};
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+
+/// The key that stable random addresses are derived from
+pub const SECRET_PATH: &str = "/var/lib/snail/mac-secret";
+/// The address that is in use on an interface is recorded in this directory
+pub const ASSIGNMENT_DIR: &str = "/var/lib/snail";
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const SECRET_LEN: usize = 32;
+
+const SIOCETHTOOL: libc::c_ulong = 0x8946;
+const ETHTOOL_GPERMADDR: u32 = 0x20;
+const MAX_ADDR_LEN: usize = 32;
+
+#[repr(C)]
+struct EthtoolPermAddr {
+    cmd: u32,
+    size: u32,
+    data: [u8; MAX_ADDR_LEN],
+}
+
+#[repr(C)]
+struct IfReq {
+    name: [u8; 16],
+    data: *mut EthtoolPermAddr,
+    // the rest of the ifreq union
+    _pad: [u8; 16],
+}
+
+pub fn format_mac(mac: [u8; 6]) -> String {
+    mac.iter()
+        .map(|x| format!("{:02x}", x))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Locally administered unicast addresses can't collide with vendor addresses
+fn local_unicast(mut mac: [u8; 6]) -> [u8; 6] {
+    mac[0] = (mac[0] | 0x02) & !0x01;
+    mac
+}
+
+pub fn random() -> [u8; 6] {
+    local_unicast(rand::thread_rng().gen())
+}
+
+/// The same secret and context always result in the same address
+pub fn derive(secret: &[u8], context: &str) -> [u8; 6] {
+    let mut hasher = Sha256::new();
+    hasher.input(secret);
+    hasher.input(context.as_bytes());
+    let hash = hasher.result();
+
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&hash[..6]);
+    local_unicast(mac)
+}
+
+/// The address of a random policy, None for `permanent`
+pub fn random_address(policy: MacPolicy, interface: &str, secret: &[u8], boot_id: &str, ssid: Option<&str>) -> Option<[u8; 6]> {
+    match policy {
+        MacPolicy::Permanent => None,
+        MacPolicy::Boot => Some(derive(secret, &format!("{}/boot/{}", interface, boot_id))),
+        MacPolicy::Ssid => match ssid {
+            Some(ssid) => Some(derive(secret, &format!("{}/ssid/{}", interface, ssid))),
+            None => {
+                info!("ssid is unknown, using a random mac address");
+                Some(random())
+            },
+        },
+        MacPolicy::Connect => Some(random()),
+    }
+}
+
+/// Read the secret, it's created on first use
+pub fn read_secret<P: AsRef<Path>>(path: P) -> Result<Vec<u8>> {
+    let path = path.as_ref();
+
+    if path.exists() {
+        let secret = fs::read(path)
+            .context(format!("failed to read {:?}", path))?;
+        if secret.len() != SECRET_LEN {
+            bail!("invalid secret in {:?}", path);
+        }
+        return Ok(secret);
+    }
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .context(format!("failed to create {:?}", parent))?;
+    }
+
+    let mut secret = vec![0; SECRET_LEN];
+    rand::thread_rng().fill(&mut secret[..]);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+        .context(format!("failed to create {:?}", path))?;
+    file.write_all(&secret)?;
+
+    Ok(secret)
+}
+
+fn boot_id() -> Result<String> {
+    let boot_id = fs::read_to_string(BOOT_ID_PATH)?;
+    Ok(boot_id.trim().to_string())
+}
+
+/// The address that has been assigned to the interface by the vendor
+pub fn permanent_address(interface: &str) -> Result<[u8; 6]> {
+    if interface.len() >= 16 {
+        bail!("interface name is too long");
+    }
+
+    let mut perm = EthtoolPermAddr {
+        cmd: ETHTOOL_GPERMADDR,
+        size: MAX_ADDR_LEN as u32,
+        data: [0; MAX_ADDR_LEN],
+    };
+    let mut req = IfReq {
+        name: [0; 16],
+        data: &mut perm,
+        _pad: [0; 16],
+    };
+    req.name[..interface.len()].copy_from_slice(interface.as_bytes());
+
+    let fd = socket::socket(AddressFamily::Inet, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    let ret = unsafe { libc::ioctl(fd, SIOCETHTOOL as _, &mut req) };
+    let err = io::Error::last_os_error();
+    let _ = unistd::close(fd);
+    if ret < 0 {
+        return Err(err.into());
+    }
+
+    if perm.size != 6 || perm.data[..6].iter().all(|x| *x == 0) {
+        bail!("interface has no permanent address");
+    }
+
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&perm.data[..6]);
+    Ok(mac)
+}
+
+/// Set the address of the interface through netlink, the link is brought
+/// down for the change and up again afterwards.
+pub fn set_address(interface: &str, mac: [u8; 6]) -> Result<()> {
+    let index = if_nametoindex(interface)?;
+    let mut socket = netlink::Socket::connect()?;
+
+    socket.set_link_up(index, false)?;
+    let result = socket.set_link_address(index, mac);
+    socket.set_link_up(index, true)?;
+
+    result.context("failed to set mac address")?;
+    Ok(())
+}
+
+/// The address that has been set on an interface. Changing the address
+/// resets the link, the association that follows has to keep this address.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Assignment {
+    /// the network that uses the address, None until we're connected
+    pub ssid: Option<String>,
+    pub mac: [u8; 6],
+    /// the address has been set and we're waiting for the link to come back
+    #[serde(default)]
+    pub pending: bool,
+}
+
+impl Assignment {
+    pub fn path(interface: &str) -> String {
+        format!("{}/mac-{}.json", ASSIGNMENT_DIR, interface)
+    }
+
+    /// Read the assignment, a missing or broken file is treated as unknown
+    pub fn load<P: AsRef<Path>>(path: P) -> Option<Assignment> {
+        let text = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&text).ok()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let json = serde_json::to_string(self)?;
+        fs::write(path, json)
+            .context(format!("failed to write {:?}", path))?;
+        Ok(())
+    }
+
+    /// Check if `mac` is the address we've set for the connection to `ssid`,
+    /// an address that wasn't used by a connection yet can be taken by any
+    pub fn is_current(&self, ssid: Option<&str>, mac: [u8; 6]) -> bool {
+        self.mac == mac && (self.ssid.is_none() || self.ssid.as_ref().map(|x| x.as_str()) == ssid)
+    }
+}
+
+/// Policies that pick a new address every time can't be recomputed when the
+/// link comes back, we need to remember which address we've picked
+fn is_random(policy: MacPolicy, ssid: Option<&str>) -> bool {
+    match policy {
+        MacPolicy::Connect => true,
+        MacPolicy::Ssid => ssid.is_none(),
+        _ => false,
+    }
+}
+
+fn record(interface: &str, ssid: Option<&str>, mac: [u8; 6], pending: bool) {
+    let assignment = Assignment {
+        ssid: ssid.map(String::from),
+        mac,
+        pending,
+    };
+    if let Err(err) = assignment.save(Assignment::path(interface)) {
+        warn!("failed to record mac address: {}", err);
+    }
+}
+
+/// Keep the recorded address if it belongs to this connection and mark it as
+/// used by `ssid`
+fn claim<P: AsRef<Path>>(path: P, ssid: Option<&str>, current: [u8; 6]) -> Result<bool> {
+    let mut assignment = match Assignment::load(&path) {
+        Some(assignment) => assignment,
+        None => return Ok(false),
+    };
+    if !assignment.is_current(ssid, current) {
+        return Ok(false);
+    }
+
+    assignment.ssid = ssid.map(String::from);
+    assignment.pending = false;
+    assignment.save(&path)?;
+    Ok(true)
+}
+
+/// Forget the address when the connection is gone, so the next connection
+/// gets a new random address. A link reset that was caused by setting the
+/// address is ignored.
+fn forget<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    match Assignment::load(path) {
+        Some(ref assignment) if assignment.pending => {
+            debug!("link has been reset after setting the mac address");
+        },
+        Some(_) => fs::remove_file(path)
+            .context(format!("failed to remove {:?}", path))?,
+        None => (),
+    }
+    Ok(())
+}
+
+/// Apply the mac policy for `ssid` to the interface and return the address
+/// that is used now
+pub fn apply(config: &Config, interface: &str, ssid: Option<&str>) -> Result<[u8; 6]> {
+    let policy = config.mac_policy(ssid);
+    let current = hardware_address(interface)?;
+
+    let mac = match policy {
+        MacPolicy::Permanent => match permanent_address(interface) {
+            Ok(mac) => mac,
+            Err(err) => {
+                debug!("failed to read permanent address of {}: {}", interface, err);
+                current
+            },
+        },
+        _ => {
+            let secret = read_secret(SECRET_PATH)?;
+            let boot_id = boot_id()?;
+            random_address(policy, interface, &secret, &boot_id, ssid)
+                .unwrap_or(current)
+        },
+    };
+
+    if mac == current {
+        debug!("keeping mac address {} on {}", format_mac(mac), interface);
+    } else {
+        info!("setting mac address of {} to {} ({:?})", interface, format_mac(mac), policy);
+        set_address(interface, mac)?;
+    }
+
+    Ok(mac)
+}
+
+/// Apply the mac policy before we associate with a network, `ssid` is only
+/// known if we're going to connect to a specific network. The address is
+/// recorded for the first connection.
+pub fn assign(config: &Config, interface: &str, ssid: Option<&str>) -> Result<[u8; 6]> {
+    let mac = apply(config, interface, ssid)?;
+    record(interface, ssid, mac, true);
+    Ok(mac)
+}
+
+/// Apply the mac policy when the interface got a carrier, before dhcp is
+/// started. The address has been set before the association already, this
+/// only changes it if the policy depends on the network we ended up on.
+/// Returns true if the address has been changed, the association is lost in
+/// that case and we have to wait for the next carrier.
+pub fn apply_on_carrier(config: &Config, interface: &str, ssid: Option<&str>) -> Result<bool> {
+    let current = hardware_address(interface)?;
+
+    if is_random(config.mac_policy(ssid), ssid) &&
+            claim(Assignment::path(interface), ssid, current).unwrap_or(false) {
+        debug!("keeping assigned mac address {} on {}", format_mac(current), interface);
+        return Ok(false);
+    }
+
+    let mac = apply(config, interface, ssid)?;
+    let changed = mac != current;
+    record(interface, ssid, mac, changed);
+    Ok(changed)
+}
+
+/// The connection is gone, a random address isn't used again for the next one
+pub fn release(interface: &str) {
+    if let Err(err) = forget(Assignment::path(interface)) {
+        warn!("failed to release mac address: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = &[0x42; SECRET_LEN];
+    const BOOT_ID: &str = "7e2aa8a2-2b1b-4c49-a4a2-8b4a6a9c6a59";
+
+    fn is_local_unicast(mac: [u8; 6]) -> bool {
+        mac[0] & 0x03 == 0x02
+    }
+
+    #[test]
+    fn test_format_mac() {
+        assert_eq!(format_mac([0x02, 0x00, 0xab, 0x0c, 0xff, 0x01]), "02:00:ab:0c:ff:01");
+    }
+
+    #[test]
+    fn test_random_address_permanent() {
+        assert_eq!(random_address(MacPolicy::Permanent, "wlan0", SECRET, BOOT_ID, Some("Hotel")), None);
+    }
+
+    #[test]
+    fn test_random_address_boot() {
+        let mac = random_address(MacPolicy::Boot, "wlan0", SECRET, BOOT_ID, Some("Hotel")).unwrap();
+        assert!(is_local_unicast(mac));
+        // same address on every network
+        assert_eq!(random_address(MacPolicy::Boot, "wlan0", SECRET, BOOT_ID, Some("Airport")), Some(mac));
+        assert_eq!(random_address(MacPolicy::Boot, "wlan0", SECRET, BOOT_ID, None), Some(mac));
+        // but not after a reboot or on other interfaces
+        assert_ne!(random_address(MacPolicy::Boot, "wlan0", SECRET, "another-boot", None), Some(mac));
+        assert_ne!(random_address(MacPolicy::Boot, "wlan1", SECRET, BOOT_ID, None), Some(mac));
+    }
+
+    #[test]
+    fn test_random_address_ssid() {
+        let mac = random_address(MacPolicy::Ssid, "wlan0", SECRET, BOOT_ID, Some("Hotel")).unwrap();
+        assert!(is_local_unicast(mac));
+        // stays the same across reboots
+        assert_eq!(random_address(MacPolicy::Ssid, "wlan0", SECRET, "another-boot", Some("Hotel")), Some(mac));
+        assert_ne!(random_address(MacPolicy::Ssid, "wlan0", SECRET, BOOT_ID, Some("Airport")), Some(mac));
+        assert_ne!(random_address(MacPolicy::Ssid, "wlan0", &[0x23; SECRET_LEN], BOOT_ID, Some("Hotel")), Some(mac));
+    }
+
+    #[test]
+    fn test_random_address_connect() {
+        let a = random_address(MacPolicy::Connect, "wlan0", SECRET, BOOT_ID, Some("Hotel")).unwrap();
+        let b = random_address(MacPolicy::Connect, "wlan0", SECRET, BOOT_ID, Some("Hotel")).unwrap();
+        assert!(is_local_unicast(a));
+        assert!(is_local_unicast(b));
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn test_read_secret() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let path = dir.path().join("snail/mac-secret");
+
+        let secret = read_secret(&path).unwrap();
+        assert_eq!(secret.len(), SECRET_LEN);
+        assert_eq!(read_secret(&path).unwrap(), secret);
+
+        fs::write(&path, b"short").unwrap();
+        assert!(read_secret(&path).is_err());
+    }
+
+    #[test]
+    fn test_assignment() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let path = dir.path().join("mac-wlan0.json");
+        assert_eq!(Assignment::load(&path), None);
+
+        let mac = [0x02, 0x00, 0xab, 0x0c, 0xff, 0x01];
+        let assignment = Assignment {
+            ssid: Some("Hotel".into()),
+            mac,
+            pending: false,
+        };
+        assignment.save(&path).unwrap();
+
+        let assignment = Assignment::load(&path).unwrap();
+        assert!(assignment.is_current(Some("Hotel"), mac));
+        assert!(!assignment.is_current(Some("Airport"), mac));
+        assert!(!assignment.is_current(None, mac));
+        assert!(!assignment.is_current(Some("Hotel"), [0x02, 0, 0, 0, 0, 1]));
+
+        fs::write(&path, b"{").unwrap();
+        assert_eq!(Assignment::load(&path), None);
+    }
+
+    #[test]
+    fn test_assignment_before_connect() {
+        let mac = [0x02, 0x00, 0xab, 0x0c, 0xff, 0x01];
+        let assignment = Assignment {
+            ssid: None,
+            mac,
+            pending: true,
+        };
+        // the first connection takes the address that was set at startup
+        assert!(assignment.is_current(Some("Hotel"), mac));
+        assert!(assignment.is_current(None, mac));
+        assert!(!assignment.is_current(Some("Hotel"), [0x02, 0, 0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_is_random() {
+        assert!(is_random(MacPolicy::Connect, Some("Hotel")));
+        assert!(is_random(MacPolicy::Ssid, None));
+        assert!(!is_random(MacPolicy::Ssid, Some("Hotel")));
+        assert!(!is_random(MacPolicy::Boot, None));
+        assert!(!is_random(MacPolicy::Permanent, None));
+    }
+
+    #[test]
+    fn test_reconnect_same_ssid() {
+        let dir = ::tempfile::tempdir().unwrap();
+        let path = dir.path().join("mac-wlan0.json");
+        let mac = [0x02, 0x00, 0xab, 0x0c, 0xff, 0x01];
+
+        // we've set the address on carrier, this resets the link
+        Assignment {
+            ssid: Some("Hotel".into()),
+            mac,
+            pending: true,
+        }.save(&path).unwrap();
+        forget(&path).unwrap();
+        // the association that follows keeps the address
+        assert!(claim(&path, Some("Hotel"), mac).unwrap());
+        assert!(!Assignment::load(&path).unwrap().pending);
+
+        // the connection is lost, reconnecting needs a new address
+        forget(&path).unwrap();
+        assert_eq!(Assignment::load(&path), None);
+        assert!(!claim(&path, Some("Hotel"), mac).unwrap());
+    }
+}
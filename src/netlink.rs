@@ -0,0 +1,553 @@
+use errors::Result;
+
+use nix::libc;
+use nix::sys::socket::{self, AddressFamily, SockType, SockFlag, SockAddr, MsgFlags, sockopt};
+use nix::sys::time::{TimeVal, TimeValLike};
+use nix::unistd;
+
+use std::io;
+use std::mem;
+use std::net::IpAddr;
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+
+
+pub const NLMSG_ERROR: u16 = 2;
+pub const NLMSG_DONE: u16 = 3;
+
+pub const NLM_F_REQUEST: u16 = 1;
+pub const NLM_F_ACK: u16 = 4;
+pub const NLM_F_DUMP: u16 = 0x300;
+
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_GETADDR: u16 = 22;
+const RTM_NEWROUTE: u16 = 24;
+
+const IFLA_ADDRESS: u16 = 1;
+const IFLA_MTU: u16 = 4;
+const IFF_UP: u32 = 1;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+
+const RT_TABLE_MAIN: u8 = 254;
+/// Same as routes added with `ip route`
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+
+/// The upper bits of the attribute type are flags, eg. NLA_F_NESTED
+const NLA_TYPE_MASK: u16 = 0x3fff;
+const NLMSG_HDRLEN: usize = 16;
+const NLA_HDRLEN: usize = 4;
+const SOL_NETLINK: libc::c_int = 270;
+
+/// Dumps are split into messages of up to 32k
+const RECV_BUFFER: usize = 64 * 1024;
+
+/// Messages and attributes are aligned to 4 bytes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn pad(buf: &mut Vec<u8>) {
+    let len = align(buf.len());
+    buf.resize(len, 0);
+}
+
+/// An attribute that can be nested into another attribute
+pub fn attribute(attr_type: u16, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(align(NLA_HDRLEN + data.len()));
+    buf.extend_from_slice(&((NLA_HDRLEN + data.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&attr_type.to_ne_bytes());
+    buf.extend_from_slice(data);
+    pad(&mut buf);
+    buf
+}
+
+/// A request to the kernel, the length and sequence number are set when
+/// it's sent
+#[derive(Debug)]
+pub struct Message {
+    buf: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(msg_type: u16, flags: u16) -> Message {
+        let mut buf = Vec::with_capacity(64);
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&msg_type.to_ne_bytes());
+        buf.extend_from_slice(&flags.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        buf.extend_from_slice(&0u32.to_ne_bytes());
+        Message {
+            buf,
+        }
+    }
+
+    /// The fixed header of the protocol, eg. ifinfomsg or genlmsghdr
+    pub fn push(&mut self, header: &[u8]) {
+        self.buf.extend_from_slice(header);
+        pad(&mut self.buf);
+    }
+
+    pub fn attr(&mut self, attr_type: u16, data: &[u8]) {
+        self.buf.extend(attribute(attr_type, data));
+    }
+
+    fn finish(mut self, seq: u32) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf[8..12].copy_from_slice(&seq.to_ne_bytes());
+        self.buf
+    }
+}
+
+/// A message from the kernel
+#[derive(Debug, PartialEq)]
+pub struct Received {
+    pub msg_type: u16,
+    pub flags: u16,
+    pub seq: u32,
+    pub payload: Vec<u8>,
+}
+
+pub fn read_u16(data: &[u8]) -> Option<u16> {
+    if data.len() < 2 {
+        return None;
+    }
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(&data[..2]);
+    Some(u16::from_ne_bytes(bytes))
+}
+
+pub fn read_u32(data: &[u8]) -> Option<u32> {
+    if data.len() < 4 {
+        return None;
+    }
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[..4]);
+    Some(u32::from_ne_bytes(bytes))
+}
+
+/// Split a datagram into its messages
+pub fn parse_messages(buf: &[u8]) -> Result<Vec<Received>> {
+    let mut messages = Vec::new();
+    let mut pos = 0;
+
+    while pos + NLMSG_HDRLEN <= buf.len() {
+        let header = &buf[pos..];
+        let len = read_u32(header).unwrap_or(0) as usize;
+        if len < NLMSG_HDRLEN || pos + len > buf.len() {
+            bail!("invalid netlink message");
+        }
+
+        messages.push(Received {
+            msg_type: read_u16(&header[4..]).unwrap_or(0),
+            flags: read_u16(&header[6..]).unwrap_or(0),
+            seq: read_u32(&header[8..]).unwrap_or(0),
+            payload: header[NLMSG_HDRLEN..len].to_vec(),
+        });
+
+        pos += align(len);
+    }
+
+    Ok(messages)
+}
+
+/// The result of a request from an NLMSG_ERROR message, an errno of 0 is
+/// an acknowledgement
+fn error_result(payload: &[u8]) -> Result<()> {
+    let errno = match read_u32(payload) {
+        Some(errno) => errno as i32,
+        None => bail!("truncated netlink error"),
+    };
+
+    if errno == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::from_raw_os_error(-errno).into())
+    }
+}
+
+/// Iterates over the attributes in a message or a nested attribute, the
+/// flags are removed from the type. Stops at the first broken attribute.
+#[derive(Debug)]
+pub struct Attributes<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Iterator for Attributes<'a> {
+    type Item = (u16, &'a [u8]);
+
+    fn next(&mut self) -> Option<(u16, &'a [u8])> {
+        let len = read_u16(self.buf)? as usize;
+        let attr_type = read_u16(&self.buf[2..])?;
+        if len < NLA_HDRLEN || len > self.buf.len() {
+            return None;
+        }
+
+        let data = &self.buf[NLA_HDRLEN..len];
+        let next = align(len).min(self.buf.len());
+        self.buf = &self.buf[next..];
+
+        Some((attr_type & NLA_TYPE_MASK, data))
+    }
+}
+
+pub fn attributes(buf: &[u8]) -> Attributes {
+    Attributes {
+        buf,
+    }
+}
+
+/// A minimal netlink socket to talk to the kernel without calling `ip` or `iw`
+#[derive(Debug)]
+pub struct Socket {
+    fd: RawFd,
+    seq: u32,
+}
+
+impl Socket {
+    /// A rtnetlink socket, used to configure links
+    pub fn connect() -> Result<Socket> {
+        // protocol 0 is NETLINK_ROUTE
+        let fd = socket::socket(AddressFamily::Netlink, SockType::Raw, SockFlag::SOCK_CLOEXEC, None)?;
+        Ok(Socket {
+            fd,
+            seq: 0,
+        })
+    }
+
+    /// A generic netlink socket, used for nl80211
+    pub fn generic() -> Result<Socket> {
+        // nix doesn't support NETLINK_GENERIC as protocol
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_GENERIC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Socket {
+            fd,
+            seq: 0,
+        })
+    }
+
+    /// Receive the messages of a multicast group
+    pub fn add_membership(&self, group: u32) -> Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(self.fd, SOL_NETLINK, libc::NETLINK_ADD_MEMBERSHIP,
+                             &group as *const u32 as *const libc::c_void,
+                             mem::size_of::<u32>() as libc::socklen_t)
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    pub fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        let timeout = TimeVal::milliseconds(timeout.as_millis() as i64);
+        socket::setsockopt(self.fd, sockopt::ReceiveTimeout, &timeout)?;
+        Ok(())
+    }
+
+    /// Send a message and return its sequence number
+    pub fn send(&mut self, msg: Message) -> Result<u32> {
+        self.seq += 1;
+        let msg = msg.finish(self.seq);
+        socket::sendto(self.fd, &msg, &SockAddr::new_netlink(0, 0), MsgFlags::empty())?;
+        Ok(self.seq)
+    }
+
+    pub fn recv(&mut self) -> Result<Vec<Received>> {
+        let mut buf = vec![0; RECV_BUFFER];
+        let n = socket::recv(self.fd, &mut buf, MsgFlags::empty())?;
+        parse_messages(&buf[..n])
+    }
+
+    /// Send a request and collect the replies until the kernel acknowledged
+    /// it or finished the dump. Messages that don't belong to the request,
+    /// like multicast events, are discarded.
+    pub fn request(&mut self, msg: Message) -> Result<Vec<Received>> {
+        let seq = self.send(msg)?;
+        let mut replies = Vec::new();
+
+        loop {
+            for msg in self.recv()? {
+                if msg.seq != seq {
+                    continue;
+                }
+
+                match msg.msg_type {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => {
+                        error_result(&msg.payload)?;
+                        return Ok(replies);
+                    },
+                    _ => replies.push(msg),
+                }
+            }
+        }
+    }
+
+    pub fn set_link_up(&mut self, index: u32, up: bool) -> Result<()> {
+        let flags = if up { IFF_UP } else { 0 };
+        self.request(link_message(index, flags, IFF_UP, None))?;
+        Ok(())
+    }
+
+    /// Most drivers require the link to be down to change the address
+    pub fn set_link_address(&mut self, index: u32, address: [u8; 6]) -> Result<()> {
+        self.request(link_message(index, 0, 0, Some(&address)))?;
+        Ok(())
+    }
+
+    pub fn set_link_mtu(&mut self, index: u32, mtu: u32) -> Result<()> {
+        let mut msg = link_message(index, 0, 0, None);
+        msg.attr(IFLA_MTU, &mtu.to_ne_bytes());
+        self.request(msg)?;
+        Ok(())
+    }
+
+    /// Add an address to the link, an existing address is replaced
+    pub fn add_address(&mut self, index: u32, address: IpAddr, prefix: u8) -> Result<()> {
+        let (family, data) = address_bytes(address);
+        let mut msg = Message::new(RTM_NEWADDR, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE);
+        msg.push(&address_header(family, prefix, index));
+        msg.attr(IFA_LOCAL, &data);
+        msg.attr(IFA_ADDRESS, &data);
+        self.request(msg)?;
+        Ok(())
+    }
+
+    /// Remove all addresses of a family from the link, like `ip address flush`
+    pub fn flush_addresses(&mut self, index: u32, ipv6: bool) -> Result<()> {
+        let family = if ipv6 { libc::AF_INET6 } else { libc::AF_INET } as u8;
+        let mut msg = Message::new(RTM_GETADDR, NLM_F_REQUEST | NLM_F_DUMP);
+        msg.push(&address_header(family, 0, 0));
+
+        for reply in self.request(msg)? {
+            if reply.msg_type != RTM_NEWADDR || reply.payload.len() < 8 {
+                continue;
+            }
+            // the kernel doesn't filter the dump by the index in the request
+            if reply.payload[0] != family || read_u32(&reply.payload[4..]) != Some(index) {
+                continue;
+            }
+
+            // the address is deleted with the same header and attributes
+            let mut msg = Message::new(RTM_DELADDR, NLM_F_REQUEST | NLM_F_ACK);
+            msg.push(&reply.payload);
+            self.request(msg)?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a route through the link to the main table, an existing route
+    /// to the same destination is replaced. Without a gateway the
+    /// destination is on-link.
+    pub fn replace_route(&mut self, index: u32, destination: IpAddr, prefix: u8, gateway: Option<IpAddr>) -> Result<()> {
+        self.request(route_message(index, destination, prefix, gateway))?;
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.fd);
+    }
+}
+
+/// A RTM_NEWLINK request, `change` selects the bits of `flags` that are applied
+fn link_message(index: u32, flags: u32, change: u32, address: Option<&[u8; 6]>) -> Message {
+    let mut msg = Message::new(RTM_NEWLINK, NLM_F_REQUEST | NLM_F_ACK);
+
+    // ifinfomsg
+    let mut header = Vec::with_capacity(16);
+    header.push(0); // AF_UNSPEC
+    header.push(0);
+    header.extend_from_slice(&0u16.to_ne_bytes());
+    header.extend_from_slice(&(index as i32).to_ne_bytes());
+    header.extend_from_slice(&flags.to_ne_bytes());
+    header.extend_from_slice(&change.to_ne_bytes());
+    msg.push(&header);
+
+    if let Some(address) = address {
+        msg.attr(IFLA_ADDRESS, address);
+    }
+
+    msg
+}
+
+fn address_bytes(address: IpAddr) -> (u8, Vec<u8>) {
+    match address {
+        IpAddr::V4(ip) => (libc::AF_INET as u8, ip.octets().to_vec()),
+        IpAddr::V6(ip) => (libc::AF_INET6 as u8, ip.octets().to_vec()),
+    }
+}
+
+/// ifaddrmsg
+fn address_header(family: u8, prefix: u8, index: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(8);
+    header.push(family);
+    header.push(prefix);
+    header.push(0); // flags
+    header.push(0); // scope
+    header.extend_from_slice(&index.to_ne_bytes());
+    header
+}
+
+/// A RTM_NEWROUTE request that creates or replaces a route in the main table
+fn route_message(index: u32, destination: IpAddr, prefix: u8, gateway: Option<IpAddr>) -> Message {
+    let mut msg = Message::new(RTM_NEWROUTE, NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_REPLACE);
+    let (family, destination) = address_bytes(destination);
+    let scope = if gateway.is_some() { RT_SCOPE_UNIVERSE } else { RT_SCOPE_LINK };
+
+    // rtmsg
+    let mut header = Vec::with_capacity(12);
+    header.push(family);
+    header.push(prefix); // dst_len
+    header.push(0); // src_len
+    header.push(0); // tos
+    header.push(RT_TABLE_MAIN);
+    header.push(RTPROT_BOOT);
+    header.push(scope);
+    header.push(RTN_UNICAST);
+    header.extend_from_slice(&0u32.to_ne_bytes());
+    msg.push(&header);
+
+    // the default route has no destination
+    if prefix > 0 {
+        msg.attr(RTA_DST, &destination);
+    }
+    if let Some(gateway) = gateway {
+        msg.attr(RTA_GATEWAY, &address_bytes(gateway).1);
+    }
+    msg.attr(RTA_OIF, &index.to_ne_bytes());
+
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(buf: &[u8], pos: usize) -> u16 {
+        read_u16(&buf[pos..]).unwrap()
+    }
+
+    fn u32_at(buf: &[u8], pos: usize) -> u32 {
+        read_u32(&buf[pos..]).unwrap()
+    }
+
+    #[test]
+    fn test_link_message() {
+        let msg = link_message(3, 0, IFF_UP, None).finish(7);
+        assert_eq!(msg.len(), 32);
+        assert_eq!(u32_at(&msg, 0), 32);
+        assert_eq!(u16_at(&msg, 4), RTM_NEWLINK);
+        assert_eq!(u16_at(&msg, 6), NLM_F_REQUEST | NLM_F_ACK);
+        assert_eq!(u32_at(&msg, 8), 7);
+        assert_eq!(u32_at(&msg, 20), 3);
+        assert_eq!(u32_at(&msg, 24), 0);
+        assert_eq!(u32_at(&msg, 28), IFF_UP);
+
+        let msg = link_message(3, 0, 0, Some(&[0x02, 0, 0, 0, 0, 1])).finish(8);
+        assert_eq!(msg.len(), 44);
+        assert_eq!(u32_at(&msg, 0), 44);
+        assert_eq!(u16_at(&msg, 32), 10);
+        assert_eq!(u16_at(&msg, 34), IFLA_ADDRESS);
+        assert_eq!(&msg[36..42], &[0x02, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_route_message() {
+        let gateway = "192.0.2.1".parse().unwrap();
+        let msg = route_message(3, "0.0.0.0".parse().unwrap(), 0, Some(gateway)).finish(1);
+        assert_eq!(msg.len(), 44);
+        assert_eq!(u16_at(&msg, 4), RTM_NEWROUTE);
+        assert_eq!(&msg[16..24], &[libc::AF_INET as u8, 0, 0, 0, RT_TABLE_MAIN, RTPROT_BOOT, RT_SCOPE_UNIVERSE, RTN_UNICAST]);
+        assert_eq!(u16_at(&msg, 30), RTA_GATEWAY);
+        assert_eq!(&msg[32..36], &[192, 0, 2, 1]);
+        assert_eq!(u16_at(&msg, 38), RTA_OIF);
+        assert_eq!(u32_at(&msg, 40), 3);
+
+        let msg = route_message(3, "198.51.100.0".parse().unwrap(), 24, None).finish(2);
+        assert_eq!(msg.len(), 44);
+        assert_eq!(msg[17], 24);
+        assert_eq!(msg[22], RT_SCOPE_LINK);
+        assert_eq!(u16_at(&msg, 30), RTA_DST);
+        assert_eq!(&msg[32..36], &[198, 51, 100, 0]);
+        assert_eq!(u16_at(&msg, 38), RTA_OIF);
+    }
+
+    #[test]
+    fn test_address_header() {
+        let header = address_header(libc::AF_INET as u8, 24, 3);
+        assert_eq!(header.len(), 8);
+        assert_eq!(&header[..4], &[libc::AF_INET as u8, 24, 0, 0]);
+        assert_eq!(u32_at(&header, 4), 3);
+    }
+
+    fn error_message(seq: u32, errno: i32) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.extend_from_slice(&36u32.to_ne_bytes());
+        msg.extend_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        msg.extend_from_slice(&0u16.to_ne_bytes());
+        msg.extend_from_slice(&seq.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&errno.to_ne_bytes());
+        // the header of the request
+        msg.extend_from_slice(&[0; 16]);
+        msg
+    }
+
+    #[test]
+    fn test_parse_messages() {
+        let mut buf = error_message(1, 0);
+        buf.extend(error_message(2, -libc::EPERM));
+
+        let messages = parse_messages(&buf).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].msg_type, NLMSG_ERROR);
+        assert_eq!(messages[1].seq, 2);
+        assert_eq!(messages[1].payload.len(), 20);
+
+        assert!(error_result(&messages[0].payload).is_ok());
+        let err = error_result(&messages[1].payload).unwrap_err();
+        assert!(err.to_string().contains("not permitted"), "{}", err);
+
+        assert!(parse_messages(&buf[..20]).is_err());
+        assert!(error_result(&[0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_attributes() {
+        let nested = attribute(1, b"");
+        let mut buf = attribute(1, b"wlan0\0");
+        buf.extend(attribute(0x8000 | 45, &nested));
+        buf.extend(attribute(3, &7u32.to_ne_bytes()));
+
+        let attrs = attributes(&buf).collect::<Vec<_>>();
+        assert_eq!(attrs, vec![
+            (1, &b"wlan0\0"[..]),
+            (45, &nested[..]),
+            (3, &7u32.to_ne_bytes()[..]),
+        ]);
+        assert_eq!(attributes(attrs[1].1).collect::<Vec<_>>(), vec![(1, &b""[..])]);
+
+        // a truncated attribute ends the iteration
+        assert_eq!(attributes(&buf[..buf.len() - 2]).count(), 2);
+    }
+}
@@ -0,0 +1,577 @@
+use errors::{Result, ResultExt};
+use netlink::{self, Message, Socket, NLM_F_REQUEST, NLM_F_ACK, NLM_F_DUMP};
+use wifi::{self, Band, Network, Security};
+
+use nix;
+use nix::errno::Errno;
+use nix::net::if_::if_nametoindex;
+
+use std::io;
+use std::time::Duration;
+
+
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+const CTRL_ATTR_MCAST_GROUPS: u16 = 7;
+const CTRL_ATTR_MCAST_GRP_NAME: u16 = 1;
+const CTRL_ATTR_MCAST_GRP_ID: u16 = 2;
+
+const NL80211_CMD_GET_INTERFACE: u8 = 5;
+const NL80211_CMD_GET_SCAN: u8 = 32;
+const NL80211_CMD_TRIGGER_SCAN: u8 = 33;
+const NL80211_CMD_NEW_SCAN_RESULTS: u8 = 34;
+const NL80211_CMD_SCAN_ABORTED: u8 = 35;
+
+const NL80211_ATTR_IFINDEX: u16 = 3;
+const NL80211_ATTR_SCAN_SSIDS: u16 = 45;
+const NL80211_ATTR_BSS: u16 = 47;
+const NL80211_ATTR_SSID: u16 = 52;
+
+const NL80211_BSS_BSSID: u16 = 1;
+const NL80211_BSS_FREQUENCY: u16 = 2;
+const NL80211_BSS_CAPABILITY: u16 = 5;
+const NL80211_BSS_INFORMATION_ELEMENTS: u16 = 6;
+const NL80211_BSS_SIGNAL_MBM: u16 = 7;
+const NL80211_BSS_STATUS: u16 = 9;
+const NL80211_BSS_SEEN_MS_AGO: u16 = 10;
+const NL80211_BSS_BEACON_IES: u16 = 11;
+
+const NL80211_BSS_STATUS_ASSOCIATED: u32 = 1;
+
+const WLAN_CAPABILITY_IBSS: u16 = 0x02;
+const WLAN_CAPABILITY_PRIVACY: u16 = 0x10;
+const WLAN_EID_SSID: u8 = 0;
+
+/// A scan of all channels usually takes 3-5 seconds
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A base station from the scan results of the kernel
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Bss {
+    pub bssid: [u8; 6],
+    /// in MHz
+    pub frequency: u32,
+    pub capability: u16,
+    /// in dBm
+    pub signal: i32,
+    pub associated: bool,
+    pub seen_ms_ago: u32,
+    /// the information elements of the last probe response or beacon
+    pub ies: Vec<u8>,
+}
+
+/// Iterates over the information elements, stops at the first broken one
+pub fn information_elements(mut ies: &[u8]) -> impl Iterator<Item=(u8, &[u8])> + '_ {
+    ::std::iter::from_fn(move || {
+        if ies.len() < 2 {
+            return None;
+        }
+        let len = ies[1] as usize;
+        if ies.len() < 2 + len {
+            return None;
+        }
+
+        let ie = (ies[0], &ies[2..2 + len]);
+        ies = &ies[2 + len..];
+        Some(ie)
+    })
+}
+
+/// The channel number of a frequency in MHz
+pub fn channel(frequency: u32) -> u16 {
+    let channel = match frequency {
+        2484 => 14,
+        2412..=2472 => (frequency - 2407) / 5,
+        5955..=7115 => (frequency - 5950) / 5,
+        5000..=5925 => (frequency - 5000) / 5,
+        _ => 0,
+    };
+    channel as u16
+}
+
+impl Bss {
+    /// The ssid of hidden networks is empty, or zeroed out if the access
+    /// point only hides its content
+    pub fn is_hidden(&self) -> bool {
+        information_elements(&self.ies)
+            .find(|(id, _)| *id == WLAN_EID_SSID)
+            .map(|(_, ssid)| is_hidden_ssid(ssid))
+            .unwrap_or(true)
+    }
+
+    /// Hidden networks have an empty ssid
+    pub fn ssid(&self) -> String {
+        information_elements(&self.ies)
+            .find(|(id, _)| *id == WLAN_EID_SSID)
+            .map(|(_, ssid)| String::from_utf8_lossy(ssid).to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn bssid(&self) -> String {
+        self.bssid.iter()
+            .map(|x| format!("{:02X}", x))
+            .collect::<Vec<_>>()
+            .join(":")
+    }
+
+    pub fn channel(&self) -> u16 {
+        channel(self.frequency)
+    }
+
+    /// The same scale as the wext quality that iwlist reports
+    pub fn quality(&self) -> String {
+        let quality = (self.signal + 110).max(0).min(70);
+        format!("{}/70", quality)
+    }
+}
+
+impl<'a> From<&'a Bss> for Network {
+    fn from(bss: &Bss) -> Network {
+        let privacy = bss.capability & WLAN_CAPABILITY_PRIVACY != 0;
+        let mode = if bss.capability & WLAN_CAPABILITY_IBSS != 0 {
+            "Ad-Hoc"
+        } else {
+            "Master"
+        };
+
+        Network {
+            ap: bss.bssid(),
+            essid: bss.ssid(),
+            security: Security::from_ies(privacy, &bss.ies),
+            quality: bss.quality(),
+            signal: bss.signal,
+            channel: bss.channel(),
+            frequency: bss.frequency,
+            band: Band::from_frequency(bss.frequency),
+            mode: mode.to_string(),
+            bitrates: wifi::bitrates(&bss.ies),
+            last_beacon_ms: Some(u64::from(bss.seen_ms_ago)),
+            ies: bss.ies.clone(),
+        }
+    }
+}
+
+fn genl_message(family: u16, flags: u16, cmd: u8, version: u8) -> Message {
+    let mut msg = Message::new(family, flags);
+    // genlmsghdr
+    msg.push(&[cmd, version, 0, 0]);
+    msg
+}
+
+/// Split the genlmsghdr from the attributes, returns the command
+fn genl_payload(payload: &[u8]) -> Option<(u8, &[u8])> {
+    if payload.len() < 4 {
+        return None;
+    }
+    Some((payload[0], &payload[4..]))
+}
+
+/// The family id and multicast groups of a generic netlink family
+fn parse_family(attrs: &[u8]) -> Option<(u16, Vec<(String, u32)>)> {
+    let mut id = None;
+    let mut groups = Vec::new();
+
+    for (attr_type, data) in netlink::attributes(attrs) {
+        match attr_type {
+            CTRL_ATTR_FAMILY_ID => id = netlink::read_u16(data),
+            CTRL_ATTR_MCAST_GROUPS => {
+                for (_, group) in netlink::attributes(data) {
+                    let mut name = None;
+                    let mut group_id = None;
+                    for (attr_type, data) in netlink::attributes(group) {
+                        match attr_type {
+                            CTRL_ATTR_MCAST_GRP_NAME => name = Some(cstr(data)),
+                            CTRL_ATTR_MCAST_GRP_ID => group_id = netlink::read_u32(data),
+                            _ => (),
+                        }
+                    }
+                    if let (Some(name), Some(group_id)) = (name, group_id) {
+                        groups.push((name, group_id));
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    id.map(|id| (id, groups))
+}
+
+fn cstr(data: &[u8]) -> String {
+    let data = match data.iter().position(|x| *x == 0) {
+        Some(end) => &data[..end],
+        None => data,
+    };
+    String::from_utf8_lossy(data).to_string()
+}
+
+fn parse_bss(attrs: &[u8]) -> Option<Bss> {
+    let mut bss = Bss::default();
+    let mut has_bssid = false;
+    let mut beacon_ies = None;
+
+    for (attr_type, data) in netlink::attributes(attrs) {
+        match attr_type {
+            NL80211_BSS_BSSID if data.len() == 6 => {
+                bss.bssid.copy_from_slice(data);
+                has_bssid = true;
+            },
+            NL80211_BSS_FREQUENCY => bss.frequency = netlink::read_u32(data).unwrap_or(0),
+            NL80211_BSS_CAPABILITY => bss.capability = netlink::read_u16(data).unwrap_or(0),
+            NL80211_BSS_INFORMATION_ELEMENTS => bss.ies = data.to_vec(),
+            NL80211_BSS_BEACON_IES => beacon_ies = Some(data),
+            // mBm is 1/100 dBm
+            NL80211_BSS_SIGNAL_MBM => bss.signal = netlink::read_u32(data).unwrap_or(0) as i32 / 100,
+            NL80211_BSS_STATUS => bss.associated = netlink::read_u32(data) == Some(NL80211_BSS_STATUS_ASSOCIATED),
+            NL80211_BSS_SEEN_MS_AGO => bss.seen_ms_ago = netlink::read_u32(data).unwrap_or(0),
+            _ => (),
+        }
+    }
+
+    // some drivers only report the elements of the beacon
+    if bss.ies.is_empty() {
+        if let Some(ies) = beacon_ies {
+            bss.ies = ies.to_vec();
+        }
+    }
+
+    if has_bssid {
+        Some(bss)
+    } else {
+        None
+    }
+}
+
+fn is_hidden_ssid(ssid: &[u8]) -> bool {
+    ssid.iter().all(|x| *x == 0)
+}
+
+/// The ssid from the reply of NL80211_CMD_GET_INTERFACE, it's only set if
+/// the interface is associated
+fn parse_interface_ssid(messages: &[netlink::Received]) -> Option<String> {
+    messages.iter()
+        .filter_map(|msg| genl_payload(&msg.payload))
+        .flat_map(|(_, attrs)| netlink::attributes(attrs))
+        .find(|(attr_type, _)| *attr_type == NL80211_ATTR_SSID)
+        .filter(|(_, ssid)| !is_hidden_ssid(ssid))
+        .map(|(_, ssid)| String::from_utf8_lossy(ssid).to_string())
+}
+
+/// Parse the messages of a NL80211_CMD_GET_SCAN dump
+fn parse_scan_results(messages: &[netlink::Received]) -> Vec<Bss> {
+    messages.iter()
+        .filter_map(|msg| genl_payload(&msg.payload))
+        .flat_map(|(_, attrs)| netlink::attributes(attrs))
+        .filter(|(attr_type, _)| *attr_type == NL80211_ATTR_BSS)
+        .filter_map(|(_, bss)| parse_bss(bss))
+        .collect()
+}
+
+/// A connection to the nl80211 interface of the kernel
+#[derive(Debug)]
+pub struct Nl80211 {
+    socket: Socket,
+    family: u16,
+    scan_group: Option<u32>,
+}
+
+impl Nl80211 {
+    pub fn connect() -> Result<Nl80211> {
+        let mut socket = Socket::generic()?;
+
+        let mut msg = genl_message(GENL_ID_CTRL, NLM_F_REQUEST, CTRL_CMD_GETFAMILY, 1);
+        msg.attr(CTRL_ATTR_FAMILY_NAME, b"nl80211\0");
+        let replies = socket.request(msg)
+            .context("failed to resolve nl80211")?;
+
+        let (family, groups) = replies.iter()
+            .filter_map(|msg| genl_payload(&msg.payload))
+            .filter_map(|(_, attrs)| parse_family(attrs))
+            .next()
+            .ok_or_else(|| format_err!("nl80211 is not available"))?;
+        let scan_group = groups.into_iter()
+            .find(|(name, _)| name == "scan")
+            .map(|(_, id)| id);
+
+        Ok(Nl80211 {
+            socket,
+            family,
+            scan_group,
+        })
+    }
+
+    /// The results of previous scans, this doesn't need any privileges
+    pub fn scan_results(&mut self, index: u32) -> Result<Vec<Bss>> {
+        let mut msg = genl_message(self.family, NLM_F_REQUEST | NLM_F_DUMP, NL80211_CMD_GET_SCAN, 0);
+        msg.attr(NL80211_ATTR_IFINDEX, &index.to_ne_bytes());
+        let replies = self.socket.request(msg)?;
+        Ok(parse_scan_results(&replies))
+    }
+
+    /// The ssid we're associated with, this is also known for hidden
+    /// networks because it's taken from the association instead of the
+    /// beacons
+    pub fn interface_ssid(&mut self, index: u32) -> Result<Option<String>> {
+        let mut msg = genl_message(self.family, NLM_F_REQUEST | NLM_F_ACK, NL80211_CMD_GET_INTERFACE, 0);
+        msg.attr(NL80211_ATTR_IFINDEX, &index.to_ne_bytes());
+        let replies = self.socket.request(msg)?;
+        Ok(parse_interface_ssid(&replies))
+    }
+
+    /// Start a new scan and wait until it's done
+    pub fn trigger_scan(&mut self, index: u32) -> Result<()> {
+        let scan_group = match self.scan_group {
+            Some(group) => group,
+            None => bail!("nl80211 has no scan multicast group"),
+        };
+
+        // the events are received on their own socket so they can't get
+        // mixed up with the replies of our requests
+        let mut events = Socket::generic()?;
+        events.add_membership(scan_group)?;
+        events.set_timeout(SCAN_TIMEOUT)?;
+
+        let mut msg = genl_message(self.family, NLM_F_REQUEST | NLM_F_ACK, NL80211_CMD_TRIGGER_SCAN, 0);
+        msg.attr(NL80211_ATTR_IFINDEX, &index.to_ne_bytes());
+        // a single wildcard ssid for an active scan
+        msg.attr(NL80211_ATTR_SCAN_SSIDS, &netlink::attribute(1, b""));
+        self.socket.request(msg)?;
+
+        loop {
+            let messages = match events.recv() {
+                Ok(messages) => messages,
+                Err(err) => {
+                    if let Some(&nix::Error::Sys(Errno::EAGAIN)) = err.downcast_ref() {
+                        bail!("scan timed out");
+                    }
+                    return Err(err);
+                },
+            };
+
+            for msg in messages {
+                let (cmd, attrs) = match genl_payload(&msg.payload) {
+                    Some(payload) => payload,
+                    None => continue,
+                };
+
+                let ifindex = netlink::attributes(attrs)
+                    .find(|(attr_type, _)| *attr_type == NL80211_ATTR_IFINDEX)
+                    .and_then(|(_, data)| netlink::read_u32(data));
+                if ifindex != Some(index) {
+                    continue;
+                }
+
+                match cmd {
+                    NL80211_CMD_NEW_SCAN_RESULTS => return Ok(()),
+                    NL80211_CMD_SCAN_ABORTED => bail!("scan has been aborted"),
+                    _ => (),
+                }
+            }
+        }
+    }
+}
+
+/// Scan for networks, if we aren't allowed to start a scan or the interface
+/// is busy the results of the last scan are used
+pub fn scan(interface: &str) -> Result<Vec<Network>> {
+    let index = if_nametoindex(interface)?;
+    let mut nl = Nl80211::connect()?;
+
+    if let Err(err) = nl.trigger_scan(index) {
+        let busy = match err.downcast_ref::<io::Error>().and_then(|err| err.raw_os_error()) {
+            Some(errno) => errno == Errno::EPERM as i32 || errno == Errno::EBUSY as i32,
+            None => false,
+        };
+        if busy {
+            info!("can't start a scan on {} ({}), using previous results", interface, err);
+        } else {
+            return Err(err);
+        }
+    }
+
+    let networks = nl.scan_results(index)?
+        .iter()
+        .map(Network::from)
+        .collect();
+    Ok(networks)
+}
+
+/// The base station we're associated with, if any
+pub fn link(interface: &str) -> Result<Option<Bss>> {
+    let index = if_nametoindex(interface)?;
+    let mut nl = Nl80211::connect()?;
+
+    let bss = nl.scan_results(index)?
+        .into_iter()
+        .find(|bss| bss.associated);
+    Ok(bss)
+}
+
+/// The ssid of the interface, if it's associated
+pub fn ssid(interface: &str) -> Result<Option<String>> {
+    let index = if_nametoindex(interface)?;
+    let mut nl = Nl80211::connect()?;
+    nl.interface_ssid(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use netlink::attribute;
+    use wifi::{AuthSuite, Cipher, SecurityMode};
+
+    // the first elements of "Cell 01" in tests/iwlist.txt and the RSN
+    // element that iwlist decoded
+    const IES: &str = "00096675782D61646D696E 010882840B0C12161824 030101 \
+                       30140100000FAC040100000FAC040100000FAC020C00";
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s = s.replace(' ', "");
+        (0..s.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn bss_attr(bssid: [u8; 6], frequency: u32, mbm: i32, status: Option<u32>) -> Vec<u8> {
+        let mut bss = attribute(NL80211_BSS_BSSID, &bssid);
+        bss.extend(attribute(NL80211_BSS_FREQUENCY, &frequency.to_ne_bytes()));
+        bss.extend(attribute(NL80211_BSS_CAPABILITY, &0x0411u16.to_ne_bytes()));
+        bss.extend(attribute(NL80211_BSS_INFORMATION_ELEMENTS, &hex(IES)));
+        bss.extend(attribute(NL80211_BSS_SIGNAL_MBM, &mbm.to_ne_bytes()));
+        bss.extend(attribute(NL80211_BSS_SEEN_MS_AGO, &1200u32.to_ne_bytes()));
+        if let Some(status) = status {
+            bss.extend(attribute(NL80211_BSS_STATUS, &status.to_ne_bytes()));
+        }
+        bss
+    }
+
+    fn scan_message(bss: &[u8]) -> netlink::Received {
+        let mut payload = vec![NL80211_CMD_NEW_SCAN_RESULTS, 1, 0, 0];
+        payload.extend(attribute(NL80211_ATTR_IFINDEX, &3u32.to_ne_bytes()));
+        payload.extend(attribute(0x8000 | NL80211_ATTR_BSS, bss));
+        netlink::Received {
+            msg_type: 0x1c,
+            flags: 2,
+            seq: 1,
+            payload,
+        }
+    }
+
+    #[test]
+    fn test_channel() {
+        assert_eq!(channel(2412), 1);
+        assert_eq!(channel(2472), 13);
+        assert_eq!(channel(2484), 14);
+        assert_eq!(channel(5180), 36);
+        assert_eq!(channel(5825), 165);
+        assert_eq!(channel(5955), 1);
+        assert_eq!(channel(60480), 0);
+    }
+
+    #[test]
+    fn test_information_elements() {
+        let ies = hex(IES);
+        let ies = information_elements(&ies).collect::<Vec<_>>();
+        assert_eq!(ies.len(), 4);
+        assert_eq!(ies[0], (0, &b"fux-admin"[..]));
+        assert_eq!(ies[2], (3, &[1][..]));
+
+        // a truncated element is ignored
+        assert_eq!(information_elements(&[0, 9, b'f', b'u']).count(), 0);
+    }
+
+    #[test]
+    fn test_parse_scan_results() {
+        let messages = vec![
+            scan_message(&bss_attr([0xd8, 0xc7, 0xc8, 0xeb, 0x02, 0xf0], 2412, -7900, None)),
+            scan_message(&bss_attr([0xd8, 0xc7, 0xc8, 0xeb, 0x02, 0xf1], 5180, -4500, Some(NL80211_BSS_STATUS_ASSOCIATED))),
+            // broken entries are skipped
+            scan_message(&attribute(NL80211_BSS_FREQUENCY, &2412u32.to_ne_bytes())),
+        ];
+
+        let results = parse_scan_results(&messages);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].frequency, 2412);
+        assert_eq!(results[0].seen_ms_ago, 1200);
+        assert!(!results[0].associated);
+        assert!(results[1].associated);
+
+        let network = Network::from(&results[0]);
+        assert_eq!(network.ap, "D8:C7:C8:EB:02:F0");
+        assert_eq!(network.essid, "fux-admin");
+        assert_eq!(network.security.mode, SecurityMode::Wpa2);
+        assert_eq!(network.security.auth_suites(), vec![AuthSuite::Psk]);
+        assert_eq!(network.security.ciphers(), vec![Cipher::Ccmp]);
+        assert_eq!(network.quality, "31/70");
+        assert_eq!(network.signal, -79);
+        assert_eq!(network.channel, 1);
+        assert_eq!(network.frequency, 2412);
+        assert_eq!(network.band, Some(Band::Ghz2));
+        assert_eq!(network.mode, "Master");
+        assert_eq!(network.bitrates, vec![1.0, 2.0, 5.5, 6.0, 9.0, 11.0, 12.0, 18.0]);
+        assert_eq!(network.last_beacon_ms, Some(1200));
+        assert_eq!(network.ies, hex(IES));
+
+        let network = Network::from(&results[1]);
+        assert_eq!(network.channel, 36);
+        assert_eq!(network.band, Some(Band::Ghz5));
+        assert_eq!(network.quality, "65/70");
+    }
+
+    #[test]
+    fn test_hidden_ssid() {
+        let mut bss = Bss::default();
+        assert!(bss.is_hidden());
+
+        bss.ies = hex("0000 010882840B0C12161824");
+        assert!(bss.is_hidden());
+        assert_eq!(bss.ssid(), "");
+
+        bss.ies = hex("0003000000 010882840B0C12161824");
+        assert!(bss.is_hidden());
+
+        bss.ies = hex(IES);
+        assert!(!bss.is_hidden());
+    }
+
+    #[test]
+    fn test_parse_interface_ssid() {
+        let message = |attrs: Vec<u8>| {
+            let mut payload = vec![7, 1, 0, 0];
+            payload.extend(attribute(NL80211_ATTR_IFINDEX, &3u32.to_ne_bytes()));
+            payload.extend(attrs);
+            netlink::Received {
+                msg_type: 0x1c,
+                flags: 0,
+                seq: 1,
+                payload,
+            }
+        };
+
+        let messages = vec![message(attribute(NL80211_ATTR_SSID, b"hidden-admin"))];
+        assert_eq!(parse_interface_ssid(&messages), Some("hidden-admin".to_string()));
+
+        // not associated
+        let messages = vec![message(Vec::new())];
+        assert_eq!(parse_interface_ssid(&messages), None);
+
+        let messages = vec![message(attribute(NL80211_ATTR_SSID, &[0, 0, 0]))];
+        assert_eq!(parse_interface_ssid(&messages), None);
+    }
+
+    #[test]
+    fn test_parse_family() {
+        let mut group = attribute(CTRL_ATTR_MCAST_GRP_NAME, b"scan\0");
+        group.extend(attribute(CTRL_ATTR_MCAST_GRP_ID, &4u32.to_ne_bytes()));
+        let groups = attribute(1, &group);
+
+        let mut attrs = attribute(CTRL_ATTR_FAMILY_NAME, b"nl80211\0");
+        attrs.extend(attribute(CTRL_ATTR_FAMILY_ID, &0x1cu16.to_ne_bytes()));
+        attrs.extend(attribute(CTRL_ATTR_MCAST_GROUPS, &groups));
+
+        assert_eq!(parse_family(&attrs), Some((0x1c, vec![("scan".to_string(), 4)])));
+        assert_eq!(parse_family(&attribute(CTRL_ATTR_FAMILY_NAME, b"nl80211\0")), None);
+    }
+}
@@ -1,8 +1,9 @@
 use errors::{Result, Error};
 use scripts::ctx::State;
-use dns::DnsResolver;
+use dns::{self, DnsResolver};
+use structs::LuaList;
 use web::HttpClient;
-use hlua;
+use hlua::{self, AnyLuaValue};
 use std::sync::Arc;
 
 pub fn dns<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hlua::Lua, state: Arc<State<C, R>>) {
@@ -15,9 +16,25 @@ pub fn dns<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hlua::Lu
     }))
 }
 
+pub fn dns_query<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hlua::Lua, state: Arc<State<C, R>>) {
+    lua.set("dns_query", hlua::function2(move |name: String, record_type: String| -> Result<AnyLuaValue> {
+        let record_type = dns::parse_record_type(&record_type)
+            .map_err(|err| state.set_error(err))?;
+        let records = state.resolver.query(&name, record_type)
+            .map_err(|err| state.set_error(err))?;
+
+        let mut list = LuaList::new();
+        for record in records {
+            list.push(record);
+        }
+        Ok(list.into())
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use scripts::loader::Loader;
+    use scripts::network::DecapInfo;
 
     #[test]
     #[ignore]
@@ -31,6 +48,23 @@ mod tests {
             print(x)
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
+    }
+
+    #[test]
+    #[ignore]
+    fn verify_query() {
+        let script = Loader::init_default(r#"
+        descr = "verify_query"
+
+        function detect() end
+        function decap()
+            x = dns_query("_xmpp-server._tcp.jabber.org", "SRV")
+            print(x)
+            x = dns_query("1.1.1.1", "PTR")
+            print(x)
+        end
+        "#).expect("failed to load script");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 }
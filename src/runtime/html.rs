@@ -28,24 +28,8 @@ pub fn html_select_list<C: HttpClient + 'static, R: DnsResolver + 'static>(lua:
 
 pub fn html_meta_refresh<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hlua::Lua, state: Arc<State<C, R>>) {
     lua.set("html_meta_refresh", hlua::function1(move |html: String| -> Result<String> {
-        let meta = html::html_select(&html, "meta[http-equiv=\"refresh\"]")
-            .map_err(|err| state.set_error(err))?;
-
-        let content = match meta.attrs.get("content") {
-            Some(content) => content,
-            None => return Err(state.set_error(format_err!("content attribute not found"))),
-        };
-
-        let url = match content.find(";") {
-            Some(idx) => content[idx+1..].trim(),
-            None => return Err(state.set_error(format_err!("content has no attributes"))),
-        };
-
-        if url.starts_with("url=") {
-            Ok(url[4..].to_string())
-        } else {
-            Err(state.set_error(format_err!("url= attribute not found")))
-        }
+        html::meta_refresh(&html)
+            .ok_or_else(|| state.set_error(format_err!("meta refresh with url not found")))
     }))
 }
 
@@ -60,6 +44,7 @@ pub fn html_form<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hl
 #[cfg(test)]
 mod tests {
     use scripts::loader::Loader;
+    use scripts::network::DecapInfo;
 
     #[test]
     fn verify_html_select() {
@@ -81,7 +66,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -107,7 +92,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -139,7 +124,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -153,7 +138,7 @@ mod tests {
             if last_err() then return end
         end
         "#).expect("failed to load script");
-        let x = script.decap();
+        let x = script.decap(&DecapInfo::default());
         assert!(x.is_err());
     }
 
@@ -200,6 +185,6 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 }
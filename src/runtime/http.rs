@@ -45,6 +45,7 @@ pub fn http_send<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hl
 #[cfg(test)]
 mod tests {
     use scripts::loader::Loader;
+    use scripts::network::DecapInfo;
 
     #[test]
     #[ignore]
@@ -65,7 +66,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -101,7 +102,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -135,6 +136,6 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 }
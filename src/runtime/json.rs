@@ -26,6 +26,7 @@ pub fn json_encode<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut
 #[cfg(test)]
 mod tests {
     use scripts::loader::Loader;
+    use scripts::network::DecapInfo;
 
     #[test]
     fn verify_json_encode() {
@@ -46,7 +47,7 @@ mod tests {
             })
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -69,7 +70,7 @@ mod tests {
             json_decode(x)
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -82,7 +83,7 @@ mod tests {
             json_decode("{\"almost_one\":0.9999,\"data\":{\"password\":\"fizz\",\"user\":\"bar\"},\"hello\":\"world\",\"list\":[1,3,3,7]}")
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -95,7 +96,7 @@ mod tests {
             json_decode("{\"almost_one\":0.9999,\"data\":{\"password\":\"fizz\",\"user\":\"bar\"}}}}}}}}}")
         end
         "#).expect("failed to load script");
-        let r = script.decap();
+        let r = script.decap(&DecapInfo::default());
         assert!(r.is_err());
     }
 }
@@ -12,3 +12,5 @@ mod print;
 pub use self::print::*;
 mod url;
 pub use self::url::*;
+mod wifi;
+pub use self::wifi::*;
@@ -61,6 +61,7 @@ pub fn url_parse<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hl
 #[cfg(test)]
 mod tests {
     use scripts::loader::Loader;
+    use scripts::network::DecapInfo;
 
     #[test]
     fn verify_relative_path() {
@@ -76,7 +77,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -93,7 +94,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -110,7 +111,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -127,7 +128,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -144,7 +145,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -161,7 +162,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -178,7 +179,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -195,7 +196,7 @@ mod tests {
             end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -216,7 +217,7 @@ mod tests {
             if url['params'] ~= nil then return 'params' end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 
     #[test]
@@ -239,6 +240,6 @@ mod tests {
             if url['params']['y[]'] ~= "asdf" then return 'params' end
         end
         "#).expect("failed to load script");
-        script.decap().expect("decap failed");
+        script.decap(&DecapInfo::default()).expect("decap failed");
     }
 }
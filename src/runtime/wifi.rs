@@ -0,0 +1,100 @@
+use errors::Result;
+use scripts::ctx::State;
+use dns::DnsResolver;
+use web::HttpClient;
+use wifi::{self, WifiCredentials};
+use hlua;
+use std::sync::Arc;
+
+pub fn wifi_set_open<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hlua::Lua, state: Arc<State<C, R>>) {
+    lua.set("wifi_set_open", hlua::function0(move || {
+        state.set_credentials(WifiCredentials::Open);
+    }))
+}
+
+pub fn wifi_set_psk<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hlua::Lua, state: Arc<State<C, R>>) {
+    lua.set("wifi_set_psk", hlua::function1(move |psk: String| -> Result<()> {
+        if !wifi::is_valid_passphrase(&psk) {
+            return Err(state.set_error(format_err!("psk needs to be 8 to 63 printable ascii characters")));
+        }
+
+        state.set_credentials(WifiCredentials::Psk(psk));
+        Ok(())
+    }))
+}
+
+pub fn wifi_set_identity<C: HttpClient + 'static, R: DnsResolver + 'static>(lua: &mut hlua::Lua, state: Arc<State<C, R>>) {
+    lua.set("wifi_set_identity", hlua::function2(move |identity: String, password: String| {
+        state.set_credentials(WifiCredentials::Identity {
+            identity,
+            password,
+        });
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use scripts::loader::Loader;
+    use scripts::network::DetectInfo;
+    use wifi::WifiCredentials;
+
+    #[test]
+    fn verify_wifi_set_psk() {
+        let script = Loader::init_default(r#"
+        descr = "verify_wifi_set_psk"
+
+        function detect() end
+        function connect(network)
+            wifi_set_psk('hunter2' .. network['ssid'])
+        end
+        function decap() end
+        "#).expect("failed to load script");
+        let credentials = script.connect(&DetectInfo::new("hunter2")).expect("connect failed");
+        assert_eq!(credentials, Some(WifiCredentials::Psk("hunter2hunter2".into())));
+    }
+
+    #[test]
+    fn verify_wifi_set_psk_invalid() {
+        let script = Loader::init_default(r#"
+        descr = "verify_wifi_set_psk_invalid"
+
+        function detect() end
+        function connect(network)
+            wifi_set_psk('hunter2\nhunter2')
+        end
+        function decap() end
+        "#).expect("failed to load script");
+        let r = script.connect(&DetectInfo::new("example network"));
+        assert!(r.is_err());
+    }
+
+    #[test]
+    fn verify_wifi_set_identity() {
+        let script = Loader::init_default(r#"
+        descr = "verify_wifi_set_identity"
+
+        function detect() end
+        function connect(network)
+            wifi_set_identity('user@example.com', 'hunter2')
+        end
+        function decap() end
+        "#).expect("failed to load script");
+        let credentials = script.connect(&DetectInfo::new("eduroam")).expect("connect failed");
+        assert_eq!(credentials, Some(WifiCredentials::Identity {
+            identity: "user@example.com".into(),
+            password: "hunter2".into(),
+        }));
+    }
+
+    #[test]
+    fn verify_connect_undefined() {
+        let script = Loader::init_default(r#"
+        descr = "verify_connect_undefined"
+
+        function detect() end
+        function decap() end
+        "#).expect("failed to load script");
+        let credentials = script.connect(&DetectInfo::new("example network")).expect("connect failed");
+        assert_eq!(credentials, None);
+    }
+}
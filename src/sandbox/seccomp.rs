@@ -178,6 +178,9 @@ pub fn zmq_stage1() -> Result<()> {
     ctx.allow_syscall(Syscall::lseek)?;
     ctx.allow_syscall(Syscall::openat)?; // needed for stage1
     ctx.allow_syscall(Syscall::stat)?; // needed for stage1
+    #[cfg(not(target_arch = "aarch64"))]
+    ctx.allow_syscall(Syscall::mkdir)?; // needed for stage1
+    ctx.allow_syscall(Syscall::mkdirat)?; // needed for stage1
     ctx.allow_syscall(Syscall::fstat)?; // needed for stage1
     #[cfg(target_arch = "arm")]
     ctx.allow_syscall(Syscall::fstat64)?; // needed for stage1
@@ -251,6 +254,10 @@ pub fn zmq_stage3() -> Result<()> {
     ctx.allow_syscall(Syscall::clock_gettime)?;
     ctx.allow_syscall(Syscall::gettimeofday)?;
     ctx.allow_syscall(Syscall::restart_syscall)?;
+    ctx.allow_syscall(Syscall::lseek)?; // needed for the knowledge base
+    ctx.allow_syscall(Syscall::ftruncate)?; // needed for the knowledge base
+    #[cfg(target_arch = "arm")]
+    ctx.allow_syscall(Syscall::ftruncate64)?; // needed for the knowledge base
 
     ctx.load()?;
 
@@ -279,6 +286,8 @@ pub fn dns_stage1() -> Result<()> {
     ctx.allow_syscall(Syscall::getsockname)?;
     ctx.allow_syscall(Syscall::lseek)?;
     ctx.allow_syscall(Syscall::bind)?;
+    ctx.allow_syscall(Syscall::listen)?;
+    ctx.allow_syscall(Syscall::setsockopt)?;
     ctx.allow_syscall(Syscall::ioctl)?;
     ctx.allow_syscall(Syscall::mmap)?; // needed for stage1
     ctx.allow_syscall(Syscall::mprotect)?; // needed for stage1
@@ -303,6 +312,13 @@ pub fn dns_stage1() -> Result<()> {
     ctx.allow_syscall(Syscall::exit_group)?; // needed for stage1
     ctx.allow_syscall(Syscall::prctl)?; // needed for stage1
     ctx.allow_syscall(Syscall::seccomp)?; // needed for stage1
+    ctx.allow_syscall(Syscall::eventfd2)?; // needed for the ipc client
+    ctx.allow_syscall(Syscall::exit)?; // needed for the tcp client threads
+    ctx.allow_syscall(Syscall::getpeername)?; // needed for the ipc client
+    #[cfg(not(target_arch = "aarch64"))]
+    ctx.allow_syscall(Syscall::poll)?; // needed for the ipc client
+    #[cfg(target_arch = "aarch64")]
+    ctx.allow_syscall(Syscall::ppoll)?; // needed for the ipc client
 
     ctx.load()?;
 
@@ -331,6 +347,21 @@ pub fn dns_stage3() -> Result<()> {
     ctx.allow_syscall(Syscall::getsockname)?;
     ctx.allow_syscall(Syscall::bind)?;
     ctx.allow_syscall(Syscall::ioctl)?;
+    ctx.allow_syscall(Syscall::accept4)?; // needed for the tcp server
+    ctx.allow_syscall(Syscall::setsockopt)?; // needed for the tcp server
+    ctx.allow_syscall(Syscall::clone)?; // needed for the tcp thread
+    ctx.allow_syscall(Syscall::mmap)?; // needed for the tcp thread
+    ctx.allow_syscall(Syscall::mprotect)?; // needed for the tcp thread
+    ctx.allow_syscall(Syscall::munmap)?; // needed for the tcp thread
+    ctx.allow_syscall(Syscall::madvise)?; // needed for the tcp thread
+    ctx.allow_syscall(Syscall::set_robust_list)?; // needed for the tcp thread
+    ctx.allow_syscall(Syscall::sigaltstack)?; // needed for the tcp thread
+    ctx.allow_syscall(Syscall::exit)?; // needed for the tcp client threads
+    ctx.allow_syscall(Syscall::nanosleep)?; // needed for the status thread
+    #[cfg(not(target_arch = "aarch64"))]
+    ctx.allow_syscall(Syscall::poll)?; // needed for the ipc client
+    #[cfg(target_arch = "aarch64")]
+    ctx.allow_syscall(Syscall::ppoll)?; // needed for the ipc client
 
     ctx.load()?;
 
@@ -4,16 +4,20 @@ use errors::{Result, Error};
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 
+use decap::WalledGardenFingerprint;
 use dns::DnsResolver;
 use runtime;
+use scripts::network::{DetectInfo, DecapInfo};
 use web::HttpClient;
 use web::structs::{HttpSession, HttpRequest, RequestOptions};
+use wifi::WifiCredentials;
 
 
 #[derive(Debug, Clone)]
 pub struct State<C: HttpClient, R: DnsResolver> {
     error: Arc<Mutex<Option<Error>>>,
     http_sessions: Arc<Mutex<HashMap<String, HttpSession>>>,
+    credentials: Arc<Mutex<Option<WifiCredentials>>>,
     pub http: Arc<C>,
     pub resolver: Arc<R>,
 }
@@ -23,6 +27,7 @@ impl<C: HttpClient, R: DnsResolver> State<C, R> {
         State {
             error: Arc::new(Mutex::new(None)),
             http_sessions: Arc::new(Mutex::new(HashMap::new())),
+            credentials: Arc::new(Mutex::new(None)),
             http,
             resolver,
         }
@@ -54,6 +59,11 @@ impl<C: HttpClient, R: DnsResolver> State<C, R> {
         HttpRequest::new(&session, method, url, options)
     }
 
+    pub fn set_credentials(&self, credentials: WifiCredentials) {
+        let mut mtx = self.credentials.lock().unwrap();
+        *mtx = Some(credentials);
+    }
+
     pub fn register_in_jar(&self, session: &str, key: String, value: String) {
         let mut mtx = self.http_sessions.lock().unwrap();
         if let Some(session) = mtx.get_mut(session) {
@@ -67,6 +77,7 @@ impl<C: HttpClient, R: DnsResolver> State<C, R> {
 pub struct Script<C: HttpClient, R: DnsResolver> {
     descr: String,
     code: String,
+    healthcheck_interval: Option<u64>,
     http: Arc<C>,
     resolver: Arc<R>,
 }
@@ -77,6 +88,7 @@ fn ctx<'a, C: HttpClient + 'static, R: DnsResolver + 'static>(http: Arc<C>, reso
     let state = Arc::new(State::new(http, resolver));
 
     runtime::dns(&mut lua, state.clone());
+    runtime::dns_query(&mut lua, state.clone());
     runtime::html_form(&mut lua, state.clone());
     runtime::html_meta_refresh(&mut lua, state.clone());
     runtime::html_select(&mut lua, state.clone());
@@ -90,6 +102,9 @@ fn ctx<'a, C: HttpClient + 'static, R: DnsResolver + 'static>(http: Arc<C>, reso
     runtime::print(&mut lua, state.clone());
     runtime::url_join(&mut lua, state.clone());
     runtime::url_parse(&mut lua, state.clone());
+    runtime::wifi_set_identity(&mut lua, state.clone());
+    runtime::wifi_set_open(&mut lua, state.clone());
+    runtime::wifi_set_psk(&mut lua, state.clone());
 
     (lua, state)
 }
@@ -119,9 +134,20 @@ impl<C: HttpClient + 'static, R: DnsResolver + 'static> Script<C, R> {
         ensure_function_exists(&mut lua, "detect")?;
         ensure_function_exists(&mut lua, "decap")?;
 
+        let healthcheck_interval: Option<f64> = lua.get("healthcheck_interval");
+        let healthcheck_interval = match healthcheck_interval {
+            // casting those to an integer is undefined behavior
+            Some(x) if !x.is_finite() || x <= 0.0 || x >= u64::max_value() as f64 => {
+                bail!("invalid healthcheck_interval: {}", x);
+            },
+            Some(x) => Some(x.ceil() as u64),
+            None => None,
+        };
+
         Ok(Script {
             descr,
             code,
+            healthcheck_interval,
 
             http,
             resolver,
@@ -132,7 +158,11 @@ impl<C: HttpClient + 'static, R: DnsResolver + 'static> Script<C, R> {
         &self.descr
     }
 
-    pub fn detect_network(&self, network: &str) -> Result<bool> {
+    pub fn healthcheck_interval(&self) -> Option<u64> {
+        self.healthcheck_interval
+    }
+
+    pub fn detect_network(&self, network: &DetectInfo) -> Result<bool> {
         let (mut lua, state) = ctx(self.http.clone(), self.resolver.clone());
         lua.execute::<()>(&self.code)?;
 
@@ -141,6 +171,7 @@ impl<C: HttpClient + 'static, R: DnsResolver + 'static> Script<C, R> {
             None => bail!("function undefined: detect"),
         };
 
+        let network: hlua::AnyLuaValue = network.clone().into();
         let result: hlua::AnyLuaValue = match detect.call_with_args((network, )) {
             Ok(res) => res,
             Err(err) => {
@@ -160,7 +191,108 @@ impl<C: HttpClient + 'static, R: DnsResolver + 'static> Script<C, R> {
         }
     }
 
-    pub fn decap(&self) -> Result<()> {
+    pub fn connect(&self, network: &DetectInfo) -> Result<Option<WifiCredentials>> {
+        let (mut lua, state) = ctx(self.http.clone(), self.resolver.clone());
+        lua.execute::<()>(&self.code)?;
+
+        // this function is optional
+        let mut connect: hlua::LuaFunction<_> = match lua.get("connect") {
+            Some(func) => func,
+            None => return Ok(None),
+        };
+
+        let network: hlua::AnyLuaValue = network.clone().into();
+        let result: hlua::AnyLuaValue = match connect.call_with_args((network, )) {
+            Ok(res) => res,
+            Err(err) => {
+                bail!(format!("execution failed: {:?}", err));
+            },
+        };
+
+        if let Some(err) = state.error.lock().unwrap().take() {
+            return Err(err);
+        }
+
+        use hlua::AnyLuaValue::*;
+        match result {
+            LuaNil => (),
+            LuaBoolean(true) => (),
+            LuaBoolean(false) => bail!("script returned false"),
+            LuaString(x) => bail!(format!("error: {:?}", x)),
+            x => bail!(format!("lua returned wrong type: {:?}", x)),
+        }
+
+        let credentials = state.credentials.lock().unwrap().take();
+        Ok(credentials)
+    }
+
+    pub fn detect_portal(&self, fingerprint: &WalledGardenFingerprint) -> Result<bool> {
+        let (mut lua, state) = ctx(self.http.clone(), self.resolver.clone());
+        lua.execute::<()>(&self.code)?;
+
+        // this function is optional
+        let mut detect_portal: hlua::LuaFunction<_> = match lua.get("detect_portal") {
+            Some(func) => func,
+            None => return Ok(false),
+        };
+
+        let fingerprint: hlua::AnyLuaValue = fingerprint.clone().into();
+        let result: hlua::AnyLuaValue = match detect_portal.call_with_args((fingerprint, )) {
+            Ok(res) => res,
+            Err(err) => {
+                bail!(format!("execution failed: {:?}", err));
+            },
+        };
+
+        if let Some(err) = state.error.lock().unwrap().take() {
+            return Err(err);
+        }
+
+        use hlua::AnyLuaValue::*;
+        match result {
+            LuaNil => Ok(false),
+            LuaBoolean(x) => Ok(x),
+            LuaString(x) => bail!(format!("error: {:?}", x)),
+            x => bail!(format!("lua returned wrong type: {:?}", x)),
+        }
+    }
+
+    pub fn healthcheck(&self, network: &DecapInfo) -> Result<Option<bool>> {
+        let (mut lua, state) = ctx(self.http.clone(), self.resolver.clone());
+        lua.execute::<()>(&self.code)?;
+
+        // this function is optional
+        let mut healthcheck: hlua::LuaFunction<_> = match lua.get("healthcheck") {
+            Some(func) => func,
+            None => return Ok(None),
+        };
+
+        let network: hlua::AnyLuaValue = network.clone().into();
+        let result: hlua::AnyLuaValue = match healthcheck.call_with_args((network, )) {
+            Ok(res) => res,
+            Err(err) => {
+                bail!(format!("execution failed: {:?}", err));
+            },
+        };
+
+        if let Some(err) = state.error.lock().unwrap().take() {
+            warn!("healthcheck reported error: {}", err);
+            return Ok(Some(false));
+        }
+
+        use hlua::AnyLuaValue::*;
+        match result {
+            LuaNil => Ok(Some(true)),
+            LuaBoolean(x) => Ok(Some(x)),
+            LuaString(x) => {
+                warn!("healthcheck reported error: {:?}", x);
+                Ok(Some(false))
+            },
+            x => bail!(format!("lua returned wrong type: {:?}", x)),
+        }
+    }
+
+    pub fn decap(&self, network: &DecapInfo) -> Result<()> {
         let (mut lua, state) = ctx(self.http.clone(), self.resolver.clone());
         lua.execute::<()>(&self.code)?;
 
@@ -169,7 +301,8 @@ impl<C: HttpClient + 'static, R: DnsResolver + 'static> Script<C, R> {
             None => bail!("function undefined: decap"),
         };
 
-        let result: hlua::AnyLuaValue = match decap.call() {
+        let network: hlua::AnyLuaValue = network.clone().into();
+        let result: hlua::AnyLuaValue = match decap.call_with_args((network, )) {
             Ok(res) => res,
             Err(err) => {
                 bail!(format!("execution failed: {:?}", err));
@@ -190,3 +323,193 @@ impl<C: HttpClient + 'static, R: DnsResolver + 'static> Script<C, R> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use capport::CaptivePortalStatus;
+    use decap::{WalledGardenFingerprint, PortalPage};
+    use dnscheck::{DnsCheck, DnsMismatch};
+    use scripts::loader::Loader;
+    use scripts::network::{DetectInfo, DecapInfo};
+    use wifi::{AuthSuite, Band, Cipher, SecurityMode};
+
+    fn fingerprint() -> WalledGardenFingerprint {
+        WalledGardenFingerprint {
+            redirect: Some("http://192.0.2.1/start".into()),
+            portal: Some("https://login.hotel.example.com/portal".into()),
+            page: Some(PortalPage {
+                server: Some("nginx".into()),
+                powered_by: None,
+                title: Some("Welcome to Hotel WiFi".into()),
+                sha256: "00".into(),
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn verify_detect_portal() {
+        let script = Loader::init_default(r#"
+        descr = "detect_portal"
+
+        function detect() return false end
+        function detect_portal(portal)
+            return portal['host'] == 'login.hotel.example.com'
+                and portal['redirect'] == 'http://192.0.2.1/start'
+                and portal['title']:find('Hotel WiFi') ~= nil
+        end
+        function decap() end
+        "#).expect("failed to load script");
+        assert!(script.detect_portal(&fingerprint()).expect("detect_portal failed"));
+    }
+
+    #[test]
+    fn verify_detect_portal_undefined() {
+        let script = Loader::init_default(r#"
+        descr = "detect_portal"
+
+        function detect() return false end
+        function decap() end
+        "#).expect("failed to load script");
+        assert!(!script.detect_portal(&fingerprint()).expect("detect_portal failed"));
+    }
+
+    #[test]
+    fn verify_detect_network() {
+        let script = Loader::init_default(r#"
+        descr = "detect_network"
+
+        function detect(network)
+            return network['ssid'] == 'example network' and network['open'] == true
+        end
+        function decap() end
+        "#).expect("failed to load script");
+
+        let mut network = DetectInfo::new("example network");
+        assert!(!script.detect_network(&network).expect("detect failed"));
+        network.open = Some(true);
+        assert!(script.detect_network(&network).expect("detect failed"));
+    }
+
+    #[test]
+    fn verify_detect_network_security() {
+        let script = Loader::init_default(r#"
+        descr = "detect_network_security"
+
+        function detect(network)
+            return network['security'] == 'wpa2'
+                and network['auth'][1] == 'PSK'
+                and network['ciphers'][1] == 'CCMP'
+                and network['band'] == '5GHz'
+                and network['frequency'] == 5180
+                and network['signal'] == -45
+        end
+        function decap() end
+        "#).expect("failed to load script");
+
+        let mut network = DetectInfo::new("example network");
+        assert!(!script.detect_network(&network).expect("detect failed"));
+
+        network.security = Some(SecurityMode::Wpa2);
+        network.auth = vec![AuthSuite::Psk];
+        network.ciphers = vec![Cipher::Ccmp];
+        network.frequency = Some(5180);
+        network.band = Some(Band::Ghz5);
+        network.signal = Some(-45);
+        assert!(script.detect_network(&network).expect("detect failed"));
+    }
+
+    #[test]
+    fn verify_decap_network() {
+        let script = Loader::init_default(r#"
+        descr = "decap_network"
+
+        function detect() return false end
+        function decap(network)
+            return network['gateway'] == '192.0.1.1'
+                and network['address'] == '192.0.1.23/16'
+                and network['network'] == '192.0.0.0'
+                and network['dns'][2] == '192.0.1.3'
+                and network['redirect'] == 'http://192.0.2.1/start'
+                and network['portal']['title'] == 'Welcome to Hotel WiFi'
+                and network['dns_check']['hijacked'] == true
+                and network['dns_check']['mismatches'][1]['received'][1] == '192.0.2.1'
+                and network['capport']['user_portal_url'] == 'https://192.0.2.1/portal'
+                and network['capport']['seconds_remaining'] == 600
+        end
+        "#).expect("failed to load script");
+
+        let network = DecapInfo {
+            ssid: Some("example network".into()),
+            gateway: Some("192.0.1.1".parse().unwrap()),
+            address: Some("192.0.1.23/16".parse().unwrap()),
+            network: Some("192.0.0.0".parse().unwrap()),
+            dns: vec!["192.0.1.2".parse().unwrap(), "192.0.1.3".parse().unwrap()],
+            redirect: Some("http://192.0.2.1/start".into()),
+            portal: Some(fingerprint()),
+            dns_check: Some(DnsCheck {
+                hijacked: Some(true),
+                intercepted: Some(true),
+                checked: vec!["one.one.one.one".into()],
+                mismatches: vec![DnsMismatch {
+                    name: "one.one.one.one".into(),
+                    expected: vec!["1.1.1.1".parse().unwrap()],
+                    received: vec!["192.0.2.1".parse().unwrap()],
+                }],
+            }),
+            capport: Some(CaptivePortalStatus {
+                captive: true,
+                user_portal_url: Some("https://192.0.2.1/portal".into()),
+                seconds_remaining: Some(600),
+                ..Default::default()
+            }),
+        };
+        script.decap(&network).expect("decap failed");
+    }
+
+    #[test]
+    fn verify_healthcheck() {
+        let script = Loader::init_default(r#"
+        descr = "healthcheck"
+
+        healthcheck_interval = 30
+        function detect() return false end
+        function decap() end
+        function healthcheck(network)
+            return network['gateway'] == '192.0.1.1'
+        end
+        "#).expect("failed to load script");
+        assert_eq!(script.healthcheck_interval(), Some(30));
+
+        let mut network = DecapInfo::default();
+        assert_eq!(script.healthcheck(&network).expect("healthcheck failed"), Some(false));
+        network.gateway = Some("192.0.1.1".parse().unwrap());
+        assert_eq!(script.healthcheck(&network).expect("healthcheck failed"), Some(true));
+    }
+
+    #[test]
+    fn verify_healthcheck_invalid_interval() {
+        for interval in &["0", "-30", "0/0", "1/0"] {
+            let script = Loader::init_default(&format!(r#"
+            descr = "healthcheck"
+
+            healthcheck_interval = {}
+            function detect() return false end
+            function decap() end
+            "#, interval));
+            assert!(script.is_err(), "{} should be rejected", interval);
+        }
+    }
+
+    #[test]
+    fn verify_healthcheck_undefined() {
+        let script = Loader::init_default(r#"
+        descr = "healthcheck"
+
+        function detect() return false end
+        function decap() end
+        "#).expect("failed to load script");
+        assert_eq!(script.healthcheck_interval(), None);
+        assert_eq!(script.healthcheck(&DecapInfo::default()).expect("healthcheck failed"), None);
+    }
+}
@@ -1,11 +1,11 @@
 use config::Config;
-use dns::{Resolver, DnsResolver};
+use dns::{DnsCache, Resolver, DnsResolver};
 use errors::{Result, ResultExt};
 use scripts::Script;
 use web::{self, HttpClient};
-use wifi::NetworkStatus;
 
 use std::fs;
+use std::net::IpAddr;
 use std::path::Path;
 use std::sync::Arc;
 use std::collections::HashMap;
@@ -42,10 +42,19 @@ impl Loader {
         Script::load(code.into(), http, resolver)
     }
 
-    pub fn init_from_status(&self, status: &NetworkStatus) -> Result<Vec<Script<web::Client<Resolver>, Resolver>>> {
-        let resolver = Resolver::with_udp(&status.dns)?;
-        let http = Arc::new(web::Client::new(resolver));
-        let resolver = Arc::new(Resolver::with_udp(&status.dns)?);
+    /// Setup the scripts for the network, they use the dns servers the
+    /// network is configured with. The dns responses are cached if a cache
+    /// is provided
+    pub fn init_with_recursors(&self, recursors: &[IpAddr], cache: Option<&DnsCache>) -> Result<Vec<Script<web::Client<Resolver>, Resolver>>> {
+        let mut resolver = Resolver::with_udp(recursors)?;
+        let mut http_resolver = Resolver::with_udp(recursors)?;
+        if let Some(cache) = cache {
+            resolver = resolver.with_cache(cache.clone());
+            http_resolver = http_resolver.with_cache(cache.clone());
+        }
+
+        let http = Arc::new(web::Client::new(http_resolver));
+        let resolver = Arc::new(resolver);
 
         self.init(http, resolver)
     }
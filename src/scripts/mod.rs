@@ -2,3 +2,5 @@ pub mod ctx;
 pub use self::ctx::Script;
 pub mod loader;
 pub use self::loader::Loader;
+pub mod network;
+pub use self::network::{DetectInfo, DecapInfo};
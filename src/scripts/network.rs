@@ -0,0 +1,207 @@
+use capport::CaptivePortalStatus;
+use decap::WalledGardenFingerprint;
+use dhcp::Prefix;
+use dnscheck::DnsCheck;
+use structs::{LuaMap, LuaList};
+use wifi::{AuthSuite, Band, Cipher, Network, NetworkStatus, SecurityMode};
+
+use hlua::AnyLuaValue;
+
+use std::net::IpAddr;
+
+
+/// The network table that is passed to detect(network)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DetectInfo {
+    pub ssid: String,
+    // None if we don't have scan results for this network
+    pub open: Option<bool>,
+    pub bssid: Option<String>,
+    pub security: Option<SecurityMode>,
+    pub auth: Vec<AuthSuite>,
+    pub ciphers: Vec<Cipher>,
+    pub frequency: Option<u32>,
+    pub band: Option<Band>,
+    pub signal: Option<i32>,
+}
+
+impl DetectInfo {
+    pub fn new<I: Into<String>>(ssid: I) -> DetectInfo {
+        DetectInfo {
+            ssid: ssid.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl<'a> From<&'a Network> for DetectInfo {
+    fn from(network: &Network) -> DetectInfo {
+        DetectInfo {
+            ssid: network.essid.clone(),
+            open: Some(network.security.is_open()),
+            bssid: Some(network.ap.clone()),
+            security: Some(network.security.mode),
+            auth: network.security.auth_suites(),
+            ciphers: network.security.ciphers(),
+            frequency: Some(network.frequency).filter(|x| *x != 0),
+            band: network.band,
+            signal: Some(network.signal),
+        }
+    }
+}
+
+fn names<T, F: Fn(&T) -> &'static str>(list: &[T], name: F) -> LuaList {
+    let names = list.iter()
+        .map(|x| name(x).to_string())
+        .collect::<Vec<_>>();
+    LuaList::from(names)
+}
+
+impl Into<AnyLuaValue> for DetectInfo {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        map.insert_str("ssid", self.ssid);
+        if let Some(open) = self.open {
+            map.insert("open", AnyLuaValue::LuaBoolean(open));
+        }
+        if let Some(bssid) = self.bssid {
+            map.insert_str("bssid", bssid);
+        }
+        if let Some(security) = self.security {
+            map.insert_str("security", security.as_str());
+            map.insert("auth", names(&self.auth, |x| x.as_str()));
+            map.insert("ciphers", names(&self.ciphers, |x| x.as_str()));
+        }
+        if let Some(frequency) = self.frequency {
+            map.insert_num("frequency", f64::from(frequency));
+        }
+        if let Some(band) = self.band {
+            map.insert_str("band", band.as_str());
+        }
+        if let Some(signal) = self.signal {
+            map.insert_num("signal", f64::from(signal));
+        }
+
+        map.into()
+    }
+}
+
+/// The network table that is passed to decap(network)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DecapInfo {
+    pub ssid: Option<String>,
+    pub gateway: Option<IpAddr>,
+    pub address: Option<Prefix>,
+    pub network: Option<IpAddr>,
+    pub dns: Vec<IpAddr>,
+    pub redirect: Option<String>,
+    pub portal: Option<WalledGardenFingerprint>,
+    pub dns_check: Option<DnsCheck>,
+    pub capport: Option<CaptivePortalStatus>,
+}
+
+impl DecapInfo {
+    /// `recursors` are the dns servers the scripts are using
+    pub fn new(status: &NetworkStatus, recursors: &[IpAddr], fingerprint: Option<&WalledGardenFingerprint>) -> DecapInfo {
+        DecapInfo {
+            ssid: status.ssid.clone(),
+            gateway: status.routers.first().cloned(),
+            address: status.address,
+            network: status.network,
+            dns: recursors.to_vec(),
+            redirect: fingerprint.and_then(|x| x.redirect.clone()),
+            portal: fingerprint.cloned(),
+            dns_check: status.dns_check.as_ref().map(|x| (**x).clone()),
+            capport: status.capport.as_ref().map(|x| (**x).clone()),
+        }
+    }
+}
+
+impl Into<AnyLuaValue> for DecapInfo {
+    fn into(self) -> AnyLuaValue {
+        let mut map = LuaMap::new();
+
+        if let Some(ssid) = self.ssid {
+            map.insert_str("ssid", ssid);
+        }
+        if let Some(gateway) = self.gateway {
+            map.insert_str("gateway", gateway.to_string());
+        }
+        if let Some(address) = self.address {
+            map.insert_str("address", address.to_string());
+        }
+        if let Some(network) = self.network {
+            map.insert_str("network", network.to_string());
+        }
+
+        let dns = self.dns.into_iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>();
+        map.insert("dns", LuaList::from(dns));
+
+        if let Some(redirect) = self.redirect {
+            map.insert_str("redirect", redirect);
+        }
+        if let Some(portal) = self.portal {
+            map.insert("portal", portal);
+        }
+        if let Some(dns_check) = self.dns_check {
+            map.insert("dns_check", dns_check);
+        }
+        if let Some(capport) = self.capport {
+            map.insert("capport", capport);
+        }
+
+        map.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decap_info_from_status() {
+        let mut status = NetworkStatus::empty();
+        status.ssid = Some("example network".into());
+        status.routers = vec!["192.0.1.1".parse().unwrap(), "192.0.1.254".parse().unwrap()];
+        status.address = Some("192.0.1.23/16".parse().unwrap());
+        status.network = Some("192.0.0.0".parse().unwrap());
+        status.dns = vec!["192.0.1.2".parse().unwrap()];
+        status.dns_check = Some(Box::new(DnsCheck {
+            hijacked: Some(true),
+            ..Default::default()
+        }));
+        status.capport = Some(Box::new(CaptivePortalStatus {
+            captive: true,
+            user_portal_url: Some("https://example.com/portal".into()),
+            ..Default::default()
+        }));
+
+        let mut fingerprint = WalledGardenFingerprint::default();
+        fingerprint.redirect = Some("http://example.com/portal?some=query".into());
+
+        // the dns servers of the network profile replace the ones from dhcp
+        let recursors = vec!["192.0.2.53".parse().unwrap()];
+        let info = DecapInfo::new(&status, &recursors, Some(&fingerprint));
+        assert_eq!(info, DecapInfo {
+            ssid: Some("example network".into()),
+            gateway: Some("192.0.1.1".parse().unwrap()),
+            address: Some("192.0.1.23/16".parse().unwrap()),
+            network: Some("192.0.0.0".parse().unwrap()),
+            dns: recursors,
+            redirect: Some("http://example.com/portal?some=query".into()),
+            portal: Some(fingerprint),
+            dns_check: Some(DnsCheck {
+                hijacked: Some(true),
+                ..Default::default()
+            }),
+            capport: Some(CaptivePortalStatus {
+                captive: true,
+                user_portal_url: Some("https://example.com/portal".into()),
+                ..Default::default()
+            }),
+        });
+    }
+}
@@ -91,3 +91,44 @@ impl Into<AnyLuaValue> for LuaMap {
         )
     }
 }
+
+#[derive(Debug, Default)]
+pub struct LuaList(Vec<AnyLuaValue>);
+
+impl LuaList {
+    #[inline]
+    pub fn new() -> LuaList {
+        LuaList::default()
+    }
+
+    #[inline]
+    pub fn push<V: Into<AnyLuaValue>>(&mut self, v: V) {
+        self.0.push(v.into());
+    }
+
+    #[inline]
+    pub fn push_str<V: Into<String>>(&mut self, v: V) {
+        self.0.push(AnyLuaValue::LuaString(v.into()));
+    }
+}
+
+impl From<Vec<String>> for LuaList {
+    fn from(x: Vec<String>) -> LuaList {
+        let mut list = LuaList::new();
+        for v in x {
+            list.push_str(v);
+        }
+        list
+    }
+}
+
+impl Into<AnyLuaValue> for LuaList {
+    fn into(self: LuaList) -> AnyLuaValue {
+        AnyLuaValue::LuaArray(
+            self.0.into_iter()
+                .enumerate()
+                .map(|(i, v)| (AnyLuaValue::LuaNumber((i + 1) as f64), v))
+                .collect()
+        )
+    }
+}
@@ -1,8 +1,10 @@
 // this file contains some helpers that we want to drop eventually
 
 use std::process::Command;
-use wifi::Network;
 use errors::Result;
+use wifi::{AuthSuite, Band, Cipher, Network, Security, Suites};
+
+use regex::Regex;
 
 pub fn run(cmd: &str, args: &[&str]) -> Result<()> {
     let status = Command::new(cmd)
@@ -47,84 +49,214 @@ pub fn current_essid(iface: &str) -> Result<String> {
     }
 }
 
+fn parse_bssid_from_iwconfig(output: &str) -> Option<String> {
+    if let Some(idx) = output.find("Access Point: ") {
+        let output = &output[idx + 14..];
+        let bssid = output.split_whitespace().next()?;
+
+        // "Not-Associated" if there is no access point
+        if bssid.len() == 17 && bssid.chars().all(|c| c.is_ascii_hexdigit() || c == ':') {
+            return Some(bssid.to_string());
+        }
+    }
+
+    None
+}
+
+pub fn current_bssid(iface: &str) -> Result<String> {
+    let output = cmd("iwconfig", &[iface])?;
+    match parse_bssid_from_iwconfig(&output) {
+        Some(bssid) => Ok(bssid),
+        None => bail!("bssid for interface not found"),
+    }
+}
+
+/// Scan with the deprecated `iwlist`, this is only used if nl80211 isn't available
 pub fn scan_wifi(iface: &str) -> Result<Vec<Network>> {
     let output = cmd("iwlist", &[iface, "scan"])?;
     parse_scan_output(&output)
 }
 
-pub fn parse_scan_output(output: &str) -> Result<Vec<Network>> {
-    use regex::Regex;
+fn unquote(value: &str) -> &str {
+    let value = if value.starts_with('"') { &value[1..] } else { value };
+    if value.ends_with('"') { &value[..value.len() - 1] } else { value }
+}
 
+/// The value after the colon, eg. in "Pairwise Ciphers (2) : CCMP TKIP"
+fn value_of(line: &str) -> &str {
+    match line.find(':') {
+        Some(idx) => line[idx + 1..].trim(),
+        None => "",
+    }
+}
+
+pub fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        bail!("invalid hex: {:?}", hex);
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16)
+            .map_err(|_| format_err!("invalid hex: {:?}", hex)))
+        .collect()
+}
+
+/// Bitrates like "1 Mb/s; 2 Mb/s; 5.5 Mb/s"
+fn parse_bitrates(line: &str) -> Result<Vec<f32>> {
+    line.split(';')
+        .map(|rate| rate.trim().trim_end_matches("Mb/s").trim())
+        .filter(|rate| !rate.is_empty())
+        .map(|rate| rate.parse()
+            .map_err(|_| format_err!("invalid bitrate: {:?}", line)))
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SecurityElement {
+    Wpa,
+    Rsn,
+}
+
+/// A cell of the iwlist output while it's being parsed
+#[derive(Debug, Default)]
+struct Cell {
+    network: Network,
+    privacy: bool,
+    wpa: Option<Suites>,
+    rsn: Option<Suites>,
+    /// the element the following lines belong to
+    element: Option<SecurityElement>,
+    /// the previous line was a list of bitrates that may continue
+    bitrates: bool,
+}
+
+impl Cell {
+    fn new(ap: &str) -> Cell {
+        let mut cell = Cell::default();
+        cell.network.ap = ap.to_string();
+        cell
+    }
+
+    fn suites(&mut self) -> Option<&mut Suites> {
+        match self.element {
+            Some(SecurityElement::Wpa) => self.wpa.as_mut(),
+            Some(SecurityElement::Rsn) => self.rsn.as_mut(),
+            None => None,
+        }
+    }
+
+    fn parse_ie(&mut self, ie: &str) -> Result<()> {
+        self.element = None;
+
+        if ie.starts_with("Unknown: ") {
+            let ie = parse_hex(&ie[9..])?;
+            self.network.ies.extend(ie);
+        } else if ie.starts_with("IEEE 802.11i/WPA2 ") {
+            self.rsn = Some(Suites::default());
+            self.element = Some(SecurityElement::Rsn);
+        } else if ie.starts_with("WPA ") {
+            self.wpa = Some(Suites::default());
+            self.element = Some(SecurityElement::Wpa);
+        }
+
+        Ok(())
+    }
+
+    fn parse_suites(&mut self, trimmed: &str) {
+        let value = value_of(trimmed);
+        if let Some(suites) = self.suites() {
+            if trimmed.starts_with("Group Cipher") {
+                suites.group_cipher = Some(Cipher::from_name(value));
+            } else if trimmed.starts_with("Pairwise Ciphers") {
+                suites.pairwise_ciphers = value.split_whitespace()
+                    .map(Cipher::from_name)
+                    .collect();
+            } else if trimmed.starts_with("Authentication Suites") {
+                suites.auth_suites = value.split_whitespace()
+                    .map(AuthSuite::from_name)
+                    .collect();
+            }
+        }
+    }
+
+    fn parse_line(&mut self, line: &str, signal_re: &Regex) -> Result<()> {
+        let trimmed = line.trim_start();
+        let bitrates = self.bitrates;
+        self.bitrates = false;
+
+        if trimmed.starts_with("Encryption key:") {
+            self.privacy = &trimmed[15..] == "on";
+        } else if trimmed.starts_with("ESSID:") {
+            self.network.essid = String::from(unquote(&trimmed[6..]));
+        } else if trimmed.starts_with("Channel:") {
+            self.network.channel = trimmed[8..].parse::<u16>()
+                .map_err(|_| format_err!("invalid channel: {:?}", trimmed))?;
+        } else if trimmed.starts_with("Frequency:") {
+            let ghz = value_of(trimmed).split_whitespace().next().unwrap_or("");
+            let ghz = ghz.parse::<f64>()
+                .map_err(|_| format_err!("invalid frequency: {:?}", trimmed))?;
+            self.network.frequency = (ghz * 1000.0).round() as u32;
+            self.network.band = Band::from_frequency(self.network.frequency);
+        } else if trimmed.starts_with("Mode:") {
+            self.network.mode = String::from(&trimmed[5..]);
+        } else if trimmed.starts_with("Quality=") {
+            let cap = match signal_re.captures(line) {
+                Some(cap) => cap,
+                None => bail!("invalid signal quality: {:?}", trimmed),
+            };
+
+            self.network.quality = cap[1].to_string();
+            self.network.signal = cap[2].parse()
+                .map_err(|_| format_err!("invalid signal level: {:?}", trimmed))?;
+        } else if trimmed.starts_with("Bit Rates:") {
+            self.network.bitrates.extend(parse_bitrates(&trimmed[10..])?);
+            self.bitrates = true;
+        } else if bitrates && trimmed.ends_with("Mb/s") {
+            self.network.bitrates.extend(parse_bitrates(trimmed)?);
+            self.bitrates = true;
+        } else if trimmed.starts_with("Extra: Last beacon: ") {
+            let ms = trimmed[20..].trim_end_matches("ms ago");
+            self.network.last_beacon_ms = Some(ms.parse()
+                .map_err(|_| format_err!("invalid beacon age: {:?}", trimmed))?);
+        } else if trimmed.starts_with("IE: ") {
+            self.parse_ie(&trimmed[4..])?;
+        } else {
+            self.parse_suites(trimmed);
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> Network {
+        let mut network = self.network;
+        network.security = Security::new(self.privacy, self.wpa, self.rsn);
+        network
+    }
+}
+
+pub fn parse_scan_output(output: &str) -> Result<Vec<Network>> {
     let re = Regex::new(r"^\s+Cell \d+ - Address: ([0-9A-F:]+)$").unwrap();
     let signal_re = Regex::new(r"^\s*Quality=([\d/]+)\s+Signal level=(\-\d+) dBm").unwrap();
 
     let mut networks = Vec::new();
-    let mut ap = None;
-    let mut essid = None;
-    let mut encryption = None;
-    let mut quality = None;
-    let mut signal = None;
-    let mut channel = None;
-    let mut mode = None;
-
-    for line in output.split("\n") {
-        if !line.starts_with(" ") {
+    let mut cell = None;
+
+    for line in output.split('\n') {
+        if !line.starts_with(' ') {
             continue;
         }
-        if let Some(cell) = re.captures(line) {
-            if ap.is_some() {
-                networks.push(Network::build(&mut ap,
-                                             &mut essid,
-                                             &mut encryption,
-                                             &mut quality,
-                                             &mut signal,
-                                             &mut channel,
-                                             &mut mode));
-            }
 
-            ap = Some(cell.get(1).unwrap().as_str().to_string());
-            // println!("got ap {:?}", ap);
-        } else {
-            let trimmed = line.trim_start();
-
-            if trimmed.starts_with("Encryption key:") {
-                encryption = Some(String::from(&trimmed[15..]));
-                // println!("\tencryption: {:?}", encryption);
-            } else if trimmed.starts_with("ESSID:") {
-                essid = Some(String::from(&trimmed[7..trimmed.len()-1]));
-                // println!("\tessid={:?}", essid);
-            } else if trimmed.starts_with("Channel:") {
-                channel = Some(trimmed[8..].parse::<u16>().unwrap());
-                // println!("\tchannel={:?}", channel);
-            } else if trimmed.starts_with("Mode:") {
-                mode = Some(String::from(&trimmed[5..]));
-                // println!("\tmode={:?}", mode);
-            } else if trimmed.starts_with("Quality=") {
-                let cap = signal_re.captures(line).expect("regex didn't match");
-
-                quality = Some(String::from(cap.get(1).unwrap().as_str()));
-                signal = Some({
-                    let signal = cap.get(2).unwrap().as_str();
-                    signal.parse().unwrap()
-                });
-
-                // println!("\tquality={:?} signal={:?}", quality, signal);
-            } else if trimmed.starts_with("IE: Unknown: ") {
-                // ignore unknown extension
-            } else {
-                // println!("{:?}", line);
+        if let Some(captures) = re.captures(line) {
+            if let Some(cell) = cell.take() {
+                networks.push(Cell::finish(cell));
             }
+            cell = Some(Cell::new(&captures[1]));
+        } else if let Some(cell) = cell.as_mut() {
+            cell.parse_line(line, &signal_re)?;
         }
     }
 
-    if ap.is_some() {
-        networks.push(Network::build(&mut ap,
-                                     &mut essid,
-                                     &mut encryption,
-                                     &mut quality,
-                                     &mut signal,
-                                     &mut channel,
-                                     &mut mode));
+    if let Some(cell) = cell {
+        networks.push(cell.finish());
     }
 
     Ok(networks)
@@ -133,6 +265,7 @@ pub fn parse_scan_output(output: &str) -> Result<Vec<Network>> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use wifi::SecurityMode;
 
     #[test]
     fn test_parse_essid() {
@@ -142,10 +275,76 @@ mod tests {
         assert_eq!(essid, Some(String::from("this is my ssid")));
     }
 
+    #[test]
+    fn test_parse_bssid() {
+        let data = "wlp3s0    IEEE 802.11  ESSID:\"this is my ssid\"\n          Mode:Managed  Frequency:2.412 GHz  Access Point: 00:11:22:AA:BB:CC\n";
+        let bssid = parse_bssid_from_iwconfig(data);
+        assert_eq!(bssid, Some(String::from("00:11:22:AA:BB:CC")));
+
+        let data = "wlp3s0    IEEE 802.11  ESSID:off/any\n          Mode:Managed  Access Point: Not-Associated\n";
+        let bssid = parse_bssid_from_iwconfig(data);
+        assert_eq!(bssid, None);
+    }
+
     #[test]
     fn test_parse_scan() {
         let output = include_str!("../tests/iwlist.txt");
         let result = parse_scan_output(output).unwrap();
-        println!("{:#?}", result);
+        assert_eq!(result.len(), 54);
+
+        let network = &result[0];
+        assert_eq!(network.ap, "D8:C7:C8:EB:02:F0");
+        assert_eq!(network.essid, "fux-admin");
+        assert_eq!(network.quality, "31/70");
+        assert_eq!(network.signal, -79);
+        assert_eq!(network.channel, 1);
+        assert_eq!(network.frequency, 2412);
+        assert_eq!(network.band, Some(Band::Ghz2));
+        assert_eq!(network.mode, "Master");
+        assert_eq!(network.bitrates, vec![1.0, 2.0, 5.5, 6.0, 9.0, 11.0, 12.0, 18.0, 24.0, 36.0, 48.0, 54.0]);
+        assert_eq!(network.last_beacon_ms, Some(3846));
+        assert_eq!(&network.ies[..11], &[0x00, 0x09, b'f', b'u', b'x', b'-', b'a', b'd', b'm', b'i', b'n']);
+        assert_eq!(network.security, Security {
+            mode: SecurityMode::Wpa2,
+            wpa: None,
+            rsn: Some(Suites {
+                group_cipher: Some(Cipher::Ccmp),
+                pairwise_ciphers: vec![Cipher::Ccmp],
+                auth_suites: vec![AuthSuite::Psk],
+            }),
+        });
+
+        // an open network
+        assert_eq!(result[2].essid, "hamburg.freifunk.net");
+        assert_eq!(result[2].security.mode, SecurityMode::Open);
+
+        let modes = result.iter()
+            .map(|network| network.security.mode)
+            .collect::<Vec<_>>();
+        assert_eq!(modes.iter().filter(|x| **x == SecurityMode::Wpa2).count(), 38);
+        assert_eq!(modes.iter().filter(|x| **x == SecurityMode::Wpa).count(), 0);
+        assert!(result.iter().all(|network| network.band.is_some()));
+
+        let mixed = result.iter()
+            .filter_map(|network| network.security.wpa.as_ref())
+            .collect::<Vec<_>>();
+        assert_eq!(mixed.len(), 5);
+        assert_eq!(mixed[0].group_cipher, Some(Cipher::Tkip));
+    }
+
+    #[test]
+    fn test_parse_scan_invalid() {
+        let output = include_str!("../tests/iwlist.txt");
+
+        let broken = output.replacen("Channel:1\n", "Channel:one\n", 1);
+        assert!(parse_scan_output(&broken).is_err());
+
+        let broken = output.replacen("Signal level=-79 dBm", "Signal level=unknown", 1);
+        assert!(parse_scan_output(&broken).is_err());
+
+        // a truncated line doesn't panic
+        let result = parse_scan_output("          Cell 01 - Address: D8:C7:C8:EB:02:F0\n                    ESSID:\"\n").unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].essid, "");
     }
 }
@@ -7,10 +7,12 @@ use http::Request;
 
 use tokio_core::reactor;
 use futures::{future, Stream};
+use futures::future::Either;
 
 use std::net::IpAddr;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use http::Uri;
 use errors::Result;
 
@@ -25,6 +27,7 @@ pub struct Client<R: DnsResolver> {
     client: hyper::Client<HttpsConnector<Connector<HttpConnector>>>,
     resolver: R,
     records: Arc<Mutex<HashMap<String, IpAddr>>>,
+    timeout: Option<Duration>,
 }
 
 impl<R: DnsResolver> Client<R> {
@@ -39,9 +42,14 @@ impl<R: DnsResolver> Client<R> {
             client,
             resolver,
             records,
+            timeout: None,
         }
     }
 
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = Some(timeout);
+    }
+
     pub fn pre_resolve(&self, uri: &Uri) -> Result<()> {
         let host = match uri.host() {
             Some(host) => host,
@@ -83,12 +91,24 @@ impl<R: DnsResolver> HttpClient for Client<R> {
         self.pre_resolve(url)?;
 
         let mut core = reactor::Core::new()?;
-        let (parts, body) = core.run(self.client.request(request).and_then(|res| {
+        let fut = self.client.request(request).and_then(|res| {
             debug!("http response: {:?}", res);
             let (parts, body) = res.into_parts();
             let body = body.concat2();
             (future::ok(parts), body)
-        }))?;
+        });
+
+        let (parts, body) = if let Some(timeout) = self.timeout {
+            let timeout = reactor::Timeout::new(timeout, &core.handle())?;
+            match core.run(fut.select2(timeout)) {
+                Ok(Either::A((reply, _))) => reply,
+                Ok(Either::B(_)) => bail!("request timed out"),
+                Err(Either::A((err, _))) => return Err(err.into()),
+                Err(Either::B((err, _))) => return Err(err.into()),
+            }
+        } else {
+            core.run(fut)?
+        };
 
         let body = String::from_utf8_lossy(&body);
         let reply = Response::from((parts, body.to_string()));
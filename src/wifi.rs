@@ -1,71 +1,655 @@
+use capport::CaptivePortalStatus;
+use decap::DecapReport;
+use dnscheck::DnsCheck;
 use dhcp;
+use nl80211;
+use utils;
 use std::net::IpAddr;
+use std::process::{Command, Child};
 use errors::*;
 
 use trust_dns_resolver;
 
 
-#[derive(Debug)]
+/// The strongest security mode a network advertises
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityMode {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2,
+    Wpa3,
+}
+
+impl Default for SecurityMode {
+    fn default() -> SecurityMode {
+        SecurityMode::Open
+    }
+}
+
+impl SecurityMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SecurityMode::Open => "open",
+            SecurityMode::Wep => "wep",
+            SecurityMode::Wpa => "wpa",
+            SecurityMode::Wpa2 => "wpa2",
+            SecurityMode::Wpa3 => "wpa3",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Cipher {
+    #[serde(rename="WEP-40")]
+    Wep40,
+    #[serde(rename="TKIP")]
+    Tkip,
+    #[serde(rename="CCMP")]
+    Ccmp,
+    #[serde(rename="WEP-104")]
+    Wep104,
+    #[serde(rename="GCMP")]
+    Gcmp,
+    #[serde(rename="GCMP-256")]
+    Gcmp256,
+    #[serde(rename="CCMP-256")]
+    Ccmp256,
+    #[serde(rename="unknown")]
+    Unknown,
+}
+
+impl Cipher {
+    /// The suite type of the RSN and WPA elements
+    fn from_suite(suite: u8) -> Cipher {
+        match suite {
+            1 => Cipher::Wep40,
+            2 => Cipher::Tkip,
+            4 => Cipher::Ccmp,
+            5 => Cipher::Wep104,
+            8 => Cipher::Gcmp,
+            9 => Cipher::Gcmp256,
+            10 => Cipher::Ccmp256,
+            _ => Cipher::Unknown,
+        }
+    }
+
+    /// The names that are used by iwlist
+    pub fn from_name(name: &str) -> Cipher {
+        match name {
+            "WEP-40" => Cipher::Wep40,
+            "TKIP" => Cipher::Tkip,
+            "CCMP" => Cipher::Ccmp,
+            "WEP-104" => Cipher::Wep104,
+            _ => Cipher::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Cipher::Wep40 => "WEP-40",
+            Cipher::Tkip => "TKIP",
+            Cipher::Ccmp => "CCMP",
+            Cipher::Wep104 => "WEP-104",
+            Cipher::Gcmp => "GCMP",
+            Cipher::Gcmp256 => "GCMP-256",
+            Cipher::Ccmp256 => "CCMP-256",
+            Cipher::Unknown => "unknown",
+        }
+    }
+}
+
+/// The key management, eg. a pre-shared key or 802.1X
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AuthSuite {
+    #[serde(rename="802.1X")]
+    Ieee8021x,
+    #[serde(rename="PSK")]
+    Psk,
+    #[serde(rename="FT/802.1X")]
+    FtIeee8021x,
+    #[serde(rename="FT/PSK")]
+    FtPsk,
+    #[serde(rename="802.1X-SHA256")]
+    Ieee8021xSha256,
+    #[serde(rename="PSK-SHA256")]
+    PskSha256,
+    #[serde(rename="SAE")]
+    Sae,
+    #[serde(rename="FT/SAE")]
+    FtSae,
+    #[serde(rename="802.1X-SUITE-B-192")]
+    SuiteB192,
+    #[serde(rename="OWE")]
+    Owe,
+    #[serde(rename="unknown")]
+    Unknown,
+}
+
+impl AuthSuite {
+    fn from_suite(suite: u8) -> AuthSuite {
+        match suite {
+            1 => AuthSuite::Ieee8021x,
+            2 => AuthSuite::Psk,
+            3 => AuthSuite::FtIeee8021x,
+            4 => AuthSuite::FtPsk,
+            5 => AuthSuite::Ieee8021xSha256,
+            6 => AuthSuite::PskSha256,
+            8 => AuthSuite::Sae,
+            9 => AuthSuite::FtSae,
+            12 => AuthSuite::SuiteB192,
+            18 => AuthSuite::Owe,
+            _ => AuthSuite::Unknown,
+        }
+    }
+
+    /// The names that are used by iwlist
+    pub fn from_name(name: &str) -> AuthSuite {
+        match name {
+            "802.1x" => AuthSuite::Ieee8021x,
+            "PSK" => AuthSuite::Psk,
+            _ => AuthSuite::Unknown,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthSuite::Ieee8021x => "802.1X",
+            AuthSuite::Psk => "PSK",
+            AuthSuite::FtIeee8021x => "FT/802.1X",
+            AuthSuite::FtPsk => "FT/PSK",
+            AuthSuite::Ieee8021xSha256 => "802.1X-SHA256",
+            AuthSuite::PskSha256 => "PSK-SHA256",
+            AuthSuite::Sae => "SAE",
+            AuthSuite::FtSae => "FT/SAE",
+            AuthSuite::SuiteB192 => "802.1X-SUITE-B-192",
+            AuthSuite::Owe => "OWE",
+            AuthSuite::Unknown => "unknown",
+        }
+    }
+
+    pub fn is_enterprise(self) -> bool {
+        match self {
+            AuthSuite::Ieee8021x |
+            AuthSuite::FtIeee8021x |
+            AuthSuite::Ieee8021xSha256 |
+            AuthSuite::SuiteB192 => true,
+            _ => false,
+        }
+    }
+}
+
+/// The cipher and authentication suites of a WPA or RSN element
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Suites {
+    pub group_cipher: Option<Cipher>,
+    pub pairwise_ciphers: Vec<Cipher>,
+    pub auth_suites: Vec<AuthSuite>,
+}
+
+const RSN_OUI: [u8; 3] = [0x00, 0x0f, 0xac];
+const WPA_OUI: [u8; 3] = [0x00, 0x50, 0xf2];
+
+const WLAN_EID_SUPP_RATES: u8 = 1;
+const WLAN_EID_RSN: u8 = 48;
+const WLAN_EID_EXT_SUPP_RATES: u8 = 50;
+const WLAN_EID_VENDOR_SPECIFIC: u8 = 221;
+
+/// Take a suite selector from the start of `data`, the type is 0 for
+/// selectors of other vendors
+fn take_suite(data: &mut &[u8], oui: [u8; 3]) -> Option<u8> {
+    let buf: &[u8] = *data;
+    if buf.len() < 4 {
+        return None;
+    }
+    *data = &buf[4..];
+    if buf[..3] == oui {
+        Some(buf[3])
+    } else {
+        Some(0)
+    }
+}
+
+/// Take a list of suite selectors that is prefixed with its length
+fn take_suite_list(data: &mut &[u8], oui: [u8; 3]) -> Option<Vec<u8>> {
+    let buf: &[u8] = *data;
+    if buf.len() < 2 {
+        return None;
+    }
+    let count = u16::from(buf[0]) | u16::from(buf[1]) << 8;
+    *data = &buf[2..];
+    (0..count).map(|_| take_suite(data, oui)).collect()
+}
+
+impl Suites {
+    /// Parse the body of an RSN element or a WPA element after the oui.
+    /// Missing fields have defaults in the standard, those are used for
+    /// truncated elements as well.
+    fn parse(data: &[u8], oui: [u8; 3], default_cipher: Cipher) -> Option<Suites> {
+        // skip the version
+        let mut data = data.get(2..)?;
+
+        let group_cipher = take_suite(&mut data, oui)
+            .map(Cipher::from_suite)
+            .unwrap_or(default_cipher);
+        let pairwise_ciphers = match take_suite_list(&mut data, oui) {
+            Some(suites) => suites.into_iter().map(Cipher::from_suite).collect(),
+            None => vec![default_cipher],
+        };
+        let auth_suites = match take_suite_list(&mut data, oui) {
+            Some(suites) => suites.into_iter().map(AuthSuite::from_suite).collect(),
+            None => vec![AuthSuite::Ieee8021x],
+        };
+
+        Some(Suites {
+            group_cipher: Some(group_cipher),
+            pairwise_ciphers,
+            auth_suites,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct Security {
+    pub mode: SecurityMode,
+    /// the WPA element of WPA1 networks
+    pub wpa: Option<Suites>,
+    /// the RSN element of WPA2 and WPA3 networks
+    pub rsn: Option<Suites>,
+}
+
+impl Security {
+    pub fn new(privacy: bool, wpa: Option<Suites>, rsn: Option<Suites>) -> Security {
+        let wpa3 = rsn.iter()
+            .flat_map(|rsn| rsn.auth_suites.iter())
+            .any(|x| *x == AuthSuite::Sae || *x == AuthSuite::FtSae || *x == AuthSuite::SuiteB192);
+
+        let mode = match (&wpa, &rsn) {
+            (_, Some(_)) if wpa3 => SecurityMode::Wpa3,
+            (_, Some(_)) => SecurityMode::Wpa2,
+            (Some(_), None) => SecurityMode::Wpa,
+            (None, None) if privacy => SecurityMode::Wep,
+            (None, None) => SecurityMode::Open,
+        };
+
+        Security {
+            mode,
+            wpa,
+            rsn,
+        }
+    }
+
+    /// Use the security elements from the information elements of a beacon
+    pub fn from_ies(privacy: bool, ies: &[u8]) -> Security {
+        let mut wpa = None;
+        let mut rsn = None;
+
+        for (id, data) in nl80211::information_elements(ies) {
+            match id {
+                WLAN_EID_RSN => rsn = Suites::parse(data, RSN_OUI, Cipher::Ccmp),
+                WLAN_EID_VENDOR_SPECIFIC if data.starts_with(&[0x00, 0x50, 0xf2, 0x01]) => {
+                    wpa = Suites::parse(&data[4..], WPA_OUI, Cipher::Tkip);
+                },
+                _ => (),
+            }
+        }
+
+        Security::new(privacy, wpa, rsn)
+    }
+
+    fn suites(&self) -> impl Iterator<Item=&Suites> + '_ {
+        self.rsn.iter().chain(self.wpa.iter())
+    }
+
+    /// The authentication suites of all elements
+    pub fn auth_suites(&self) -> Vec<AuthSuite> {
+        let mut auth = Vec::new();
+        for suite in self.suites().flat_map(|x| x.auth_suites.iter()) {
+            if !auth.contains(suite) {
+                auth.push(*suite);
+            }
+        }
+        auth
+    }
+
+    /// The pairwise ciphers of all elements
+    pub fn ciphers(&self) -> Vec<Cipher> {
+        let mut ciphers = Vec::new();
+        for cipher in self.suites().flat_map(|x| x.pairwise_ciphers.iter()) {
+            if !ciphers.contains(cipher) {
+                ciphers.push(*cipher);
+            }
+        }
+        ciphers
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.mode == SecurityMode::Open
+    }
+
+    pub fn is_enterprise(&self) -> bool {
+        self.auth_suites().into_iter().any(AuthSuite::is_enterprise)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Band {
+    #[serde(rename="2.4GHz")]
+    Ghz2,
+    #[serde(rename="5GHz")]
+    Ghz5,
+    #[serde(rename="6GHz")]
+    Ghz6,
+    #[serde(rename="60GHz")]
+    Ghz60,
+}
+
+impl Band {
+    pub fn from_frequency(frequency: u32) -> Option<Band> {
+        match frequency {
+            2400..=2500 => Some(Band::Ghz2),
+            // 5935 is the 6 GHz channel 2
+            5935 | 5955..=7125 => Some(Band::Ghz6),
+            4900..=5930 => Some(Band::Ghz5),
+            57000..=71000 => Some(Band::Ghz60),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Band::Ghz2 => "2.4GHz",
+            Band::Ghz5 => "5GHz",
+            Band::Ghz6 => "6GHz",
+            Band::Ghz60 => "60GHz",
+        }
+    }
+}
+
+/// The supported bitrates in Mb/s, from the (extended) supported rates elements
+pub fn bitrates(ies: &[u8]) -> Vec<f32> {
+    nl80211::information_elements(ies)
+        .filter(|(id, _)| *id == WLAN_EID_SUPP_RATES || *id == WLAN_EID_EXT_SUPP_RATES)
+        .flat_map(|(_, rates)| rates.iter())
+        // values above 60 Mb/s are membership selectors, eg. for HT or VHT
+        .filter(|rate| *rate & 0x7f <= 120)
+        .map(|rate| f32::from(rate & 0x7f) / 2.0)
+        .collect()
+}
+
+/// Raw bytes are encoded as hex string in json
+mod hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::de::Error;
+    use utils;
+
+    pub fn serialize<T: AsRef<[u8]>, S: Serializer>(bytes: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex = bytes.as_ref().iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        serializer.serialize_str(&hex)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        utils::parse_hex(&hex)
+            .map_err(D::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct Network {
     pub ap: String,
     pub essid: String,
-    pub encryption: String,
+    pub security: Security,
     pub quality: String,
     pub signal: i32,
     pub channel: u16,
+    /// in MHz
+    pub frequency: u32,
+    pub band: Option<Band>,
     pub mode: String,
+    /// in Mb/s
+    pub bitrates: Vec<f32>,
+    pub last_beacon_ms: Option<u64>,
+    /// the raw information elements, iwlist only shows the elements it
+    /// couldn't decode
+    #[serde(with = "hex")]
+    pub ies: Vec<u8>,
+}
+
+/// Scan for networks with nl80211, `iwlist` is used if that's not available
+pub fn scan(interface: &str) -> Result<Vec<Network>> {
+    match nl80211::scan(interface) {
+        Ok(networks) => Ok(networks),
+        Err(err) => {
+            warn!("nl80211 scan failed, falling back to iwlist: {}", err);
+            utils::scan_wifi(interface)
+        },
+    }
 }
 
-impl Network {
-    pub fn build(ap: &mut Option<String>,
-                 essid: &mut Option<String>,
-                 encryption: &mut Option<String>,
-                 quality: &mut Option<String>,
-                 signal: &mut Option<i32>,
-                 channel: &mut Option<u16>,
-                 mode: &mut Option<String>) -> Self {
-        Network {
-            ap: ap.take().unwrap_or(String::new()),
-            essid: essid.take().unwrap_or(String::new()),
-            encryption: encryption.take().unwrap_or(String::new()),
-            quality: quality.take().unwrap_or(String::new()),
-            signal: signal.take().unwrap_or(0),
-            channel: channel.take().unwrap_or(0),
-            mode: mode.take().unwrap_or(String::new()),
+/// The ssid of the network we're associated with
+pub fn current_ssid(interface: &str) -> Result<String> {
+    match nl80211::link(interface) {
+        Ok(Some(ref bss)) if !bss.is_hidden() => Ok(bss.ssid()),
+        // hidden networks don't have the ssid in their beacons, it's taken
+        // from the association instead
+        Ok(Some(_)) => match nl80211::ssid(interface) {
+            Ok(Some(ssid)) => Ok(ssid),
+            Ok(None) => utils::current_essid(interface),
+            Err(err) => {
+                debug!("nl80211 interface info failed, falling back to iwconfig: {}", err);
+                utils::current_essid(interface)
+            },
+        },
+        Ok(None) => bail!("interface is not associated"),
+        Err(err) => {
+            debug!("nl80211 link info failed, falling back to iwconfig: {}", err);
+            utils::current_essid(interface)
+        },
+    }
+}
+
+/// The bssid of the access point we're associated with
+pub fn current_bssid(interface: &str) -> Result<String> {
+    match nl80211::link(interface) {
+        Ok(Some(bss)) => Ok(bss.bssid()),
+        Ok(None) => bail!("interface is not associated"),
+        Err(err) => {
+            debug!("nl80211 link info failed, falling back to iwconfig: {}", err);
+            utils::current_bssid(interface)
+        },
+    }
+}
+
+/// The scan result of the access point we're associated with
+pub fn current_network(interface: &str) -> Result<Network> {
+    match nl80211::link(interface) {
+        Ok(Some(bss)) => Ok(Network::from(&bss)),
+        Ok(None) => bail!("interface is not associated"),
+        Err(err) => {
+            debug!("nl80211 link info failed, falling back to iwlist: {}", err);
+            let bssid = utils::current_bssid(interface)?;
+            utils::scan_wifi(interface)?
+                .into_iter()
+                .find(|network| network.ap.eq_ignore_ascii_case(&bssid))
+                .ok_or_else(|| format_err!("access point not found in scan results"))
+        },
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WifiCredentials {
+    Open,
+    Psk(String),
+    Identity {
+        identity: String,
+        password: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConnectProfile {
+    pub ssid: String,
+    pub credentials: WifiCredentials,
+}
+
+fn wpa_quote(value: &str) -> String {
+    if value.chars().all(|c| c.is_ascii() && !c.is_ascii_control() && c != '"') {
+        format!("\"{}\"", value)
+    } else {
+        // fallback to the hex notation of wpa_supplicant
+        value.as_bytes().iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// A WPA passphrase has 8 to 63 printable ascii characters (IEEE 802.11i),
+/// wpa_supplicant only accepts it quoted
+pub fn is_valid_passphrase(psk: &str) -> bool {
+    psk.len() >= 8 && psk.len() <= 63 && psk.bytes().all(|b| b >= 32 && b <= 126)
+}
+
+impl ConnectProfile {
+    pub fn to_wpa_supplicant(&self) -> Result<String> {
+        let mut conf = String::from("network={\n");
+
+        conf += &format!("    ssid={}\n", wpa_quote(&self.ssid));
+
+        match &self.credentials {
+            WifiCredentials::Open => {
+                conf += "    key_mgmt=NONE\n";
+            },
+            WifiCredentials::Psk(psk) => {
+                if !is_valid_passphrase(psk) {
+                    bail!("psk needs to be 8 to 63 printable ascii characters");
+                }
+                conf += "    key_mgmt=WPA-PSK\n";
+                conf += &format!("    psk=\"{}\"\n", psk);
+            },
+            WifiCredentials::Identity { identity, password } => {
+                conf += "    key_mgmt=WPA-EAP\n";
+                conf += "    eap=PEAP TTLS\n";
+                conf += &format!("    identity={}\n", wpa_quote(identity));
+                conf += &format!("    password={}\n", wpa_quote(password));
+                conf += "    phase2=\"auth=MSCHAPV2\"\n";
+            },
         }
+
+        conf += "}\n";
+        Ok(conf)
     }
 }
 
+/// A running wpa_supplicant, the process is stopped when this is dropped
+#[derive(Debug)]
+pub struct WpaSupplicant {
+    child: Child,
+}
+
+impl Drop for WpaSupplicant {
+    fn drop(&mut self) {
+        if let Err(err) = self.child.kill() {
+            debug!("failed to kill wpa_supplicant: {}", err);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+pub fn run_wpa_supplicant(conf: &str, interface: &str) -> Result<WpaSupplicant> {
+    let child = Command::new("wpa_supplicant")
+                    .args(&["-c", conf,
+                            "-i", interface])
+                    .spawn()?;
+    Ok(WpaSupplicant {
+        child,
+    })
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetworkStatus {
+    /// incremented by snaild for every new dhcp lease
+    #[serde(default)]
+    pub generation: u64,
     pub ssid: Option<String>,
-    pub router: String,
+    #[serde(default)]
+    pub bssid: Option<String>,
+    /// the mac address of our interface
+    #[serde(default)]
+    pub mac: Option<String>,
+    /// the scan result of the access point
+    #[serde(default)]
+    pub wifi: Option<Box<Network>>,
+    #[serde(default)]
+    pub routers: Vec<IpAddr>,
+    /// our address from the dhcp lease, with the prefix length of the subnet
+    #[serde(default)]
+    pub address: Option<dhcp::Prefix>,
+    /// the network number of the subnet
+    #[serde(default)]
+    pub network: Option<IpAddr>,
     pub dns: Vec<IpAddr>,
+    /// the optional settings of the dhcp lease
+    #[serde(default)]
+    pub lease: Box<dhcp::LeaseOptions>,
 
     pub has_uplink: Option<bool>,
     pub script_used: Option<String>,
+    pub decap_report: Option<Box<DecapReport>>,
+    /// the most recent dns check, after decap if the portal has been solved
+    #[serde(default)]
+    pub dns_check: Option<Box<DnsCheck>>,
+    /// the most recent answer of the captive portal api
+    #[serde(default)]
+    pub capport: Option<Box<CaptivePortalStatus>>,
 }
 
 impl NetworkStatus {
     pub fn new(ssid: Option<String>, config: dhcp::NetworkConfig) -> NetworkStatus {
         NetworkStatus {
+            generation: 0,
             ssid: ssid,
-            router: config.routers,
+            bssid: None,
+            mac: None,
+            wifi: None,
+            routers: config.routers,
+            address: Some(dhcp::Prefix {
+                address: config.ip_address,
+                length: config.subnet_cidr,
+            }),
+            network: Some(config.network_number),
             dns: config.dns_servers,
+            lease: config.options,
 
             has_uplink: None,
             script_used: None,
+            decap_report: None,
+            dns_check: None,
+            capport: None,
         }
     }
 
     pub fn empty() -> NetworkStatus {
         NetworkStatus {
+            generation: 0,
             ssid: None,
-            router: String::new(),
+            bssid: None,
+            mac: None,
+            wifi: None,
+            routers: Vec::new(),
+            address: None,
+            network: None,
             dns: vec![],
+            lease: Box::default(),
 
             has_uplink: None,
             script_used: None,
+            decap_report: None,
+            dns_check: None,
+            capport: None,
         }
     }
 
@@ -76,12 +660,22 @@ impl NetworkStatus {
             .collect();
 
         Ok(NetworkStatus {
+            generation: 0,
             ssid: None,
-            router: String::new(),
+            bssid: None,
+            mac: None,
+            wifi: None,
+            routers: Vec::new(),
+            address: None,
+            network: None,
             dns: dns,
+            lease: Box::default(),
 
             has_uplink: None,
             script_used: None,
+            decap_report: None,
+            dns_check: None,
+            capport: None,
         })
     }
 
@@ -89,3 +683,173 @@ impl NetworkStatus {
         self.has_uplink = uplink;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn rsn(akm: u8) -> Vec<u8> {
+        vec![48, 20, 1, 0,
+             0x00, 0x0f, 0xac, 4,
+             1, 0, 0x00, 0x0f, 0xac, 4,
+             1, 0, 0x00, 0x0f, 0xac, akm,
+             0, 0]
+    }
+
+    #[test]
+    fn test_security_from_ies() {
+        let security = Security::from_ies(true, &rsn(2));
+        assert_eq!(security.mode, SecurityMode::Wpa2);
+        assert_eq!(security.auth_suites(), vec![AuthSuite::Psk]);
+        assert!(!security.is_enterprise());
+
+        let security = Security::from_ies(true, &rsn(8));
+        assert_eq!(security.mode, SecurityMode::Wpa3);
+        assert_eq!(security.auth_suites(), vec![AuthSuite::Sae]);
+
+        let security = Security::from_ies(true, &rsn(1));
+        assert_eq!(security.mode, SecurityMode::Wpa2);
+        assert!(security.is_enterprise());
+
+        // WPA1 with TKIP
+        let wpa = [221, 22, 0x00, 0x50, 0xf2, 1, 1, 0,
+                   0x00, 0x50, 0xf2, 2,
+                   1, 0, 0x00, 0x50, 0xf2, 2,
+                   1, 0, 0x00, 0x50, 0xf2, 2];
+        let security = Security::from_ies(true, &wpa);
+        assert_eq!(security.mode, SecurityMode::Wpa);
+        assert_eq!(security.ciphers(), vec![Cipher::Tkip]);
+        assert_eq!(security.auth_suites(), vec![AuthSuite::Psk]);
+
+        // other vendor elements are ignored
+        let security = Security::from_ies(true, &[221, 4, 0x00, 0x50, 0xf2, 2]);
+        assert_eq!(security.mode, SecurityMode::Wep);
+        assert!(Security::from_ies(false, &[]).is_open());
+    }
+
+    #[test]
+    fn test_security_truncated() {
+        // only the version, everything else has defaults
+        let security = Security::from_ies(true, &[48, 2, 1, 0]);
+        assert_eq!(security.rsn, Some(Suites {
+            group_cipher: Some(Cipher::Ccmp),
+            pairwise_ciphers: vec![Cipher::Ccmp],
+            auth_suites: vec![AuthSuite::Ieee8021x],
+        }));
+
+        // a list that is shorter than its count
+        let mut ie = rsn(2);
+        ie[1] = 12;
+        ie[14] = 2;
+        ie.truncate(14);
+        assert_eq!(Security::from_ies(true, &ie).rsn.unwrap().pairwise_ciphers, vec![Cipher::Ccmp]);
+    }
+
+    #[test]
+    fn test_bitrates() {
+        // 802.11g rates with the HT membership selector
+        let ies = [1, 8, 0x82, 0x84, 0x8b, 0x96, 0x0c, 0x12, 0x18, 0xff,
+                   50, 4, 0x30, 0x48, 0x60, 0x6c];
+        assert_eq!(bitrates(&ies), vec![1.0, 2.0, 5.5, 11.0, 6.0, 9.0, 12.0, 24.0, 36.0, 48.0, 54.0]);
+    }
+
+    #[test]
+    fn test_band() {
+        assert_eq!(Band::from_frequency(2412), Some(Band::Ghz2));
+        assert_eq!(Band::from_frequency(5180), Some(Band::Ghz5));
+        assert_eq!(Band::from_frequency(5955), Some(Band::Ghz6));
+        assert_eq!(Band::from_frequency(60480), Some(Band::Ghz60));
+        assert_eq!(Band::from_frequency(0), None);
+    }
+
+    #[test]
+    fn test_network_json() {
+        let network = Network {
+            ap: "D8:C7:C8:EB:02:F0".into(),
+            essid: "fux-admin".into(),
+            security: Security::from_ies(true, &rsn(2)),
+            frequency: 2412,
+            band: Some(Band::Ghz2),
+            ies: vec![0, 1, 0xab],
+            ..Default::default()
+        };
+        let json = serde_json::to_value(&network).unwrap();
+        assert_eq!(json["security"]["mode"], "wpa2");
+        assert_eq!(json["security"]["rsn"]["auth_suites"][0], "PSK");
+        assert_eq!(json["security"]["rsn"]["pairwise_ciphers"][0], "CCMP");
+        assert_eq!(json["band"], "2.4GHz");
+        assert_eq!(json["ies"], "0001ab");
+
+        let network2: Network = serde_json::from_value(json).unwrap();
+        assert_eq!(network, network2);
+    }
+
+    #[test]
+    fn test_wpa_supplicant_psk() {
+        let profile = ConnectProfile {
+            ssid: "example network".into(),
+            credentials: WifiCredentials::Psk("hunter2hunter2".into()),
+        };
+        assert_eq!(profile.to_wpa_supplicant().unwrap(), r#"network={
+    ssid="example network"
+    key_mgmt=WPA-PSK
+    psk="hunter2hunter2"
+}
+"#);
+    }
+
+    #[test]
+    fn test_wpa_supplicant_psk_quote() {
+        let profile = ConnectProfile {
+            ssid: "example network".into(),
+            credentials: WifiCredentials::Psk("hunter2\"hunter2".into()),
+        };
+        // wpa_supplicant uses the last quote to end the string
+        assert!(profile.to_wpa_supplicant().unwrap().contains("    psk=\"hunter2\"hunter2\"\n"));
+    }
+
+    #[test]
+    fn test_wpa_supplicant_invalid_psk() {
+        for psk in &["hunter2", "hunter2hunter2\n", "hünter2hunter2", &"a".repeat(64)] {
+            let profile = ConnectProfile {
+                ssid: "example network".into(),
+                credentials: WifiCredentials::Psk((*psk).to_string()),
+            };
+            assert!(profile.to_wpa_supplicant().is_err());
+        }
+    }
+
+    #[test]
+    fn test_wpa_supplicant_open() {
+        let profile = ConnectProfile {
+            ssid: "\"quoted\"".into(),
+            credentials: WifiCredentials::Open,
+        };
+        assert_eq!(profile.to_wpa_supplicant().unwrap(), r#"network={
+    ssid=2271756f74656422
+    key_mgmt=NONE
+}
+"#);
+    }
+
+    #[test]
+    fn test_wpa_supplicant_identity() {
+        let profile = ConnectProfile {
+            ssid: "eduroam".into(),
+            credentials: WifiCredentials::Identity {
+                identity: "user@example.com".into(),
+                password: "hunter2".into(),
+            },
+        };
+        assert_eq!(profile.to_wpa_supplicant().unwrap(), r#"network={
+    ssid="eduroam"
+    key_mgmt=WPA-EAP
+    eap=PEAP TTLS
+    identity="user@example.com"
+    password="hunter2"
+    phase2="auth=MSCHAPV2"
+}
+"#);
+    }
+}
@@ -0,0 +1,147 @@
+//! Runs the native dhcp client against dnsmasq on a veth pair in a separate
+//! network namespace. This needs root and dnsmasq, run it with:
+//!
+//!     cargo test --test dhcp_netns -- --ignored
+extern crate snail;
+extern crate nix;
+extern crate tempfile;
+#[macro_use] extern crate failure;
+
+use snail::config::Config;
+use snail::dhcp::{client, NetworkConfig, UpdateMessage};
+use snail::errors::Result;
+
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::sys::signal::{raise, Signal};
+
+use std::net::IpAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+const CLIENT: &str = "snail0";
+const SERVER: &str = "snail1";
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn ip(args: &[&str]) -> Result<String> {
+    let output = Command::new("ip")
+        .args(args)
+        .stderr(Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        bail!("ip {:?} failed: {}", args, output.status);
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// dnsmasq is stopped when this is dropped
+struct Dnsmasq(Child);
+
+impl Drop for Dnsmasq {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// Move the current thread into a new network namespace, the processes we
+/// spawn inherit it. sysfs is mounted again so /sys/class/net shows the
+/// interfaces of the new namespace.
+fn setup_namespace() -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWNET | CloneFlags::CLONE_NEWNS)?;
+    mount(None::<&str>, "/", None::<&str>, MsFlags::MS_REC | MsFlags::MS_PRIVATE, None::<&str>)?;
+    mount(Some("sysfs"), "/sys", Some("sysfs"), MsFlags::empty(), None::<&str>)?;
+
+    ip(&["link", "add", CLIENT, "type", "veth", "peer", "name", SERVER])?;
+    ip(&["address", "add", "10.23.0.1/24", "dev", SERVER])?;
+    ip(&["link", "set", "dev", SERVER, "up"])?;
+    Ok(())
+}
+
+fn start_dnsmasq(dir: &tempfile::TempDir) -> Result<Dnsmasq> {
+    let leases = dir.path().join("dnsmasq.leases");
+    let pid = dir.path().join("dnsmasq.pid");
+
+    let child = Command::new("dnsmasq")
+        .args(&[
+            "--keep-in-foreground",
+            "--conf-file=/dev/null",
+            // disable the dns server
+            "--port=0",
+            "--bind-interfaces",
+            &format!("--interface={}", SERVER),
+            "--dhcp-range=10.23.0.100,10.23.0.150,255.255.255.0,1h",
+            "--dhcp-option=option:router,10.23.0.1",
+            "--dhcp-option=option:dns-server,10.23.0.53",
+            "--dhcp-option=option:domain-name,snail.test",
+            &format!("--dhcp-leasefile={}", leases.display()),
+            &format!("--pid-file={}", pid.display()),
+        ])
+        .stdin(Stdio::null())
+        .spawn()?;
+    Ok(Dnsmasq(child))
+}
+
+fn obtain_lease() -> Result<(NetworkConfig, String)> {
+    setup_namespace()?;
+
+    let dir = tempfile::tempdir()?;
+    let _dnsmasq = start_dnsmasq(&dir)?;
+
+    let config = Config::default();
+    let mut lease = None;
+    let result = client::run(CLIENT, &config, |message| {
+        if let UpdateMessage::Bound(network) = message {
+            lease = Some(network);
+            // stop the client after the first lease
+            bail!("bound");
+        }
+        Ok(())
+    });
+
+    match lease {
+        Some(lease) => {
+            let addresses = ip(&["-4", "address", "show", "dev", CLIENT])?;
+            Ok((lease, addresses))
+        },
+        None => bail!("no lease received: {:?}", result),
+    }
+}
+
+#[test]
+#[ignore]
+fn test_native_client_dnsmasq() {
+    let done = Arc::new(AtomicBool::new(false));
+    {
+        // the client is stopped with SIGTERM if dnsmasq never answers
+        let done = done.clone();
+        thread::spawn(move || {
+            thread::sleep(TIMEOUT);
+            if !done.load(Ordering::SeqCst) {
+                raise(Signal::SIGTERM).expect("failed to stop dhcp client");
+            }
+        });
+    }
+
+    // the namespaces only apply to this thread
+    let result = thread::spawn(obtain_lease).join().expect("dhcp thread crashed");
+    done.store(true, Ordering::SeqCst);
+    let (lease, addresses) = result.expect("failed to obtain lease");
+
+    let ip_address = match lease.ip_address {
+        IpAddr::V4(ip) => ip.octets(),
+        ip => panic!("unexpected address: {}", ip),
+    };
+    assert_eq!(ip_address[..3], [10, 23, 0]);
+    assert!(ip_address[3] >= 100 && ip_address[3] <= 150);
+    assert_eq!(lease.subnet_cidr, 24);
+    assert_eq!(lease.routers, vec!["10.23.0.1".parse::<IpAddr>().unwrap()]);
+    assert_eq!(lease.dns_servers, vec!["10.23.0.53".parse::<IpAddr>().unwrap()]);
+    assert_eq!(lease.options.domain_name, Some("snail.test".to_string()));
+
+    // the lease has been applied to the interface
+    assert!(addresses.contains(&format!("inet {}/24", lease.ip_address)));
+}